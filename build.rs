@@ -22,10 +22,13 @@ fn main() {
     //2. x86 Kernel
     build_kernel(&source_dir, &target_dir, "x86");
 
-    //3. AMD64(x86_64) kernel
+    //3. User programs linked into the x64 kernel
+    build_programs(&source_dir.join("programs"), &target_dir.join("programs"));
+
+    //4. AMD64(x86_64) kernel
     build_kernel(&source_dir, &target_dir, "x64");
 
-    //4. ImageMaker
+    //5. ImageMaker
     image_make(&target_dir, "Disk.img");
 }
 
@@ -40,6 +43,41 @@ fn build_bootloader(source: &Path, target: &Path) {
     assert!(nasm.success());
 }
 
+fn build_programs(source: &Path, target: &Path) {
+    println!("cargo:rerun-if-changed={}", &source.display());
+    match fs::create_dir_all(&target) {
+        Ok(_) => {}
+        Err(_) => {}
+    }
+
+    for entry in fs::read_dir(&source).unwrap() {
+        let file = entry.unwrap().path();
+        if file.extension().map_or(true, |ext| ext != "asm") {
+            continue;
+        }
+        let name = file.file_stem().unwrap().to_str().unwrap();
+        let object = format!("{}/{}.o", &target.display(), name);
+        let nasm = Command::new("nasm")
+            .args(["-f", "elf64", "-o"])
+            .arg(&object)
+            .arg(&file)
+            .status()
+            .unwrap();
+        assert!(nasm.success());
+
+        // Programs live in the second PML4 entry of their own address space
+        let ld = Command::new("ld")
+            .args(["-melf_x86_64", "-nostdlib", "-static"])
+            .args(["-Ttext-segment=0x8000000000", "-z", "max-page-size=0x1000"])
+            .args(["-e", "_start", "-o"])
+            .arg(format!("{}/{}.elf", &target.display(), name))
+            .arg(&object)
+            .status()
+            .unwrap();
+        assert!(ld.success());
+    }
+}
+
 fn build_kernel(source: &Path, root_target: &Path, arch: &str) {
     println!(
         "cargo:rerun-if-changed={}/arch/{}/src",
@@ -132,6 +170,7 @@ fn build_kernel(source: &Path, root_target: &Path, arch: &str) {
         // .arg("--target")
         // .arg(format!("{}/triple", &source.display()))
         .arg(format!("--target-dir={}", &target.display()))
+        .env("PROGRAM_DIRECTORY", root_target.join("programs"))
         .status()
         .unwrap();
     assert!(cargo.success());
@@ -1 +1 @@
-qemu-system-x86_64 -L . -m 128 -fda Disk.img -M pc
\ No newline at end of file
+qemu-system-x86_64 -L . -m 128 -fda Disk.img -M pc -smp 4
\ No newline at end of file
@@ -0,0 +1,420 @@
+use core::{mem::size_of, ptr};
+
+// The RSDP lies on a 16-byte boundary in the first KB of the EBDA, whose
+// segment the BIOS data area keeps at 0x40E, or in the BIOS ROM area
+const ACPI_EBDASEGMENTADDRESS: u64 = 0x40E;
+const ACPI_EBDASEARCHSIZE: u64 = 1024;
+const ACPI_BIOSAREASTARTADDRESS: u64 = 0xE0000;
+const ACPI_BIOSAREAENDADDRESS: u64 = 0x100000;
+const ACPI_RSDPSIGNATURE: &[u8; 8] = b"RSD PTR ";
+// Only this much of the RSDP is covered by the first checksum; revision 2
+// adds a second one over its full length
+const ACPI_RSDPREVISION1LENGTH: u64 = 20;
+
+// Address spaces of a generic address structure
+pub const ACPI_ADDRESSSPACE_MEMORY: u8 = 0;
+pub const ACPI_ADDRESSSPACE_IO: u8 = 1;
+pub const ACPI_ADDRESSSPACE_PCICONFIG: u8 = 2;
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const MADT_LOCALAPICOFFSET: u64 = size_of::<SdtHeader>() as u64;
+const MADT_FLAGSOFFSET: u64 = size_of::<SdtHeader>() as u64 + 4;
+const MADT_ENTRYOFFSET: u64 = size_of::<SdtHeader>() as u64 + 8;
+const MADT_ENTRY_LOCALAPIC: u8 = 0;
+const MADT_ENTRY_IOAPIC: u8 = 1;
+const MADT_ENTRY_INTERRUPTOVERRIDE: u8 = 2;
+const MADT_ENTRY_LOCALAPICOVERRIDE: u8 = 5;
+const MADT_LOCALAPIC_ENABLED: u32 = 0x01;
+
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+const MCFG_ENTRYOFFSET: u64 = size_of::<SdtHeader>() as u64 + 8;
+
+#[repr(C, packed(1))]
+#[derive(Clone, Copy)]
+pub struct Rsdp {
+    pub signature: [u8; 8],
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub revision: u8,
+    pub rsdt_address: u32,
+    // From revision 2 on
+    pub length: u32,
+    pub xsdt_address: u64,
+    pub extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed(1))]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+fn read<T: Copy>(address: u64) -> T {
+    unsafe { ptr::read_unaligned(address as *const T) }
+}
+
+// The bytes of every ACPI structure add up to 0
+fn is_valid(address: u64, length: u64) -> bool {
+    (0..length).fold(0u8, |sum, offset| sum.wrapping_add(unsafe { *((address + offset) as *const u8) })) == 0
+}
+
+fn find_rsdp_in(start: u64, end: u64) -> Option<u64> {
+    (start..end).step_by(16).find(|&address| {
+        let signature = unsafe { &*(address as *const [u8; 8]) };
+        if signature != ACPI_RSDPSIGNATURE || !is_valid(address, ACPI_RSDPREVISION1LENGTH) {
+            return false;
+        }
+        let rsdp: Rsdp = read(address);
+        rsdp.revision < 2 || is_valid(address, rsdp.length as u64)
+    })
+}
+
+fn find_rsdp() -> Option<u64> {
+    let ebda = (unsafe { *(ACPI_EBDASEGMENTADDRESS as *const u16) } as u64) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = find_rsdp_in(ebda, ebda + ACPI_EBDASEARCHSIZE) {
+            return Some(rsdp);
+        }
+    }
+    find_rsdp_in(ACPI_BIOSAREASTARTADDRESS, ACPI_BIOSAREAENDADDRESS)
+}
+
+// The RSDP and its address
+pub fn rsdp() -> Option<(u64, Rsdp)> {
+    let address = find_rsdp()?;
+    Some((address, read(address)))
+}
+
+// Addresses of the tables the XSDT lists, or the RSDT before ACPI 2.0. A
+// root table whose checksum does not add up lists nothing.
+fn table_addresses() -> impl Iterator<Item = u64> {
+    let root = rsdp()
+        .map(|(_, rsdp)| {
+            if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+                (rsdp.xsdt_address, size_of::<u64>() as u64)
+            } else {
+                (rsdp.rsdt_address as u64, size_of::<u32>() as u64)
+            }
+        })
+        .filter(|&(address, _)| is_valid_table(address));
+    root.into_iter().flat_map(|(address, entry_size)| {
+        let header = unsafe { ptr::read_unaligned(address as *const SdtHeader) };
+        let count = (header.length as u64).saturating_sub(size_of::<SdtHeader>() as u64) / entry_size;
+        (0..count).map(move |index| {
+            let entry = address + size_of::<SdtHeader>() as u64 + index * entry_size;
+            unsafe {
+                if entry_size == size_of::<u64>() as u64 {
+                    ptr::read_unaligned(entry as *const u64)
+                } else {
+                    ptr::read_unaligned(entry as *const u32) as u64
+                }
+            }
+        })
+    })
+}
+
+pub fn is_valid_table(address: u64) -> bool {
+    let header: SdtHeader = read(address);
+    is_valid(address, header.length as u64)
+}
+
+// What follows the header of a table, such as the AML of the DSDT
+pub fn table_data(address: u64) -> &'static [u8] {
+    let header: SdtHeader = read(address);
+    let length = (header.length as usize).saturating_sub(size_of::<SdtHeader>());
+    unsafe { core::slice::from_raw_parts((address + size_of::<SdtHeader>() as u64) as *const u8, length) }
+}
+
+// Every table the root table lists, with its header, whether or not its
+// checksum adds up
+pub fn tables() -> impl Iterator<Item = (u64, SdtHeader)> {
+    table_addresses().map(|address| (address, read(address)))
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<u64> {
+    tables()
+        .find(|(address, header)| &header.signature == signature && is_valid_table(*address))
+        .map(|(address, _)| address)
+}
+
+// Copies a table into its structure. Older revisions are shorter, and the
+// fields they lack are left 0.
+fn read_table<T: Copy>(signature: &[u8; 4]) -> Option<T> {
+    let address = find_table(signature)?;
+    let header: SdtHeader = read(address);
+    let length = (header.length as usize).min(size_of::<T>());
+    unsafe {
+        let mut table: T = core::mem::zeroed();
+        ptr::copy_nonoverlapping(address as *const u8, &mut table as *mut T as *mut u8, length);
+        Some(table)
+    }
+}
+
+// Walks the variable length entries of the MADT, giving the type and the
+// address of each
+struct MadtEntries {
+    address: u64,
+    end: u64,
+}
+
+impl Iterator for MadtEntries {
+    type Item = (u8, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.address + 2 > self.end {
+            return None;
+        }
+        let (kind, length) = unsafe { (*(self.address as *const u8), *((self.address + 1) as *const u8)) };
+        if length < 2 {
+            return None;
+        }
+        let entry = self.address;
+        self.address += length as u64;
+        Some((kind, entry))
+    }
+}
+
+fn madt_entries() -> impl Iterator<Item = (u8, u64)> {
+    find_table(MADT_SIGNATURE).into_iter().flat_map(|madt| {
+        let header = unsafe { ptr::read_unaligned(madt as *const SdtHeader) };
+        MadtEntries {
+            address: madt + MADT_ENTRYOFFSET,
+            end: madt + header.length as u64,
+        }
+    })
+}
+
+// Address of the local APICs, which a 64-bit override entry can move
+pub fn local_apic_address() -> Option<u64> {
+    let madt = find_table(MADT_SIGNATURE)?;
+    let address: u32 = read(madt + MADT_LOCALAPICOFFSET);
+    let address = madt_entries()
+        .filter(|&(kind, _)| kind == MADT_ENTRY_LOCALAPICOVERRIDE)
+        .map(|(_, entry)| read(entry + 4))
+        .last()
+        .unwrap_or(address as u64);
+    Some(address)
+}
+
+// Flags of the MADT; bit 0 says the legacy PICs are present as well
+pub fn madt_flags() -> u32 {
+    find_table(MADT_SIGNATURE)
+        .map(|madt| read(madt + MADT_FLAGSOFFSET))
+        .unwrap_or(0)
+}
+
+// APIC IDs of the processors the firmware reports as usable
+pub fn local_apic_ids() -> impl Iterator<Item = u8> {
+    madt_entries()
+        .filter(|&(kind, _)| kind == MADT_ENTRY_LOCALAPIC)
+        .filter_map(|(_, entry)| {
+            let (apic_id, flags) = unsafe {
+                (*((entry + 3) as *const u8), ptr::read_unaligned((entry + 4) as *const u32))
+            };
+            (flags & MADT_LOCALAPIC_ENABLED != 0).then_some(apic_id)
+        })
+}
+
+#[derive(Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u64,
+    // First global system interrupt the I/O APIC's pins stand for
+    pub gsi_base: u32,
+}
+
+pub fn io_apics() -> impl Iterator<Item = IoApic> {
+    madt_entries()
+        .filter(|&(kind, _)| kind == MADT_ENTRY_IOAPIC)
+        .map(|(_, entry)| IoApic {
+            id: read(entry + 2),
+            address: read::<u32>(entry + 4) as u64,
+            gsi_base: read(entry + 8),
+        })
+}
+
+// An ISA IRQ wired to a different global system interrupt, or with a
+// polarity or trigger mode other than the ISA default
+#[derive(Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+pub fn interrupt_overrides() -> impl Iterator<Item = InterruptOverride> {
+    madt_entries()
+        .filter(|&(kind, _)| kind == MADT_ENTRY_INTERRUPTOVERRIDE)
+        .map(|(_, entry)| InterruptOverride {
+            irq: read(entry + 3),
+            gsi: read(entry + 4),
+            flags: read(entry + 8),
+        })
+}
+
+// Global system interrupt an ISA IRQ arrives at
+pub fn irq_to_gsi(irq: u8) -> u32 {
+    interrupt_overrides()
+        .find(|entry| entry.irq == irq)
+        .map_or(irq as u32, |entry| entry.gsi)
+}
+
+// Where a register block lives: memory, I/O ports or PCI configuration space
+#[repr(C, packed(1))]
+#[derive(Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+// The reset register in the FADT is usable
+pub const FADT_FLAGS_RESETREGISTER: u32 = 0x400;
+
+// Fixed ACPI Description Table, up to the 64-bit PM1 control blocks
+#[repr(C, packed(1))]
+#[derive(Clone, Copy)]
+pub struct Fadt {
+    pub header: SdtHeader,
+    pub firmware_control: u32,
+    pub dsdt: u32,
+    reserved: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_interrupt: u16,
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_request: u8,
+    pub pstate_control: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm2_control_block: u32,
+    pub pm_timer_block: u32,
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub pm2_control_length: u8,
+    pub pm_timer_length: u8,
+    pub gpe0_length: u8,
+    pub gpe1_length: u8,
+    pub gpe1_base: u8,
+    pub cstate_control: u8,
+    pub c2_latency: u16,
+    pub c3_latency: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    pub century: u8,
+    pub boot_architecture_flags: u16,
+    reserved2: u8,
+    pub flags: u32,
+    // From revision 2 on
+    pub reset_register: GenericAddress,
+    pub reset_value: u8,
+    pub arm_boot_architecture_flags: u16,
+    pub minor_version: u8,
+    pub x_firmware_control: u64,
+    pub x_dsdt: u64,
+    pub x_pm1a_event_block: GenericAddress,
+    pub x_pm1b_event_block: GenericAddress,
+    pub x_pm1a_control_block: GenericAddress,
+    pub x_pm1b_control_block: GenericAddress,
+}
+
+impl Fadt {
+    pub fn dsdt_address(&self) -> u64 {
+        match self.x_dsdt {
+            0 => self.dsdt as u64,
+            address => address,
+        }
+    }
+
+    // Ports of the PM1a and PM1b control blocks, 0 when there is none. The
+    // 64-bit fields win when they name I/O ports.
+    pub fn pm1_control_ports(&self) -> (u16, u16) {
+        (
+            io_port(self.x_pm1a_control_block, self.pm1a_control_block),
+            io_port(self.x_pm1b_control_block, self.pm1b_control_block),
+        )
+    }
+}
+
+fn io_port(extended: GenericAddress, legacy: u32) -> u16 {
+    if extended.address_space == ACPI_ADDRESSSPACE_IO && extended.address != 0 {
+        extended.address as u16
+    } else {
+        legacy as u16
+    }
+}
+
+pub fn fadt() -> Option<Fadt> {
+    read_table(FADT_SIGNATURE)
+}
+
+#[repr(C, packed(1))]
+#[derive(Clone, Copy)]
+pub struct Hpet {
+    pub header: SdtHeader,
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+impl Hpet {
+    pub fn comparator_count(&self) -> u32 {
+        ((self.event_timer_block_id >> 8) & 0x1F) + 1
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.event_timer_block_id & 0x2000 != 0
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        (self.event_timer_block_id >> 16) as u16
+    }
+}
+
+pub fn hpet() -> Option<Hpet> {
+    read_table(HPET_SIGNATURE)
+}
+
+// Memory-mapped PCI configuration space of one segment group and bus range
+#[repr(C, packed(1))]
+#[derive(Clone, Copy)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+    reserved: u32,
+}
+
+pub fn mcfg_entries() -> impl Iterator<Item = McfgEntry> {
+    find_table(MCFG_SIGNATURE).into_iter().flat_map(|mcfg| {
+        let header: SdtHeader = read(mcfg);
+        let entry_size = size_of::<McfgEntry>() as u64;
+        let count = (header.length as u64).saturating_sub(MCFG_ENTRYOFFSET) / entry_size;
+        (0..count).map(move |index| read(mcfg + MCFG_ENTRYOFFSET + index * entry_size))
+    })
+}
@@ -0,0 +1,162 @@
+use core::{hint, ptr};
+
+use crate::{acpi, interrupt};
+
+// Every core sees its own local APIC at the same address
+const LAPIC_BASEADDRESS: u64 = 0xFEE00000;
+const LAPIC_REGISTER_ID: u64 = 0x20;
+const LAPIC_REGISTER_EOI: u64 = 0xB0;
+const LAPIC_REGISTER_SVR: u64 = 0xF0;
+const LAPIC_REGISTER_ICRLOW: u64 = 0x300;
+const LAPIC_REGISTER_ICRHIGH: u64 = 0x310;
+const LAPIC_REGISTER_TIMER: u64 = 0x320;
+const LAPIC_REGISTER_LINT0: u64 = 0x350;
+const LAPIC_REGISTER_LINT1: u64 = 0x360;
+const LAPIC_REGISTER_INITIALCOUNT: u64 = 0x380;
+const LAPIC_REGISTER_CURRENTCOUNT: u64 = 0x390;
+const LAPIC_REGISTER_DIVIDE: u64 = 0x3E0;
+
+const LAPIC_SVR_ENABLE: u32 = 0x100;
+// The vector the SDM suggests, with the low four bits set as older local
+// APICs require
+pub const LAPIC_SPURIOUSVECTOR: u8 = 0xFF;
+
+const LAPIC_DELIVERYMODE_FIXED: u32 = 0x000;
+const LAPIC_DELIVERYMODE_NMI: u32 = 0x400;
+const LAPIC_DELIVERYMODE_INIT: u32 = 0x500;
+const LAPIC_DELIVERYMODE_STARTUP: u32 = 0x600;
+const LAPIC_DELIVERYMODE_EXTINT: u32 = 0x700;
+const LAPIC_DELIVERYSTATUS_PENDING: u32 = 0x1000;
+const LAPIC_LEVEL_ASSERT: u32 = 0x4000;
+const LAPIC_INTERRUPT_MASK: u32 = 0x10000;
+// Shorthands that make the destination field of the ICR ignored
+const LAPIC_DESTINATION_ALLINCLUDINGSELF: u32 = 0x80000;
+const LAPIC_DESTINATION_ALLEXCLUDINGSELF: u32 = 0xC0000;
+
+// Application processors take their scheduling tick from the local APIC timer
+pub const LAPIC_TIMERVECTOR: u8 = 48;
+const LAPIC_TIMER_PERIODIC: u32 = 0x20000;
+const LAPIC_TIMER_DIVIDEBY16: u32 = 0x03;
+
+// The I/O APIC sits at its usual address until the ACPI tables say otherwise
+const IOAPIC_BASEADDRESS: u64 = 0xFEC00000;
+const IOAPIC_REGISTER_SELECT: u64 = 0x00;
+const IOAPIC_REGISTER_WINDOW: u64 = 0x10;
+
+const IOAPIC_INDEX_REDIRECTIONTABLE: u32 = 0x10;
+
+pub const IOAPIC_DELIVERYMODE_FIXED: u32 = 0x000;
+pub const IOAPIC_DELIVERYMODE_NMI: u32 = 0x400;
+pub const IOAPIC_INTERRUPT_MASK: u32 = 0x10000;
+
+// The ISA IRQs go to the I/O APIC whose pins start at interrupt 0
+fn ioapic_address() -> u64 {
+    acpi::io_apics()
+        .find(|ioapic| ioapic.gsi_base == 0)
+        .map_or(IOAPIC_BASEADDRESS, |ioapic| ioapic.address)
+}
+
+fn write_ioapic(index: u32, value: u32) {
+    let base = ioapic_address();
+    unsafe {
+        ptr::write_volatile((base + IOAPIC_REGISTER_SELECT) as *mut u32, index);
+        ptr::write_volatile((base + IOAPIC_REGISTER_WINDOW) as *mut u32, value);
+    }
+}
+
+// Routes an ISA IRQ to the local APIC `destination`, through the pin the
+// MADT may have moved it to. `flags` holds the vector, delivery mode, trigger
+// mode and mask bits of the entry.
+pub fn set_irq_redirection(irq: u8, flags: u32, destination: u8) {
+    let index = IOAPIC_INDEX_REDIRECTIONTABLE + acpi::irq_to_gsi(irq) * 2;
+    write_ioapic(index, flags | IOAPIC_INTERRUPT_MASK);
+    write_ioapic(index + 1, (destination as u32) << 24);
+    write_ioapic(index, flags);
+}
+
+fn read_local_apic(register: u64) -> u32 {
+    unsafe { ptr::read_volatile((LAPIC_BASEADDRESS + register) as *const u32) }
+}
+
+fn write_local_apic(register: u64, value: u32) {
+    unsafe { ptr::write_volatile((LAPIC_BASEADDRESS + register) as *mut u32, value) };
+}
+
+pub fn local_apic_id() -> u8 {
+    (read_local_apic(LAPIC_REGISTER_ID) >> 24) as u8
+}
+
+// Software enables the local APIC of the running core. Only the bootstrap
+// processor passes the PIC through LINT0; every core takes NMIs on LINT1.
+pub fn enable_local_apic(bootstrap: bool) {
+    let svr = read_local_apic(LAPIC_REGISTER_SVR) & !0xFF;
+    write_local_apic(LAPIC_REGISTER_SVR, svr | LAPIC_SVR_ENABLE | LAPIC_SPURIOUSVECTOR as u32);
+    if bootstrap {
+        write_local_apic(LAPIC_REGISTER_LINT0, LAPIC_DELIVERYMODE_EXTINT);
+    } else {
+        write_local_apic(LAPIC_REGISTER_LINT0, LAPIC_DELIVERYMODE_EXTINT | LAPIC_INTERRUPT_MASK);
+    }
+    write_local_apic(LAPIC_REGISTER_LINT1, LAPIC_DELIVERYMODE_NMI);
+}
+
+// Starts the timer counting down from `count` at the bus clock divided by 16,
+// raising LAPIC_TIMERVECTOR when it reaches 0
+pub fn start_local_timer(count: u32, periodic: bool) {
+    let mode = if periodic { LAPIC_TIMER_PERIODIC } else { 0 };
+    write_local_apic(LAPIC_REGISTER_DIVIDE, LAPIC_TIMER_DIVIDEBY16);
+    write_local_apic(LAPIC_REGISTER_TIMER, mode | LAPIC_TIMERVECTOR as u32);
+    write_local_apic(LAPIC_REGISTER_INITIALCOUNT, count);
+}
+
+pub fn stop_local_timer() {
+    write_local_apic(LAPIC_REGISTER_TIMER, LAPIC_INTERRUPT_MASK | LAPIC_TIMERVECTOR as u32);
+    write_local_apic(LAPIC_REGISTER_INITIALCOUNT, 0);
+}
+
+pub fn get_local_timer_count() -> u32 {
+    read_local_apic(LAPIC_REGISTER_CURRENTCOUNT)
+}
+
+pub fn send_local_eoi() {
+    write_local_apic(LAPIC_REGISTER_EOI, 0);
+}
+
+// Writes the interrupt command register and waits until the IPI is accepted.
+// An interrupt handler sending one of its own must not come in between the
+// two writes.
+fn send_ipi(apic_id: u8, command: u32) {
+    interrupt::without_interrupt(|| {
+        write_local_apic(LAPIC_REGISTER_ICRHIGH, (apic_id as u32) << 24);
+        write_local_apic(LAPIC_REGISTER_ICRLOW, command);
+        while read_local_apic(LAPIC_REGISTER_ICRLOW) & LAPIC_DELIVERYSTATUS_PENDING != 0 {
+            hint::spin_loop();
+        }
+    })
+}
+
+pub fn send_fixed_ipi(apic_id: u8, vector: u8) {
+    send_ipi(apic_id, LAPIC_DELIVERYMODE_FIXED | LAPIC_LEVEL_ASSERT | vector as u32);
+}
+
+pub fn broadcast_fixed_ipi(vector: u8, include_self: bool) {
+    let shorthand = if include_self {
+        LAPIC_DESTINATION_ALLINCLUDINGSELF
+    } else {
+        LAPIC_DESTINATION_ALLEXCLUDINGSELF
+    };
+    send_ipi(0, shorthand | LAPIC_DELIVERYMODE_FIXED | LAPIC_LEVEL_ASSERT | vector as u32);
+}
+
+// NMIs get through to cores that keep interrupts disabled
+pub fn broadcast_nmi() {
+    send_ipi(0, LAPIC_DESTINATION_ALLEXCLUDINGSELF | LAPIC_DELIVERYMODE_NMI | LAPIC_LEVEL_ASSERT);
+}
+
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, LAPIC_DELIVERYMODE_INIT | LAPIC_LEVEL_ASSERT);
+}
+
+// The core starts in real mode at `vector` * 4KB
+pub fn send_startup(apic_id: u8, vector: u8) {
+    send_ipi(apic_id, LAPIC_DELIVERYMODE_STARTUP | LAPIC_LEVEL_ASSERT | vector as u32);
+}
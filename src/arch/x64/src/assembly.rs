@@ -26,6 +26,54 @@ pub fn OutPortByte(port: u16, data: u8) {
     }
 }
 
+pub fn InPortWord(port: u16) -> u16 {
+    let mut output: u16;
+    unsafe {
+        asm!(
+            "in ax, dx",
+             in("rdx") (port as u64),
+             out("ax") output,
+             options(nostack, preserves_flags)
+        );
+    }
+    return output;
+}
+
+pub fn OutPortWord(port: u16, data: u16) {
+    unsafe {
+        asm!(
+            "out dx, ax",
+             in("rdx") (port as u64),
+             in("rax") (data as u64),
+             options(nostack, preserves_flags)
+        );
+    }
+}
+
+pub fn InPortDword(port: u16) -> u32 {
+    let mut output: u32;
+    unsafe {
+        asm!(
+            "in eax, dx",
+             in("rdx") (port as u64),
+             out("eax") output,
+             options(nostack, preserves_flags)
+        );
+    }
+    return output;
+}
+
+pub fn OutPortDword(port: u16, data: u32) {
+    unsafe {
+        asm!(
+            "out dx, eax",
+             in("rdx") (port as u64),
+             in("rax") (data as u64),
+             options(nostack, preserves_flags)
+        );
+    }
+}
+
 pub fn LoadGDTR(GDTRAddress: u64) {
     unsafe {
         asm!(
@@ -94,7 +142,7 @@ pub fn read_TSC() -> u64 {
             // options(nostack, preserves_flags)
         );
     }
-    rdx << 32 | rax >> 32
+    rdx << 32 | (rax & 0xFFFFFFFF)
 }
 
 pub fn halt() {
@@ -102,3 +150,67 @@ pub fn halt() {
         asm!("hlt", "hlt");
     }
 }
+
+// STI takes effect after the next instruction, so no interrupt can slip in
+// between enabling and halting; interrupts are disabled again on return
+pub fn halt_until_interrupt() {
+    unsafe {
+        asm!("sti", "hlt", "cli");
+    }
+}
+
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+pub fn read_cpuid(leaf: u32, sub_leaf: u32) -> CpuidResult {
+    let eax;
+    let ebx;
+    let ecx;
+    let edx;
+
+    unsafe {
+        asm!(
+            "mov {0:r}, rbx",
+            "cpuid",
+            "xchg {0:r}, rbx",
+            out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") sub_leaf => ecx,
+            out("edx") edx,
+            options(nostack, preserves_flags),
+        );
+    }
+
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+pub fn read_MSR(msr: u32) -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!(
+            "rdmsr",
+            in("ecx") msr,
+            out("eax") low,
+            out("edx") high,
+            options(nostack, preserves_flags)
+        );
+    }
+    (high as u64) << 32 | low as u64
+}
+
+pub fn write_MSR(msr: u32, value: u64) {
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nostack, preserves_flags)
+        );
+    }
+}
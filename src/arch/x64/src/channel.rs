@@ -0,0 +1,223 @@
+use core::{mem::size_of, ptr};
+
+use crate::{
+    memory::{alloc_frame, free_frame, FRAME_SIZE},
+    process::{self, BlockReason, WaitQueue},
+    spinlock::SpinLock,
+};
+
+const CHANNEL_MAXCOUNT: usize = 64;
+// Each channel keeps its queue in a frame of its own
+pub const CHANNEL_MAXCAPACITY: u64 = FRAME_SIZE / size_of::<Message>() as u64;
+
+const CHANNEL_INDEXMASK: u64 = 0xFFFFFFFF;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Message {
+    pub sender: u64,
+    pub data: u64,
+}
+
+// A bounded queue of messages. Its ID carries a generation like task IDs,
+// so a handle to a channel that is gone finds nothing. Senders wait for room
+// and receivers for a message on queues of their own.
+#[derive(Clone, Copy)]
+struct Channel {
+    id: u64,
+    buffer: u64,
+    capacity: u64,
+    head: u64,
+    count: u64,
+    reference_count: u64,
+    senders: WaitQueue,
+    receivers: WaitQueue,
+}
+
+impl Channel {
+    const fn empty() -> Self {
+        Self {
+            id: 0,
+            buffer: 0,
+            capacity: 0,
+            head: 0,
+            count: 0,
+            reference_count: 0,
+            senders: WaitQueue::new(),
+            receivers: WaitQueue::new(),
+        }
+    }
+
+    fn slot(&self, index: u64) -> *mut Message {
+        (self.buffer as *mut Message).wrapping_add(((self.head + index) % self.capacity) as usize)
+    }
+}
+
+pub struct ChannelTable {
+    channels: [Channel; CHANNEL_MAXCOUNT],
+    generation: u64,
+}
+
+impl ChannelTable {
+    const fn new() -> Self {
+        Self {
+            channels: [Channel::empty(); CHANNEL_MAXCOUNT],
+            generation: 1,
+        }
+    }
+
+    fn get(&mut self, id: u64) -> Option<&mut Channel> {
+        let channel = self.channels.get_mut((id & CHANNEL_INDEXMASK) as usize)?;
+        (id >> 32 != 0 && channel.id == id).then_some(channel)
+    }
+
+    fn create(&mut self, capacity: u64) -> Result<u64, ()> {
+        if capacity == 0 || capacity > CHANNEL_MAXCAPACITY {
+            return Err(());
+        }
+        let index = self.channels.iter().position(|channel| channel.id == 0).ok_or(())?;
+        let buffer = alloc_frame().ok_or(())?;
+        let id = (self.generation << 32) | index as u64;
+        self.generation = self.generation % CHANNEL_INDEXMASK + 1;
+        self.channels[index] = Channel {
+            id,
+            buffer,
+            capacity,
+            head: 0,
+            count: 0,
+            reference_count: 1,
+            senders: WaitQueue::new(),
+            receivers: WaitQueue::new(),
+        };
+        Ok(id)
+    }
+
+    // Ok(false) while the queue is full
+    fn push(&mut self, id: u64, message: Message) -> Result<bool, ()> {
+        let channel = self.get(id).ok_or(())?;
+        if channel.count == channel.capacity {
+            return Ok(false);
+        }
+        unsafe { ptr::write(channel.slot(channel.count), message) };
+        channel.count += 1;
+        channel.receivers.wake_all(BlockReason::Receive);
+        Ok(true)
+    }
+
+    // Ok(None) while the queue is empty
+    fn pop(&mut self, id: u64) -> Result<Option<Message>, ()> {
+        let channel = self.get(id).ok_or(())?;
+        if channel.count == 0 {
+            return Ok(None);
+        }
+        let message = unsafe { ptr::read(channel.slot(0)) };
+        channel.head = (channel.head + 1) % channel.capacity;
+        channel.count -= 1;
+        channel.senders.wake_all(BlockReason::Send);
+        Ok(Some(message))
+    }
+}
+
+static CHANNEL_TABLE: SpinLock<ChannelTable> = SpinLock::new(ChannelTable::new());
+
+// Creates a channel holding up to `capacity` messages. The caller owns the
+// one reference it starts with.
+pub fn create(capacity: u64) -> Result<u64, ()> {
+    CHANNEL_TABLE.lock().create(capacity)
+}
+
+pub fn acquire(id: u64) -> Result<(), ()> {
+    let mut table = CHANNEL_TABLE.lock();
+    let channel = table.get(id).ok_or(())?;
+    channel.reference_count += 1;
+    Ok(())
+}
+
+// Drops one reference; the last one frees the channel along with whatever
+// is still queued, and wakes the tasks waiting on it to find it gone
+pub fn release(id: u64) -> Result<(), ()> {
+    let buffer = {
+        let mut table = CHANNEL_TABLE.lock();
+        let channel = table.get(id).ok_or(())?;
+        channel.reference_count -= 1;
+        if channel.reference_count != 0 {
+            return Ok(());
+        }
+        let buffer = channel.buffer;
+        channel.senders.wake_all(BlockReason::Send);
+        channel.receivers.wake_all(BlockReason::Receive);
+        *channel = Channel::empty();
+        buffer
+    };
+    let _ = free_frame(buffer);
+    Ok(())
+}
+
+fn message(data: u64) -> Message {
+    Message {
+        sender: process::get_pid(),
+        data,
+    }
+}
+
+// Fails when the queue is full instead of waiting
+pub fn try_send(id: u64, data: u64) -> Result<(), ()> {
+    let message = message(data);
+    match CHANNEL_TABLE.lock().push(id, message) {
+        Ok(true) => Ok(()),
+        _ => Err(()),
+    }
+}
+
+// Waits for room while the queue is full
+pub fn send(id: u64, data: u64) -> Result<(), ()> {
+    let message = message(data);
+    process::wait_on(BlockReason::Send, &CHANNEL_TABLE, |table| {
+        match table.push(id, message) {
+            Ok(true) => Ok(Ok(())),
+            Ok(false) => Err(&mut table.get(id).unwrap().senders),
+            Err(()) => Ok(Err(())),
+        }
+    })
+}
+
+// Fails when the queue is empty instead of waiting
+pub fn try_receive(id: u64) -> Result<Message, ()> {
+    CHANNEL_TABLE.lock().pop(id)?.ok_or(())
+}
+
+// Waits for a message while the queue is empty
+pub fn receive(id: u64) -> Result<Message, ()> {
+    process::wait_on(BlockReason::Receive, &CHANNEL_TABLE, |table| {
+        match table.pop(id) {
+            Ok(Some(message)) => Ok(Ok(message)),
+            Ok(None) => Err(&mut table.get(id).unwrap().receivers),
+            Err(()) => Ok(Err(())),
+        }
+    })
+}
+
+// Creates a channel and hands it to the running process as a handle
+pub fn create_handle(capacity: u64) -> Result<u64, ()> {
+    let id = create(capacity)?;
+    process::alloc_handle(id).map_err(|()| {
+        let _ = release(id);
+    })
+}
+
+// Gives the process `pid` a handle of its own to the channel behind `handle`
+pub fn share_handle(handle: u64, pid: u64) -> Result<u64, ()> {
+    let id = process::get_handle(handle).ok_or(())?;
+    acquire(id)?;
+    process::alloc_handle_for(pid, id).map_err(|()| {
+        let _ = release(id);
+    })
+}
+
+pub fn close_handle(handle: u64) -> Result<(), ()> {
+    release(process::free_handle(handle)?)
+}
+
+pub fn get_channel(handle: u64) -> Result<u64, ()> {
+    process::get_handle(handle).ok_or(())
+}
@@ -1,7 +1,23 @@
-use core::fmt;
-use spin::{Lazy, Mutex};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Lazy;
 
-use crate::{assembly::OutPortByte, keyboard};
+use crate::{
+    assembly::OutPortByte,
+    channel,
+    keyboard::{self, KeyData},
+    print_string,
+    process::{self, AFFINITY_ALL, PRIORITY_HIGHIST},
+    serial::SERIAL,
+    spinlock::SpinLock,
+};
+
+const CONSOLE_INPUTCAPACITY: u64 = 64;
+
+// Channel the keyboard task delivers key events to
+static CONSOLE_INPUTCHANNEL: AtomicU64 = AtomicU64::new(0);
 
 const VGA_PORT_INDEX: u16 = 0x3D4;
 const VGA_PORT_DATA: u16 = 0x3D5;
@@ -49,6 +65,8 @@ struct ScreenChar {
 
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
+// Rows a report line may run on to
+const REPORT_MAXROWCOUNT: usize = 3;
 
 #[repr(transparent)]
 struct Buffer {
@@ -153,8 +171,10 @@ impl fmt::Write for Writer {
     }
 }
 
-pub static WRITER: Lazy<Mutex<Writer>> = Lazy::new(|| {
-    Mutex::new(Writer {
+// A task holding the writer cannot be preempted, so an interrupt handler that
+// prints on the same core never waits for it forever
+pub static WRITER: Lazy<SpinLock<Writer>> = Lazy::new(|| {
+    SpinLock::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::White, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
@@ -178,24 +198,108 @@ pub fn _print(args: fmt::Arguments) {
     WRITER.lock().write_fmt(args).unwrap();
 }
 
+// Formats a line for print_string, which needs no lock
+struct LineBuffer {
+    buffer: [u8; BUFFER_WIDTH * REPORT_MAXROWCOUNT],
+    length: usize,
+}
+
+impl LineBuffer {
+    fn new() -> Self {
+        Self {
+            buffer: [b' '; BUFFER_WIDTH * REPORT_MAXROWCOUNT],
+            length: 0,
+        }
+    }
+}
+
+impl fmt::Write for LineBuffer {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        for &byte in string.as_bytes() {
+            if self.length < self.buffer.len() {
+                self.buffer[self.length] = byte;
+                self.length += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Writes a line straight into the screen at row `y`, running on to the rows
+// below when it is long, and to the serial port when that is free. For code
+// that must not wait for WRITER: the panic handler, the watchdog and lock
+// reports, which run with other cores stopped or interrupts disabled.
+pub fn report_line(y: i32, args: fmt::Arguments) {
+    use core::fmt::Write;
+    let mut line = LineBuffer::new();
+    let _ = line.write_fmt(args);
+    let rows = ((line.length + BUFFER_WIDTH - 1) / BUFFER_WIDTH)
+        .clamp(1, BUFFER_HEIGHT.saturating_sub(y as usize).max(1));
+    print_string(0, y, &line.buffer[..rows * BUFFER_WIDTH]);
+    if let Some(mut serial) = SERIAL.try_lock() {
+        let text = core::str::from_utf8(&line.buffer[..line.length]).unwrap_or("");
+        let _ = writeln!(serial, "{}", text);
+    }
+}
+
 #[doc(hidden)]
 pub fn clear_screen() {
     WRITER.lock().clear_screen();
 }
 
+// Moves keys from the interrupt-fed key queue into the input channel, so
+// readers wait on the channel and only this task takes from the queue
+fn keyboard_task(channel_id: u64) {
+    loop {
+        let key = keyboard::WaitKeyFromKeyQueue();
+        let _ = channel::send(channel_id, key.to_u64());
+    }
+}
+
+pub fn init_input() -> Result<(), ()> {
+    let channel_id = channel::create(CONSOLE_INPUTCAPACITY)?;
+    CONSOLE_INPUTCHANNEL.store(channel_id, Ordering::Release);
+    process::create_task(
+        "keyboard",
+        PRIORITY_HIGHIST,
+        keyboard_task as u64,
+        channel_id,
+        AFFINITY_ALL,
+    )?;
+    Ok(())
+}
+
+fn is_key_down(key_data: &KeyData) -> bool {
+    (key_data.Flags & keyboard::KeyStatement::KeyFlagsDown as u8) != 0
+}
+
+// Waits for the next key press. The input channel lives as long as the
+// kernel, so a failed receive leaves only a NUL to return.
 pub fn getch() -> u8 {
+    let channel_id = CONSOLE_INPUTCHANNEL.load(Ordering::Acquire);
     loop {
-        let key_data = loop {
-            if let Ok(key) = keyboard::GetKeyFromKeyQueue() {
-                break key;
-            }
+        let key_data = match channel::receive(channel_id) {
+            Ok(message) => KeyData::from_u64(message.data),
+            Err(()) => return 0,
         };
-        if (key_data.Flags & keyboard::KeyStatement::KeyFlagsDown as u8) != 0 {
+        if is_key_down(&key_data) {
             return key_data.ASCIICode;
         }
     }
 }
 
+// Returns the next key press without waiting for one
+pub fn try_getch() -> Option<u8> {
+    let channel_id = CONSOLE_INPUTCHANNEL.load(Ordering::Acquire);
+    while let Ok(message) = channel::try_receive(channel_id) {
+        let key_data = KeyData::from_u64(message.data);
+        if is_key_down(&key_data) {
+            return Some(key_data.ASCIICode);
+        }
+    }
+    None
+}
+
 pub fn set_curser(x: usize, y: usize) {
     WRITER.lock().set_curser(x, y);
 }
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 #![allow(non_snake_case)]
 
-use crate::{interrupt, utility::memset};
+use crate::{apic, interrupt, ipi, percpu, smp::CPU_MAXCOUNT, utility::memset};
 use core::mem::size_of;
 
 const GDT_TYPE_CODE: u8 = 0x0A;
@@ -25,7 +25,7 @@ const GDT_FLAG_LOWER_TSS: u8 = GDT_FLAGS_LOWER_DPL0 | GDT_FLAGS_LOWER_P;
 const GDT_FLAG_LOWER_USERCODE: u8 =
     GDT_TYPE_CODE | GDT_FLAGS_LOWER_S | GDT_FLAGS_LOWER_DPL3 | GDT_FLAGS_LOWER_P;
 const GDT_FLAG_LOWER_USERDATA: u8 =
-    GDT_TYPE_CODE | GDT_FLAGS_LOWER_S | GDT_FLAGS_LOWER_DPL3 | GDT_FLAGS_LOWER_P;
+    GDT_TYPE_DATA | GDT_FLAGS_LOWER_S | GDT_FLAGS_LOWER_DPL3 | GDT_FLAGS_LOWER_P;
 
 const GDT_FLAGS_UPPER_CODE: u8 = GDT_FLAGS_UPPER_G | GDT_FLAGS_UPPER_L;
 const GDT_FLAGS_UPPER_DATA: u8 = GDT_FLAGS_UPPER_G | GDT_FLAGS_UPPER_L;
@@ -33,15 +33,20 @@ const GDT_FLAGS_UPPER_TSS: u8 = GDT_FLAGS_UPPER_G;
 
 pub const GDT_KERNELCODESEGMENT: u16 = 0x08;
 pub const GDT_KERNELDATASEGMENT: u16 = 0x10;
-pub const GDT_TSSSEGMENT: u16 = 0x18;
+pub const GDT_USERDATASEGMENT: u16 = 0x18;
+pub const GDT_USERCODESEGMENT: u16 = 0x20;
+pub const GDT_TSSSEGMENT: u16 = 0x28;
+
+pub const SELECTOR_RPL_0: u16 = 0x00;
+pub const SELECTOR_RPL_3: u16 = 0x03;
 
 pub const GDTR_STARTADDRESS: u64 = 0x142000;
-const GDT_MAXENTRY8COUNT: u32 = 3;
-const GDT_MAXENTRY16COUNT: u32 = 1;
+const GDT_MAXENTRY8COUNT: u32 = 5;
+// One TSS for each core
+const GDT_MAXENTRY16COUNT: u32 = CPU_MAXCOUNT as u32;
 
 const GDT_TABLESIZE: u32 = (size_of::<GDT8ENTRY>() as u32) * GDT_MAXENTRY8COUNT
     + (size_of::<GDT16ENTRY>() as u32) * GDT_MAXENTRY16COUNT;
-const TSS_SEGMENTSIZE: u32 = size_of::<TSSSEGMENT>() as u32;
 
 const IDT_TYPE_INTERRUPT: u8 = 0x0E;
 const IDT_TYPE_TRAP: u8 = 0x0F;
@@ -52,18 +57,24 @@ const IDT_FLAGS_DPL3: u8 = 0x60;
 const IDT_FLAGS_P: u8 = 0x80;
 const IDT_FLAGS_IST0: u8 = 0;
 const IDT_FLAGS_IST1: u8 = 1;
+const IDT_FLAGS_IST2: u8 = 2;
 
 const IDT_FLAGS_KERNEL: u8 = IDT_FLAGS_DPL0 | IDT_FLAGS_P;
 const IDT_FLAGS_USER: u8 = IDT_FLAGS_DPL3 | IDT_FLAGS_P;
 
-const IDT_MAXENTRYCOUNT: u16 = 100;
-pub const IDTR_STARTADDRESS: u64 =
-    GDTR_STARTADDRESS + (size_of::<GDTR>() as u64) + GDT_TABLESIZE as u64 + TSS_SEGMENTSIZE as u64;
+const IDT_MAXENTRYCOUNT: u16 = 256;
+// The TSSs live in the per-CPU areas, so the IDT follows the GDT
+pub const IDTR_STARTADDRESS: u64 = GDTR_STARTADDRESS + (size_of::<GDTR>() as u64) + GDT_TABLESIZE as u64;
 const IDT_STARTADDRESS: u64 = IDTR_STARTADDRESS + (size_of::<IDTR>() as u64);
 const IDT_TABLESIZE: u16 = IDT_MAXENTRYCOUNT * (size_of::<IDTENTRY>() as u16);
 
 pub const IST_STARTADDRESS: u32 = 0x700000;
 pub const IST_SIZE: u32 = 0x100000;
+// Each core gets an even share of the IST area. NMIs can arrive in the middle
+// of another handler, so they take the bottom of the share as a stack of
+// their own.
+pub const IST_CPUSIZE: u32 = IST_SIZE / CPU_MAXCOUNT as u32;
+pub const IST_NMISIZE: u32 = 0x4000;
 
 #[repr(C, packed(1))]
 struct GDTRStruct {
@@ -96,7 +107,7 @@ struct GDTEntry16Struct {
 }
 
 #[repr(C, packed(1))]
-struct TSSDataStruct {
+pub struct TSSDataStruct {
     dwReserved1: u32,
     Rsp: [u64; 3],
     qwReserved2: u64,
@@ -121,7 +132,7 @@ type GDTR = GDTRStruct;
 type IDTR = GDTRStruct;
 type GDT8ENTRY = GDTEntry8Struct;
 type GDT16ENTRY = GDTEntry16Struct;
-type TSSSEGMENT = TSSDataStruct;
+pub type TSSSEGMENT = TSSDataStruct;
 type IDTENTRY = IDTEntryStruct;
 
 impl GDTEntry8Struct {
@@ -148,6 +159,25 @@ impl GDTEntry16Struct {
     }
 }
 
+impl TSSDataStruct {
+    pub const fn new() -> Self {
+        Self {
+            dwReserved1: 0,
+            Rsp: [0; 3],
+            qwReserved2: 0,
+            IST: [0; 7],
+            qwReserved3: 0,
+            wReserved: 0,
+            IOMapBaseAddress: 0xFFFF,
+        }
+    }
+
+    // Stack the CPU switches to when an interrupt without IST arrives in ring 3
+    pub fn set_kernel_stack(&mut self, rsp0: u64) {
+        self.Rsp[0] = rsp0;
+    }
+}
+
 impl IDTEntryStruct {
     pub fn set(&mut self, handler: u64, selector: u16, IST: u8, flags: u8, Type: u8) {
         self.LowerBaseAddress = (handler & 0xFFFF) as u16;
@@ -163,7 +193,6 @@ impl IDTEntryStruct {
 pub fn InitializeGDTTableAndTTS() {
     let pGDTR = GDTR_STARTADDRESS as *mut GDTR;
     let pEntry = (GDTR_STARTADDRESS + (size_of::<GDTR>() as u64)) as *mut GDT8ENTRY;
-    let pTSS = ((pEntry as u64) + (GDT_TABLESIZE as u64)) as *mut TSSSEGMENT;
     unsafe {
         (*pGDTR).Limit = GDT_TABLESIZE as u16 - 1;
         (*pGDTR).BaseAddress = pEntry as u64;
@@ -182,26 +211,50 @@ pub fn InitializeGDTTableAndTTS() {
             GDT_FLAG_LOWER_KERNELDATA,
             GDT_TYPE_DATA,
         );
-        (*((pEntry.offset(3) as u64) as *mut GDT16ENTRY)).set(
-            pTSS as u64,
-            (size_of::<TSSSEGMENT>() - 1) as u32,
-            GDT_FLAGS_UPPER_TSS,
-            GDT_FLAG_LOWER_TSS,
-            GDT_TYPE_TSS,
+        (*pEntry.offset(3)).set(
+            0,
+            0xFFFFF,
+            GDT_FLAGS_UPPER_DATA,
+            GDT_FLAG_LOWER_USERDATA,
+            GDT_TYPE_DATA,
         );
-
-        InitializeTTSSegment(pTSS);
+        (*pEntry.offset(4)).set(
+            0,
+            0xFFFFF,
+            GDT_FLAGS_UPPER_CODE,
+            GDT_FLAG_LOWER_USERCODE,
+            GDT_TYPE_CODE,
+        );
+        let pTSSEntry = pEntry.offset(GDT_MAXENTRY8COUNT as isize) as *mut GDT16ENTRY;
+        for i in 0..CPU_MAXCOUNT {
+            let pTSS = percpu::get(i).tss();
+            (*pTSSEntry.add(i)).set(
+                pTSS as u64,
+                (size_of::<TSSSEGMENT>() - 1) as u32,
+                GDT_FLAGS_UPPER_TSS,
+                GDT_FLAG_LOWER_TSS,
+                GDT_TYPE_TSS,
+            );
+            InitializeTTSSegment(pTSS, i);
+        }
     }
 }
 
-fn InitializeTTSSegment(pTSS: *mut TSSSEGMENT) {
+fn InitializeTTSSegment(pTSS: *mut TSSSEGMENT, cpu: usize) {
+    let ist = IST_STARTADDRESS + IST_CPUSIZE * cpu as u32;
     memset(pTSS as *mut u8, 0, size_of::<TSSSEGMENT>() as isize);
     unsafe {
-        (*pTSS).IST[0] = (IST_STARTADDRESS + IST_SIZE) as u64;
+        (*pTSS).IST[0] = (ist + IST_CPUSIZE) as u64;
+        (*pTSS).IST[1] = (ist + IST_NMISIZE) as u64;
         (*pTSS).IOMapBaseAddress = 0xFFFF;
     }
 }
 
+// Selector of the TSS that core `cpu` loads
+pub fn get_tss_segment(cpu: usize) -> u16 {
+    GDT_TSSSEGMENT + (size_of::<GDT16ENTRY>() * cpu) as u16
+}
+
 pub fn InitializeIDTTables() {
     let pIDTR = IDTR_STARTADDRESS as *mut IDTR;
     let pEntry = (IDTR_STARTADDRESS + size_of::<IDTR>() as u64) as *mut IDTENTRY;
@@ -210,59 +263,64 @@ pub fn InitializeIDTTables() {
         (*pIDTR).BaseAddress = pEntry as u64;
         (*pIDTR).Limit = IDT_TABLESIZE - 1;
 
+        // Exceptions run on the stack they interrupted, which is the task's
+        // kernel stack from RSP0 when they come from ring 3, so a fault that
+        // ends the task leaves no shared stack behind. Only the NMI, the double
+        // fault and the machine check, which can strike on a broken stack, keep
+        // an IST.
         (*pEntry.offset(0)).set(
             interrupt::divided_by_zero as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(1)).set(
             interrupt::debug as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(2)).set(
             interrupt::NMI as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST2,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(3)).set(
             interrupt::break_point as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(4)).set(
             interrupt::overflow as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(5)).set(
             interrupt::bound_range_exceeded as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(6)).set(
             interrupt::invalid_opcode as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(7)).set(
             interrupt::device_not_avalidable as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
@@ -276,63 +334,63 @@ pub fn InitializeIDTTables() {
         (*pEntry.offset(9)).set(
             interrupt::coprocessor_segment_overrun as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(10)).set(
             interrupt::invalid_tss as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(11)).set(
             interrupt::segment_not_present as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(12)).set(
             interrupt::stack_segment_fault as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(13)).set(
             interrupt::general_protection as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(14)).set(
             interrupt::page_fault as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(15)).set(
             interrupt::ISR15 as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(16)).set(
             interrupt::FPU_error as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
         (*pEntry.offset(17)).set(
             interrupt::alignment_check as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
@@ -346,7 +404,7 @@ pub fn InitializeIDTTables() {
         (*pEntry.offset(19)).set(
             interrupt::SMID_error as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
@@ -354,16 +412,18 @@ pub fn InitializeIDTTables() {
             (*pEntry.offset(i)).set(
                 interrupt::common_exception as u64,
                 0x08,
-                IDT_FLAGS_IST1,
+                IDT_FLAGS_IST0,
                 IDT_FLAGS_KERNEL,
                 IDT_TYPE_INTERRUPT,
             );
         }
 
+        // The timer saves the interrupted context on the task's own stack, so
+        // switching tasks is only a matter of switching stacks
         (*pEntry.offset(32)).set(
             interrupt::timer as u64,
             0x08,
-            IDT_FLAGS_IST1,
+            IDT_FLAGS_IST0,
             IDT_FLAGS_KERNEL,
             IDT_TYPE_INTERRUPT,
         );
@@ -482,5 +542,28 @@ pub fn InitializeIDTTables() {
                 IDT_TYPE_INTERRUPT,
             );
         }
+        (*pEntry.offset(apic::LAPIC_TIMERVECTOR as isize)).set(
+            interrupt::local_timer as u64,
+            0x08,
+            IDT_FLAGS_IST0,
+            IDT_FLAGS_KERNEL,
+            IDT_TYPE_INTERRUPT,
+        );
+        // Not on the IST of the double fault, which would start over on top of
+        // a shootdown it struck in the middle of
+        (*pEntry.offset(ipi::IPI_CALLVECTOR as isize)).set(
+            interrupt::call_function as u64,
+            0x08,
+            IDT_FLAGS_IST0,
+            IDT_FLAGS_KERNEL,
+            IDT_TYPE_INTERRUPT,
+        );
+        (*pEntry.offset(apic::LAPIC_SPURIOUSVECTOR as isize)).set(
+            interrupt::spurious_interrupt as u64,
+            0x08,
+            IDT_FLAGS_IST0,
+            IDT_FLAGS_KERNEL,
+            IDT_TYPE_INTERRUPT,
+        );
     }
 }
@@ -0,0 +1,248 @@
+use core::{mem::size_of, ptr};
+
+use crate::{
+    memory::{alloc_frame, free_frame},
+    page::{
+        self, PAGE_FLAGS_NX, PAGE_FLAGS_RW, PAGE_FLAGS_USERDATA, PAGE_FLAGS_USERRODATA, PAGE_SIZE,
+        USER_BASEADDRESS, USER_ENDADDRESS,
+    },
+    process::{self, UserSpace},
+};
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELF_CLASS64: u8 = 2;
+const ELF_DATA2LSB: u8 = 1;
+const ELF_VERSIONCURRENT: u8 = 1;
+const ELF_TYPEEXEC: u16 = 2;
+const ELF_MACHINEX8664: u16 = 0x3E;
+
+const ELF_SEGMENTLOAD: u32 = 1;
+const ELF_SEGMENTFLAGS_X: u32 = 0x1;
+const ELF_SEGMENTFLAGS_W: u32 = 0x2;
+
+// The stack ends one unmapped guard page below the end of the user range
+const ELF_STACKTOPADDRESS: u64 = USER_ENDADDRESS - PAGE_SIZE;
+const ELF_STACKSIZE: u64 = 0x10000;
+const ELF_STACKADDRESS: u64 = ELF_STACKTOPADDRESS - ELF_STACKSIZE;
+pub const ELF_MAXARGCOUNT: usize = 16;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_header_offset: u64,
+    section_header_offset: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_count: u16,
+    section_header_size: u16,
+    section_header_count: u16,
+    section_name_index: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    virtual_address: u64,
+    physical_address: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+
+pub struct Program {
+    pub name: &'static str,
+    pub image: &'static [u8],
+}
+
+// Images built from src/programs and linked into the kernel, standing in for
+// a file system until there is one
+pub static PROGRAM_TABLE: &[Program] = &[Program {
+    name: "hello",
+    image: include_bytes!(concat!(env!("PROGRAM_DIRECTORY"), "/hello.elf")),
+}];
+
+pub fn find_program(name: &str) -> Option<&'static [u8]> {
+    PROGRAM_TABLE
+        .iter()
+        .find(|program| program.name == name)
+        .map(|program| program.image)
+}
+
+// The image may sit at any alignment, so headers are copied out of it
+fn read<T: Copy>(image: &[u8], offset: u64) -> Result<T, ()> {
+    let end = offset.checked_add(size_of::<T>() as u64).ok_or(())?;
+    if end > image.len() as u64 {
+        return Err(());
+    }
+    Ok(unsafe { ptr::read_unaligned(image.as_ptr().add(offset as usize) as *const T) })
+}
+
+fn read_header(image: &[u8]) -> Result<ElfHeader, ()> {
+    let header: ElfHeader = read(image, 0)?;
+    if header.ident[0..4] != ELF_MAGIC
+        || header.ident[4] != ELF_CLASS64
+        || header.ident[5] != ELF_DATA2LSB
+        || header.ident[6] != ELF_VERSIONCURRENT
+        || header.kind != ELF_TYPEEXEC
+        || header.machine != ELF_MACHINEX8664
+        || header.program_header_size as usize != size_of::<ProgramHeader>()
+        || header.entry < USER_BASEADDRESS
+        || header.entry >= ELF_STACKADDRESS
+    {
+        return Err(());
+    }
+    Ok(header)
+}
+
+// Copies into another address space through the identity mapping of the
+// frames behind it, one page at a time
+fn write_user(pml4: u64, address: u64, data: &[u8]) -> Result<(), ()> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let current = address + offset as u64;
+        let physical = page::translate(pml4, current).ok_or(())?;
+        let length = ((PAGE_SIZE - (current & (PAGE_SIZE - 1))) as usize).min(data.len() - offset);
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr().add(offset), physical as *mut u8, length);
+        }
+        offset += length;
+    }
+    Ok(())
+}
+
+fn map_range(pml4: u64, address: u64, size: u64, flags: u64) -> Result<(), ()> {
+    let mut page = address & !(PAGE_SIZE - 1);
+    while page < address + size {
+        if page::translate(pml4, page).is_some() {
+            page::update_page(pml4, page, flags)?;
+        } else {
+            let frame = alloc_frame().ok_or(())?;
+            if let Err(()) = page::map_page(pml4, page, frame, flags) {
+                let _ = free_frame(frame);
+                return Err(());
+            }
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+// Maps every PT_LOAD segment. Frames come zeroed, so whatever lies past the
+// file size, the BSS included, is already cleared.
+fn load_segments(pml4: u64, image: &[u8], header: &ElfHeader) -> Result<(u64, u64), ()> {
+    let mut start = u64::MAX;
+    let mut end = 0;
+    for index in 0..header.program_header_count as u64 {
+        let offset = header.program_header_offset + index * size_of::<ProgramHeader>() as u64;
+        let segment: ProgramHeader = read(image, offset)?;
+        if segment.kind != ELF_SEGMENTLOAD || segment.memory_size == 0 {
+            continue;
+        }
+        let segment_end = segment.virtual_address.checked_add(segment.memory_size).ok_or(())?;
+        let file_end = segment.offset.checked_add(segment.file_size).ok_or(())?;
+        if segment.file_size > segment.memory_size
+            || file_end > image.len() as u64
+            || segment.virtual_address < USER_BASEADDRESS
+            || segment_end > ELF_STACKADDRESS
+        {
+            return Err(());
+        }
+
+        let mut flags = PAGE_FLAGS_USERRODATA;
+        if segment.flags & ELF_SEGMENTFLAGS_W != 0 {
+            flags |= PAGE_FLAGS_RW;
+        }
+        if segment.flags & ELF_SEGMENTFLAGS_X != 0 {
+            flags &= !PAGE_FLAGS_NX;
+        }
+        map_range(pml4, segment.virtual_address, segment.memory_size, flags)?;
+        write_user(
+            pml4,
+            segment.virtual_address,
+            &image[segment.offset as usize..file_end as usize],
+        )?;
+
+        start = start.min(segment.virtual_address);
+        end = end.max(segment_end);
+    }
+    if start >= end {
+        return Err(());
+    }
+    Ok((start, end - start))
+}
+
+// Lays out the stack the way the System V ABI expects at the entry point:
+// argc, the argv pointers, an empty envp and an empty auxiliary vector, with
+// the strings themselves above them
+fn setup_stack(pml4: u64, args: &[&str]) -> Result<u64, ()> {
+    if args.len() > ELF_MAXARGCOUNT {
+        return Err(());
+    }
+    map_range(pml4, ELF_STACKADDRESS, ELF_STACKSIZE, PAGE_FLAGS_USERDATA)?;
+
+    let mut pointer = ELF_STACKTOPADDRESS;
+    let mut argv = [0u64; ELF_MAXARGCOUNT];
+    for (index, arg) in args.iter().enumerate().rev() {
+        pointer = pointer.checked_sub(arg.len() as u64 + 1).ok_or(())?;
+        if pointer < ELF_STACKADDRESS + PAGE_SIZE {
+            return Err(());
+        }
+        write_user(pml4, pointer, arg.as_bytes())?;
+        write_user(pml4, pointer + arg.len() as u64, &[0])?;
+        argv[index] = pointer;
+    }
+
+    // argc, argv[], NULL, envp NULL, AT_NULL pair
+    let count = args.len() + 5;
+    pointer &= !0xF;
+    if count % 2 != 0 {
+        pointer -= size_of::<u64>() as u64;
+    }
+    pointer -= (count * size_of::<u64>()) as u64;
+
+    let mut address = pointer;
+    let mut push = |value: u64| -> Result<(), ()> {
+        write_user(pml4, address, &value.to_le_bytes())?;
+        address += size_of::<u64>() as u64;
+        Ok(())
+    };
+    push(args.len() as u64)?;
+    for arg in &argv[..args.len()] {
+        push(*arg)?;
+    }
+    for _ in 0..4 {
+        push(0)?;
+    }
+    Ok(pointer)
+}
+
+// Loads an executable into a new address space and starts it as a process;
+// args[0] is passed on as the program name
+pub fn execute(image: &[u8], args: &[&str], flags: u64) -> Result<u64, ()> {
+    let header = read_header(image)?;
+    let pml4 = page::create_address_space()?;
+
+    let result = load_segments(pml4, image, &header).and_then(|(address, size)| {
+        let space = UserSpace {
+            page_table: pml4,
+            stack_pointer: setup_stack(pml4, args)?,
+            memory_address: address,
+            memory_size: size,
+        };
+        let name = args.first().copied().unwrap_or("");
+        process::create_user_process(name, flags, header.entry, &space)
+    });
+    if let Err(()) = result {
+        page::destroy_address_space(pml4);
+    }
+    result
+}
@@ -1,16 +1,21 @@
 use crate::{
     assembly::{self, EnableInterrupt},
-    console, descriptor, keyboard,
+    console, descriptor, fpu, keyboard, memory, page, pci,
+    percpu::init_percpu,
     pic::{InitializePIC, MaskedPICInterrupt},
     println,
     process::{self, create_task, init_scheduler},
     shell::start_shell,
+    serial, smp, syscall,
     timer::{convert_from_ms, init_PIT},
     utility::{check_ram_size, get_ram_size},
+    watchdog,
 };
 
 #[allow(unconditional_panic)]
 pub fn entry() {
+    // Whatever asks for the running core finds it through GS
+    init_percpu(0);
     console::init_console(0, 10);
     println!("Swtich to IA-32e Mode.......................[Pass]");
     println!("IA-32e Rust Kernel Start....................[Pass]");
@@ -37,12 +42,40 @@ pub fn entry() {
     y += 1;
     println!("Pass");
 
+    println!("System Call MSR Initialize..................[    ]");
+    syscall::init_syscall();
+    console::set_curser(45, y);
+    y += 1;
+    println!("Pass");
+
+    println!("FPU And SSE Initialize......................[    ]");
+    console::set_curser(45, y);
+    if fpu::init_FPU_unit() {
+        println!("Pass");
+    } else {
+        println!("Fail");
+    }
+    y += 1;
+
     println!("Total RAM Size Check........................[    ]");
     check_ram_size();
     console::set_curser(45, y);
     y += 1;
     println!("Pass], {} MB", get_ram_size());
 
+    println!("Page Table And Frame Allocator Initialize...[    ]");
+    page::init_page();
+    memory::init_memory();
+    console::set_curser(45, y);
+    y += 1;
+    println!("Pass], {} KB Free", memory::free_frame_count() * memory::FRAME_SIZE / 1024);
+
+    println!("Serial Port Initialize......................[    ]");
+    serial::init_serial();
+    console::set_curser(45, y);
+    y += 1;
+    println!("Pass");
+
     println!("PCB Pool And Scheduler Initialize...........[Pass]");
     init_scheduler();
     init_PIT(convert_from_ms(1) as u16, true);
@@ -64,14 +97,39 @@ pub fn entry() {
     MaskedPICInterrupt(0);
     EnableInterrupt();
     console::set_curser(45, y);
+    y += 1;
+    println!("Pass");
+
+    println!("Watchdog Initialize.........................[    ]");
+    watchdog::init_watchdog();
+    console::set_curser(45, y);
+    y += 1;
     println!("Pass");
 
+    println!("PCI Bus Enumeration.........................[    ]");
+    let device_count = pci::init_pci();
+    console::set_curser(45, y);
+    y += 1;
+    println!("Pass], {} Devices", device_count);
+
+    println!("Application Processor Start.................[    ]");
+    let count = smp::start_application_processors();
+    console::set_curser(45, y);
+    println!("Pass], {} Cores Online", count);
+
     if let Err(()) = create_task(
+        "idle",
         process::PRIORITY_LOWIST | process::PROCESS_FLAG_IDLETASK,
         process::idle_process as u64,
+        0,
+        1,
     ) {
         println!("Idle Task initalization Failed");
         loop {}
     };
+    if let Err(()) = console::init_input() {
+        println!("Keyboard Task initalization Failed");
+        loop {}
+    }
     start_shell();
 }
@@ -0,0 +1,184 @@
+#![allow(non_snake_case)]
+use core::arch::asm;
+
+use crate::assembly::read_cpuid;
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR0_TS: u64 = 1 << 3;
+const CR0_NE: u64 = 1 << 5;
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+const CPUID_FEATURE_FXSR: u32 = 1 << 24;
+const CPUID_FEATURE_XSAVE: u32 = 1 << 26;
+const CPUID_FEATURE_AVX: u32 = 1 << 28;
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+pub const FPU_CONTEXTSIZE: usize = 1024;
+
+#[repr(C, align(64))]
+pub struct FPUContext {
+    area: [u8; FPU_CONTEXTSIZE],
+}
+
+static mut USE_XSAVE: bool = false;
+static mut XSAVE_MASK: u64 = 0;
+static mut INIT_CONTEXT: FPUContext = FPUContext::empty();
+
+impl FPUContext {
+    pub const fn empty() -> Self {
+        Self {
+            area: [0; FPU_CONTEXTSIZE],
+        }
+    }
+}
+
+// Enables x87/SSE, and AVX through XSAVE when the processor has it. Returns
+// false when even FXSAVE is missing, in which case tasks cannot use the FPU.
+pub fn init_FPU_unit() -> bool {
+    let feature = read_cpuid(1, 0);
+    if feature.edx & CPUID_FEATURE_FXSR == 0 {
+        return false;
+    }
+
+    let cr0 = (read_CR0() & !CR0_EM) | CR0_MP | CR0_NE;
+    write_CR0(cr0);
+    let mut cr4 = read_CR4() | CR4_OSFXSR | CR4_OSXMMEXCPT;
+
+    if feature.ecx & CPUID_FEATURE_XSAVE != 0 {
+        cr4 |= CR4_OSXSAVE;
+        write_CR4(cr4);
+
+        let mut mask = XCR0_X87 | XCR0_SSE;
+        if feature.ecx & CPUID_FEATURE_AVX != 0 {
+            mask |= XCR0_AVX;
+        }
+        write_XCR0(mask);
+        // EBX of leaf 0xD is the save area size for the components enabled in XCR0
+        if read_cpuid(0xD, 0).ebx as usize > FPU_CONTEXTSIZE {
+            mask = XCR0_X87 | XCR0_SSE;
+            write_XCR0(mask);
+        }
+        unsafe {
+            USE_XSAVE = true;
+            XSAVE_MASK = mask;
+        }
+    } else {
+        write_CR4(cr4);
+    }
+
+    clear_TS();
+    unsafe {
+        asm!(
+            "fninit",
+            "ldmxcsr [{0}]",
+            in(reg) &MXCSR_DEFAULT,
+            options(nostack)
+        );
+        save_FPU_context(&mut *core::ptr::addr_of_mut!(INIT_CONTEXT));
+    }
+    set_TS();
+    true
+}
+
+pub fn save_FPU_context(context: &mut FPUContext) {
+    unsafe {
+        if USE_XSAVE {
+            asm!(
+                "xsave64 [{0}]",
+                in(reg) context.area.as_mut_ptr(),
+                in("eax") XSAVE_MASK as u32,
+                in("edx") (XSAVE_MASK >> 32) as u32,
+                options(nostack)
+            );
+        } else {
+            asm!(
+                "fxsave64 [{0}]",
+                in(reg) context.area.as_mut_ptr(),
+                options(nostack)
+            );
+        }
+    }
+}
+
+pub fn load_FPU_context(context: &FPUContext) {
+    unsafe {
+        if USE_XSAVE {
+            asm!(
+                "xrstor64 [{0}]",
+                in(reg) context.area.as_ptr(),
+                in("eax") XSAVE_MASK as u32,
+                in("edx") (XSAVE_MASK >> 32) as u32,
+                options(nostack)
+            );
+        } else {
+            asm!(
+                "fxrstor64 [{0}]",
+                in(reg) context.area.as_ptr(),
+                options(nostack)
+            );
+        }
+    }
+}
+
+// Puts the FPU back into the state it had right after init_FPU_unit
+pub fn init_FPU() {
+    load_FPU_context(unsafe { &*core::ptr::addr_of!(INIT_CONTEXT) });
+}
+
+pub fn set_TS() {
+    write_CR0(read_CR0() | CR0_TS);
+}
+
+pub fn clear_TS() {
+    unsafe {
+        asm!("clts", options(nostack));
+    }
+}
+
+fn read_CR0() -> u64 {
+    let cr0: u64;
+    unsafe {
+        asm!("mov {0}, cr0", out(reg) cr0, options(nostack));
+    }
+    cr0
+}
+
+fn write_CR0(cr0: u64) {
+    unsafe {
+        asm!("mov cr0, {0}", in(reg) cr0, options(nostack));
+    }
+}
+
+fn read_CR4() -> u64 {
+    let cr4: u64;
+    unsafe {
+        asm!("mov {0}, cr4", out(reg) cr4, options(nostack));
+    }
+    cr4
+}
+
+fn write_CR4(cr4: u64) {
+    unsafe {
+        asm!("mov cr4, {0}", in(reg) cr4, options(nostack));
+    }
+}
+
+fn write_XCR0(mask: u64) {
+    unsafe {
+        asm!(
+            "xsetbv",
+            in("ecx") 0,
+            in("eax") mask as u32,
+            in("edx") (mask >> 32) as u32,
+            options(nostack)
+        );
+    }
+}
@@ -1,79 +1,163 @@
 use crate::{
+    apic, ipi,
     keyboard::{ConvertScanCodeAndPutQueue, GetKeyboardScanCode, IsOutputBufferFull},
+    percpu::KernelGs,
     pic::{self, SendEOI},
-    print_string, process,
+    print_string, println,
+    process::{self, Context},
+    timer,
+    trace::{self, TraceKind},
     utility::set_interrupt_flag,
+    watchdog,
 };
 
-pub extern "x86-interrupt" fn divided_by_zero() {
-    CommonExceptionHandler(0);
+#[repr(C)]
+pub struct InterruptStackFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
 }
-pub extern "x86-interrupt" fn debug() {
-    CommonExceptionHandler(1);
+
+pub extern "x86-interrupt" fn divided_by_zero(frame: InterruptStackFrame) {
+    ExceptionHandler(0, &frame);
 }
-pub extern "x86-interrupt" fn NMI() {
-    CommonExceptionHandler(2);
+pub extern "x86-interrupt" fn debug(frame: InterruptStackFrame) {
+    ExceptionHandler(1, &frame);
 }
-pub extern "x86-interrupt" fn break_point() {
-    CommonExceptionHandler(3);
+pub extern "x86-interrupt" fn break_point(frame: InterruptStackFrame) {
+    ExceptionHandler(3, &frame);
 }
-pub extern "x86-interrupt" fn overflow() {
-    CommonExceptionHandler(4);
+pub extern "x86-interrupt" fn overflow(frame: InterruptStackFrame) {
+    ExceptionHandler(4, &frame);
 }
-pub extern "x86-interrupt" fn bound_range_exceeded() {
-    CommonExceptionHandler(5);
+pub extern "x86-interrupt" fn bound_range_exceeded(frame: InterruptStackFrame) {
+    ExceptionHandler(5, &frame);
 }
-pub extern "x86-interrupt" fn invalid_opcode() {
-    CommonExceptionHandler(6);
+pub extern "x86-interrupt" fn invalid_opcode(frame: InterruptStackFrame) {
+    ExceptionHandler(6, &frame);
 }
 pub extern "x86-interrupt" fn device_not_avalidable() {
-    CommonExceptionHandler(7);
+    let _gs = KernelGs::enter();
+    process::device_not_available();
 }
 pub extern "x86-interrupt" fn double_fault() {
     CommonExceptionHandler(8);
 }
-pub extern "x86-interrupt" fn coprocessor_segment_overrun() {
-    CommonExceptionHandler(9);
+pub extern "x86-interrupt" fn coprocessor_segment_overrun(frame: InterruptStackFrame) {
+    ExceptionHandler(9, &frame);
 }
-pub extern "x86-interrupt" fn invalid_tss() {
-    CommonExceptionHandler(10);
+pub extern "x86-interrupt" fn invalid_tss(frame: InterruptStackFrame, _error_code: u64) {
+    ExceptionHandler(10, &frame);
 }
-pub extern "x86-interrupt" fn segment_not_present() {
-    CommonExceptionHandler(11);
+pub extern "x86-interrupt" fn segment_not_present(frame: InterruptStackFrame, _error_code: u64) {
+    ExceptionHandler(11, &frame);
 }
-pub extern "x86-interrupt" fn stack_segment_fault() {
-    CommonExceptionHandler(12);
+pub extern "x86-interrupt" fn stack_segment_fault(frame: InterruptStackFrame, _error_code: u64) {
+    ExceptionHandler(12, &frame);
 }
-pub extern "x86-interrupt" fn general_protection() {
-    CommonExceptionHandler(13);
+pub extern "x86-interrupt" fn general_protection(frame: InterruptStackFrame, _error_code: u64) {
+    ExceptionHandler(13, &frame);
 }
-pub extern "x86-interrupt" fn page_fault() {
-    CommonExceptionHandler(14);
+pub extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, _error_code: u64) {
+    ExceptionHandler(14, &frame);
 }
-pub extern "x86-interrupt" fn ISR15() {
-    CommonExceptionHandler(15);
+pub extern "x86-interrupt" fn ISR15(frame: InterruptStackFrame) {
+    ExceptionHandler(15, &frame);
 }
-pub extern "x86-interrupt" fn FPU_error() {
-    CommonExceptionHandler(16);
+pub extern "x86-interrupt" fn FPU_error(frame: InterruptStackFrame) {
+    ExceptionHandler(16, &frame);
 }
-pub extern "x86-interrupt" fn alignment_check() {
-    CommonExceptionHandler(17);
+pub extern "x86-interrupt" fn alignment_check(frame: InterruptStackFrame, _error_code: u64) {
+    ExceptionHandler(17, &frame);
 }
 pub extern "x86-interrupt" fn machine_check() {
     CommonExceptionHandler(18);
 }
-pub extern "x86-interrupt" fn SMID_error() {
-    CommonExceptionHandler(19);
+pub extern "x86-interrupt" fn SMID_error(frame: InterruptStackFrame) {
+    ExceptionHandler(19, &frame);
 }
-pub extern "x86-interrupt" fn common_exception() {
-    CommonExceptionHandler(20);
+pub extern "x86-interrupt" fn common_exception(frame: InterruptStackFrame) {
+    ExceptionHandler(20, &frame);
 }
 
+// Saves the interrupted registers in Context layout so the handler can
+// inspect and redirect the code it stopped
+#[naked]
+pub fn NMI() {
+    use core::arch::asm;
+    unsafe {
+        asm!(
+        "push rbp",
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rdi",
+        "push rsi",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov ax, ds",
+        "push rax",
+        "mov ax, es",
+        "push rax",
+        "mov ax, fs",
+        "push rax",
+        "mov ax, gs",
+        "push rax",
+
+        "mov rdi, rsp",
+        "call {func}",
+
+        // Loading GS would reset the base of the per-CPU area
+        "pop rax",
+        "pop rax",
+        "mov fs, ax",
+        "pop rax",
+        "mov es, ax",
+        "pop rax",
+        "mov ds, ax",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rsi",
+        "pop rdi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "pop rbp",
+        "iretq",
+        func = sym NMIHandler,
+        options(noreturn));
+    }
+}
+
+// Runs on the interrupted task's own stack, where the pushes below complete
+// the frame the CPU pushed into a Context. The handler returns the stack
+// pointer of the task to resume, which may be another task's.
 #[naked]
 pub fn timer() {
     use core::arch::asm;
     unsafe {
         asm!(
+        // Coming from ring 3, the kernel GS is loaded for the handler
+        "test qword ptr [rsp + 8], 3",
+        "jz 2f",
+        "swapgs",
+        "2:",
         "push rbp",
         "push rax",
         "push rbx",
@@ -99,10 +183,85 @@ pub fn timer() {
         "push rax",
         
         "mov rdi, 32",
+        "mov rsi, rsp",
+        "call {func}",
+        "mov rsp, rax",
+
+        // Loading GS would reset the base of the per-CPU area
+        "pop rax",
+        "pop rax",
+        "mov fs, ax",
+        "pop rax",
+        "mov es, ax",
+        "pop rax",
+        "mov ds, ax",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rsi",
+        "pop rdi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "pop rbp",
+        // The user GS goes back when the task to resume runs in ring 3
+        "test qword ptr [rsp + 8], 3",
+        "jz 3f",
+        "swapgs",
+        "3:",
+        "iretq",
+        func = sym TimerHandler,
+        options(noreturn));
+    }
+}
+// Same as timer, for the local APIC timer of the application processors
+#[naked]
+pub fn local_timer() {
+    use core::arch::asm;
+    unsafe {
+        asm!(
+        // Coming from ring 3, the kernel GS is loaded for the handler
+        "test qword ptr [rsp + 8], 3",
+        "jz 2f",
+        "swapgs",
+        "2:",
+        "push rbp",
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rdi",
+        "push rsi",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov ax, ds",
+        "push rax",
+        "mov ax, es",
+        "push rax",
+        "mov ax, fs",
+        "push rax",
+        "mov ax, gs",
+        "push rax",
+        
+        "mov rdi, 48",
+        "mov rsi, rsp",
         "call {func}",
+        "mov rsp, rax",
 
+        // Loading GS would reset the base of the per-CPU area
         "pop rax",
-        "mov gs, ax",
         "pop rax",
         "mov fs, ax",
         "pop rax",
@@ -124,6 +283,11 @@ pub fn timer() {
         "pop rbx",
         "pop rax",
         "pop rbp",
+        // The user GS goes back when the task to resume runs in ring 3
+        "test qword ptr [rsp + 8], 3",
+        "jz 3f",
+        "swapgs",
+        "3:",
         "iretq",
         func = sym TimerHandler,
         options(noreturn));
@@ -177,6 +341,26 @@ pub extern "x86-interrupt" fn hdd2() {
 pub extern "x86-interrupt" fn common_interrupt() {
     CommonInterruptHandler(48);
 }
+pub extern "x86-interrupt" fn call_function() {
+    CallFunctionHandler(ipi::IPI_CALLVECTOR);
+}
+// A spurious interrupt is not in service in the local APIC or the PIC, so it
+// is returned from without an EOI
+pub extern "x86-interrupt" fn spurious_interrupt() {}
+
+// A fault raised in ring 3 only ends the task that caused it
+fn ExceptionHandler(vector: u8, frame: &InterruptStackFrame) {
+    let _gs = KernelGs::enter();
+    if frame.cs & 0x3 == 0x3 {
+        let pid = process::get_pid();
+        println!(
+            "[USER] Task ID[0x{:X}] Exception Vector {} At RIP[0x{:X}], Task Ended",
+            pid, vector, frame.rip
+        );
+        process::end_process(pid, process::PROCESS_EXITCODE_FAULT);
+    }
+    CommonExceptionHandler(vector);
+}
 
 fn CommonExceptionHandler(vector: u8) {
     let buffer: [u8; 2] = [vector / 10 + '0' as u8, vector % 10 + '0' as u8];
@@ -204,6 +388,8 @@ fn CommonExceptionHandler(vector: u8) {
 }
 
 fn CommonInterruptHandler(vector: u8) {
+    let _gs = KernelGs::enter();
+    trace::record(TraceKind::IrqEnter, vector as u64, 0);
     let mut buffer = b"[INT:  , ]".clone();
     static mut common_count: u8 = 0;
     buffer[5] = vector / 10 + '0' as u8;
@@ -214,9 +400,20 @@ fn CommonInterruptHandler(vector: u8) {
     }
     print_string(70, 0, &buffer);
     SendEOI((vector - pic::PIC_IRQSTARTVECTOR) as u16);
+    trace::record(TraceKind::IrqExit, vector as u64, 0);
 }
 
-fn TimerHandler(vector: u8) {
+// Besides the watchdog, NMIs come from a panicking core stopping the others
+extern "C" fn NMIHandler(context: &mut Context) {
+    let _gs = KernelGs::enter();
+    ipi::check_stop();
+    if !watchdog::handle_nmi(context) {
+        CommonExceptionHandler(2);
+    }
+}
+
+extern "C" fn TimerHandler(vector: u8, stack_pointer: u64) -> u64 {
+    trace::record(TraceKind::IrqEnter, vector as u64, 0);
     let mut buffer = b"[INT:  , ]".clone();
     static mut common_count: u8 = 0;
     buffer[5] = vector / 10 + '0' as u8;
@@ -227,15 +424,37 @@ fn TimerHandler(vector: u8) {
     }
     print_string(70, 0, &buffer);
 
-    SendEOI((vector - pic::PIC_IRQSTARTVECTOR) as u16);
-
+    // The PIT counts the time on the bootstrap processor; the other cores
+    // only take their scheduling tick from their local APIC timer
+    let ticks = if vector == apic::LAPIC_TIMERVECTOR {
+        apic::send_local_eoi();
+        1
+    } else {
+        SendEOI((vector - pic::PIC_IRQSTARTVECTOR) as u16);
+        timer::increase_tick_count()
+    };
+    process::account_ticks(ticks);
     process::decrease_time();
+    process::balance_load();
+    trace::record(TraceKind::IrqExit, vector as u64, 0);
     if process::is_expired() {
-        process::schedule();
+        process::schedule(stack_pointer)
+    } else {
+        stack_pointer
     }
 }
 
+fn CallFunctionHandler(vector: u8) {
+    let _gs = KernelGs::enter();
+    trace::record(TraceKind::IrqEnter, vector as u64, 0);
+    ipi::handle_call();
+    apic::send_local_eoi();
+    trace::record(TraceKind::IrqExit, vector as u64, 0);
+}
+
 fn KeyboardHandler(vector: u8) {
+    let _gs = KernelGs::enter();
+    trace::record(TraceKind::IrqEnter, vector as u64, 0);
     let mut buffer = b"[INT:  , ]".clone();
     static mut keyboard_count: u8 = 0;
     buffer[5] = vector / 10 + '0' as u8;
@@ -252,6 +471,7 @@ fn KeyboardHandler(vector: u8) {
     }
 
     SendEOI((vector - pic::PIC_IRQSTARTVECTOR) as u16);
+    trace::record(TraceKind::IrqExit, vector as u64, 0);
 }
 
 pub fn without_interrupt<F, T>(mut f: F) -> T
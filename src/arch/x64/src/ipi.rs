@@ -0,0 +1,144 @@
+use core::{
+    hint,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+    apic,
+    assembly::{halt, DisableInterrupt},
+    interrupt, smp,
+    spinlock::SpinLock,
+};
+
+// Vector of the cross-CPU function call, right after the local APIC timer
+pub const IPI_CALLVECTOR: u8 = 49;
+
+// A panicking core gives the others this many rounds to stop before it goes
+// on without them
+const IPI_STOPWAITCOUNT: u64 = 10_000_000;
+const IPI_NOCPU: usize = usize::MAX;
+
+#[derive(Clone, Copy)]
+pub enum IpiTarget {
+    Cpu(usize),
+    All,
+    AllButSelf,
+}
+
+// One call is in flight at a time. Its function and argument stay put until
+// every core in IPI_CALLPENDING has run it and cleared its bit.
+static IPI_CALLLOCK: SpinLock<()> = SpinLock::new(());
+static IPI_CALLFUNCTION: AtomicU64 = AtomicU64::new(0);
+static IPI_CALLARGUMENT: AtomicU64 = AtomicU64::new(0);
+static IPI_CALLPENDING: AtomicU64 = AtomicU64::new(0);
+
+static IPI_STOPPINGCPU: AtomicUsize = AtomicUsize::new(IPI_NOCPU);
+static IPI_STOPPEDCOUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Online cores the target names
+fn target_mask(target: IpiTarget, cpu: usize) -> u64 {
+    let mask = match target {
+        IpiTarget::Cpu(target) => 1 << target,
+        IpiTarget::All => u64::MAX,
+        IpiTarget::AllButSelf => !(1 << cpu),
+    };
+    // The running core counts even before it is marked online
+    mask & (smp::online_mask() | 1 << cpu)
+}
+
+pub fn send(target: IpiTarget, vector: u8) {
+    match target {
+        IpiTarget::Cpu(cpu) => apic::send_fixed_ipi(smp::get_apic_id(cpu), vector),
+        IpiTarget::All => apic::broadcast_fixed_ipi(vector, true),
+        IpiTarget::AllButSelf => apic::broadcast_fixed_ipi(vector, false),
+    }
+}
+
+// Runs `function` with `argument` on the target cores and returns once all
+// of them are done. The caller must not hold a spin lock, since a core
+// spinning on it with interrupts disabled could never answer.
+pub fn call_function(target: IpiTarget, function: fn(u64), argument: u64) {
+    // A core waiting for its turn answers the call that is in flight, so two
+    // cores calling each other cannot deadlock
+    let _call = loop {
+        if let Some(guard) = IPI_CALLLOCK.try_lock() {
+            break guard;
+        }
+        handle_call();
+        hint::spin_loop();
+    };
+
+    let cpu = smp::current_cpu();
+    let mask = target_mask(target, cpu);
+    IPI_CALLFUNCTION.store(function as u64, Ordering::Relaxed);
+    IPI_CALLARGUMENT.store(argument, Ordering::Relaxed);
+    IPI_CALLPENDING.store(mask, Ordering::Release);
+
+    if mask & !(1 << cpu) != 0 {
+        match target {
+            IpiTarget::Cpu(target) => send(IpiTarget::Cpu(target), IPI_CALLVECTOR),
+            _ => send(IpiTarget::AllButSelf, IPI_CALLVECTOR),
+        }
+    }
+    handle_call();
+    while IPI_CALLPENDING.load(Ordering::Acquire) != 0 {
+        hint::spin_loop();
+    }
+}
+
+// Called for IPI_CALLVECTOR, and by cores waiting to make a call of their own
+pub fn handle_call() {
+    interrupt::without_interrupt(|| {
+        let bit = 1 << smp::current_cpu();
+        if IPI_CALLPENDING.load(Ordering::Acquire) & bit == 0 {
+            return;
+        }
+        let function: fn(u64) = unsafe {
+            core::mem::transmute(IPI_CALLFUNCTION.load(Ordering::Relaxed) as usize)
+        };
+        function(IPI_CALLARGUMENT.load(Ordering::Relaxed));
+        IPI_CALLPENDING.fetch_and(!bit, Ordering::Release);
+    })
+}
+
+fn halt_forever() -> ! {
+    loop {
+        DisableInterrupt();
+        halt();
+    }
+}
+
+// Called by the panic handler, so that the other cores stop touching what
+// it is about to report. When another core is already panicking, this one
+// stops as well.
+pub fn stop_other_cpus() {
+    let cpu = smp::current_cpu();
+    match IPI_STOPPINGCPU.compare_exchange(IPI_NOCPU, cpu, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {}
+        Err(stopping) if stopping == cpu => return,
+        Err(_) => halt_forever(),
+    }
+
+    let others = smp::online_count().saturating_sub(1);
+    if others == 0 {
+        return;
+    }
+    apic::broadcast_nmi();
+    for _ in 0..IPI_STOPWAITCOUNT {
+        if IPI_STOPPEDCOUNT.load(Ordering::Acquire) >= others {
+            break;
+        }
+        hint::spin_loop();
+    }
+}
+
+// Called first thing from the NMI handler. Never returns on a core that is
+// being stopped.
+pub fn check_stop() {
+    let stopping = IPI_STOPPINGCPU.load(Ordering::Acquire);
+    if stopping == IPI_NOCPU || stopping == smp::current_cpu() {
+        return;
+    }
+    IPI_STOPPEDCOUNT.fetch_add(1, Ordering::Release);
+    halt_forever();
+}
@@ -1,11 +1,13 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
-use spin::{Lazy, Mutex};
+use spin::Lazy;
 
 use crate::{
     assembly::{InPortByte, OutPortByte},
-    interrupt, println,
+    println,
+    process::{self, BlockReason, WaitQueue},
+    spinlock::SpinLock,
     types::StaticQueue,
     utility::set_interrupt_flag,
 };
@@ -75,6 +77,19 @@ impl KeyData {
             Flags: 0,
         }
     }
+
+    // Packed into a single word to travel through a channel
+    pub fn to_u64(&self) -> u64 {
+        self.ScanCode as u64 | (self.ASCIICode as u64) << 8 | (self.Flags as u64) << 16
+    }
+
+    pub fn from_u64(value: u64) -> Self {
+        Self {
+            ScanCode: value as u8,
+            ASCIICode: (value >> 8) as u8,
+            Flags: (value >> 16) as u8,
+        }
+    }
 }
 
 #[repr(packed(1))]
@@ -208,10 +223,9 @@ pub fn Reboot() {
             break;
         }
     }
+    // Returns when the controller did not reset the machine
     OutPortByte(0x64, 0xD1);
     OutPortByte(0x60, 0x00);
-
-    loop {}
 }
 
 static mut gs_stKeyboardManager: KeyboardManager = KeyboardManager {
@@ -230,12 +244,15 @@ static mut KeyBuffer: [KeyData; KEY_MAXQUEUECOUNT] = [KeyData {
     Flags: 0,
 }; 100];
 
-static mut KeyQueue: Lazy<Mutex<StaticQueue<KeyData>>> = Lazy::new(|| {
-    Mutex::new(StaticQueue::new(KEY_MAXQUEUECOUNT, unsafe {
+static mut KeyQueue: Lazy<SpinLock<StaticQueue<KeyData>>> = Lazy::new(|| {
+    SpinLock::new(StaticQueue::new(KEY_MAXQUEUECOUNT, unsafe {
         &mut KeyBuffer
     }))
 });
 
+// Tasks waiting for the interrupt handler to put a key into KeyQueue
+static KEY_WAITQUEUE: SpinLock<WaitQueue> = SpinLock::new(WaitQueue::new());
+
 static KeyMappingTable: [KeyMappingEntry; KEY_MAPPINGTABLEMAXCOUNT] = [
     /*  0   */ KeyMappingEntry(KeySpecial::None as u8, KeySpecial::None as u8),
     /*  1   */ KeyMappingEntry(KeySpecial::Esc as u8, KeySpecial::Esc as u8),
@@ -467,21 +484,21 @@ pub fn ConvertScanCodeAndPutQueue(ScanCode: u8) -> bool {
     let mut result = false;
 
     if ConvertScanCodeToASCIICode(ScanCode, &mut key_data.ASCIICode, &mut key_data.Flags) {
-        let previous_interrupt = set_interrupt_flag(false);
-        unsafe {
-            KeyQueue.force_unlock();
-            result = KeyQueue.lock().enqueue(key_data);
-        }
-        set_interrupt_flag(previous_interrupt);
+        result = unsafe { KeyQueue.lock().enqueue(key_data) };
+    }
+    if result {
+        KEY_WAITQUEUE.lock().wake_all(BlockReason::Key);
     }
     result
 }
 
 pub fn GetKeyFromKeyQueue() -> Result<KeyData, ()> {
-    unsafe {
-        if KeyQueue.lock().is_empty() {
-            return Err(());
-        }
-        interrupt::without_interrupt(|| KeyQueue.lock().dequeue())
-    }
+    unsafe { KeyQueue.lock().dequeue() }
+}
+
+// Waits until the keyboard interrupt puts a key into the queue
+pub fn WaitKeyFromKeyQueue() -> KeyData {
+    process::wait_on(BlockReason::Key, &KEY_WAITQUEUE, |wait_queue| {
+        GetKeyFromKeyQueue().map_err(|()| wait_queue)
+    })
 }
@@ -7,18 +7,35 @@
 use core::arch::asm;
 use core::panic::PanicInfo;
 
+pub mod acpi;
+pub mod apic;
 pub mod assembly;
+pub mod channel;
 pub mod console;
 pub mod descriptor;
+pub mod elf;
 pub mod entry;
+pub mod fpu;
 pub mod interrupt;
+pub mod ipi;
 pub mod keyboard;
+pub mod memory;
+pub mod page;
+pub mod pci;
+pub mod percpu;
 pub mod pic;
+pub mod power;
 pub mod process;
+pub mod serial;
 pub mod shell;
+pub mod smp;
+pub mod spinlock;
+pub mod syscall;
 pub mod timer;
+pub mod trace;
 pub mod types;
 pub mod utility;
+pub mod watchdog;
 
 #[no_mangle] // don't mangle the name of this function
 pub unsafe extern "C" fn Main() -> ! {
@@ -53,10 +70,16 @@ pub fn print_string(x: i32, y: i32, str: &[u8]) {
     }
 }
 
+// Screen row the panic message starts at, above the banner on the last row
+const PANIC_MESSAGELINE: i32 = 21;
+
 /// This function is called on panic.
+/// The other cores are halted wherever they are, possibly holding the
+/// writer, so the message goes out without taking it.
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
+    ipi::stop_other_cpus();
     print_string(0, 24, b"[PANIC] Panicked from x64 Kernel");
-    println!("{}", _info);
+    console::report_line(PANIC_MESSAGELINE, format_args!("{}", _info));
     loop {}
 }
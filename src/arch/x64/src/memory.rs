@@ -0,0 +1,168 @@
+use spin::Lazy;
+
+use crate::{
+    descriptor::{IST_SIZE, IST_STARTADDRESS},
+    page::PAGE_DEFAULTSIZE,
+    spinlock::SpinLock,
+    utility::{get_ram_size, memset},
+};
+
+pub const FRAME_SIZE: u64 = 0x1000;
+// Frames above this address are left alone, which keeps the bitmap static
+const FRAME_MAXADDRESS: u64 = 0x40000000;
+const FRAME_MAXCOUNT: usize = (FRAME_MAXADDRESS / FRAME_SIZE) as usize;
+const FRAME_BITMAPCOUNT: usize = FRAME_MAXCOUNT / 64;
+
+// 4KB physical frames for page tables and program memory, handed out from
+// the RAM above the fixed kernel areas
+pub struct FrameAllocator {
+    bitmap: [u64; FRAME_BITMAPCOUNT],
+    start_address: u64,
+    end_address: u64,
+    search_index: usize,
+    free_count: u64,
+    total_count: u64,
+}
+
+impl FrameAllocator {
+    const fn new() -> Self {
+        Self {
+            bitmap: [0; FRAME_BITMAPCOUNT],
+            start_address: 0,
+            end_address: 0,
+            search_index: 0,
+            free_count: 0,
+            total_count: 0,
+        }
+    }
+
+    fn init(&mut self, start_address: u64, end_address: u64) {
+        let end_address = end_address.min(FRAME_MAXADDRESS);
+        self.start_address = start_address;
+        self.end_address = end_address.max(start_address);
+        // Frames outside of [start, end) are marked as used for good
+        self.bitmap.fill(0xFFFFFFFFFFFFFFFF);
+        let mut address = self.start_address;
+        while address < self.end_address {
+            let index = (address / FRAME_SIZE) as usize;
+            self.bitmap[index / 64] &= !(1 << (index % 64));
+            address += FRAME_SIZE;
+        }
+        self.total_count = (self.end_address - self.start_address) / FRAME_SIZE;
+        self.free_count = self.total_count;
+        self.search_index = (self.start_address / FRAME_SIZE) as usize / 64;
+    }
+
+    pub fn alloc(&mut self) -> Option<u64> {
+        if self.free_count == 0 {
+            return None;
+        }
+        for offset in 0..FRAME_BITMAPCOUNT {
+            let word = (self.search_index + offset) % FRAME_BITMAPCOUNT;
+            if self.bitmap[word] != 0xFFFFFFFFFFFFFFFF {
+                let bit = self.bitmap[word].trailing_ones() as usize;
+                self.bitmap[word] |= 1 << bit;
+                self.search_index = word;
+                self.free_count -= 1;
+                return Some((word * 64 + bit) as u64 * FRAME_SIZE);
+            }
+        }
+        None
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    // First fit search for a run of free frames, for allocations that have to
+    // be physically contiguous
+    pub fn alloc_contiguous(&mut self, count: u64) -> Option<u64> {
+        if count == 0 || self.free_count < count {
+            return None;
+        }
+        let start = (self.start_address / FRAME_SIZE) as usize;
+        let end = (self.end_address / FRAME_SIZE) as usize;
+        let mut first = start;
+        while first + count as usize <= end {
+            match (first..first + count as usize).find(|&index| self.is_used(index)) {
+                Some(used) => first = used + 1,
+                None => {
+                    for index in first..first + count as usize {
+                        self.bitmap[index / 64] |= 1 << (index % 64);
+                    }
+                    self.free_count -= count;
+                    return Some(first as u64 * FRAME_SIZE);
+                }
+            }
+        }
+        None
+    }
+
+    pub fn free(&mut self, address: u64) -> Result<(), ()> {
+        if address < self.start_address || address >= self.end_address || address % FRAME_SIZE != 0 {
+            return Err(());
+        }
+        let index = (address / FRAME_SIZE) as usize;
+        if self.bitmap[index / 64] & (1 << (index % 64)) == 0 {
+            return Err(());
+        }
+        self.bitmap[index / 64] &= !(1 << (index % 64));
+        self.free_count += 1;
+        Ok(())
+    }
+
+    pub fn free_count(&self) -> u64 {
+        self.free_count
+    }
+
+    pub fn total_count(&self) -> u64 {
+        self.total_count
+    }
+}
+
+static FRAME_ALLOCATOR: Lazy<SpinLock<FrameAllocator>> = Lazy::new(|| {
+    let mut allocator = FrameAllocator::new();
+    // The IST area is the last of the fixed kernel areas
+    let end_address = (IST_STARTADDRESS + IST_SIZE) as u64;
+    let start_address = (end_address + PAGE_DEFAULTSIZE - 1) & !(PAGE_DEFAULTSIZE - 1);
+    allocator.init(start_address, get_ram_size() * 0x100000);
+    SpinLock::new(allocator)
+});
+
+pub fn init_memory() {
+    FRAME_ALLOCATOR.lock().total_count();
+}
+
+// Returns a zero-filled frame; memory is identity mapped, so the physical
+// address can be used as a pointer directly
+pub fn alloc_frame() -> Option<u64> {
+    let frame = FRAME_ALLOCATOR.lock().alloc()?;
+    memset(frame as *mut u8, 0, FRAME_SIZE as isize);
+    Some(frame)
+}
+
+pub fn free_frame(address: u64) -> Result<(), ()> {
+    FRAME_ALLOCATOR.lock().free(address)
+}
+
+pub fn alloc_frames(count: u64) -> Option<u64> {
+    let address = FRAME_ALLOCATOR.lock().alloc_contiguous(count)?;
+    memset(address as *mut u8, 0, (FRAME_SIZE * count) as isize);
+    Some(address)
+}
+
+pub fn free_frames(address: u64, count: u64) -> Result<(), ()> {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    for index in 0..count {
+        allocator.free(address + index * FRAME_SIZE)?;
+    }
+    Ok(())
+}
+
+pub fn free_frame_count() -> u64 {
+    FRAME_ALLOCATOR.lock().free_count()
+}
+
+pub fn total_frame_count() -> u64 {
+    FRAME_ALLOCATOR.lock().total_count()
+}
@@ -0,0 +1,282 @@
+#![allow(non_snake_case)]
+
+use core::arch::asm;
+
+use crate::{
+    assembly::{read_MSR, write_MSR},
+    ipi::{self, IpiTarget},
+    memory::{alloc_frame, free_frame},
+};
+
+const PAGE_FLAGS_P: u64 = 0x00000001;
+pub const PAGE_FLAGS_RW: u64 = 0x00000002;
+const PAGE_FLAGS_US: u64 = 0x00000004;
+const PAGE_FLAGS_PS: u64 = 0x00000080;
+pub const PAGE_FLAGS_NX: u64 = 0x8000000000000000;
+
+pub const PAGE_FLAGS_USERRODATA: u64 = PAGE_FLAGS_P | PAGE_FLAGS_US | PAGE_FLAGS_NX;
+pub const PAGE_FLAGS_USERDATA: u64 = PAGE_FLAGS_P | PAGE_FLAGS_RW | PAGE_FLAGS_US | PAGE_FLAGS_NX;
+const PAGE_FLAGS_TABLE: u64 = PAGE_FLAGS_P | PAGE_FLAGS_RW | PAGE_FLAGS_US;
+
+const PAGE_ADDRESSMASK: u64 = 0x000FFFFFFFFFF000;
+const PAGE_MAXENTRYCOUNT: usize = 512;
+
+pub const KERNEL_PAGETABLE: u64 = 0x100000;
+const PAGE_DIRECTORYADDRESS: u64 = 0x102000;
+const PAGE_DIRECTORYCOUNT: u64 = 512 * 64;
+pub const PAGE_SIZE: u64 = 0x1000;
+pub const PAGE_DEFAULTSIZE: u64 = 0x200000;
+
+// PML4 entry 0 is the identity mapping every address space shares. Programs
+// live in the range covered by the next entry.
+pub const USER_BASEADDRESS: u64 = 0x0000008000000000;
+pub const USER_ENDADDRESS: u64 = 0x0000010000000000;
+
+// Flushing more pages than this one by one costs more than reloading CR3
+const PAGE_INVALIDATEMAXCOUNT: u64 = 32;
+
+const MSR_IA32_EFER: u32 = 0xC0000080;
+const EFER_NXE: u64 = 0x800;
+
+pub fn init_page() {
+    write_MSR(MSR_IA32_EFER, read_MSR(MSR_IA32_EFER) | EFER_NXE);
+}
+
+fn page_directory_entry(address: u64) -> Option<*mut u64> {
+    let index = address / PAGE_DEFAULTSIZE;
+    if index >= PAGE_DIRECTORYCOUNT {
+        None
+    } else {
+        Some((PAGE_DIRECTORYADDRESS as *mut u64).wrapping_add(index as usize))
+    }
+}
+
+// Whether the range lies in what the 32-bit stage identity maps, so that the
+// kernel can reach memory-mapped devices there by their physical address
+pub fn is_identity_mapped(address: u64, size: u64) -> bool {
+    size != 0 && page_directory_entry(address.saturating_add(size - 1)).is_some()
+}
+
+// The 32-bit stage maps all memory with 2MB pages, so user access is granted
+// or revoked for every 2MB page the range touches.
+pub fn set_user_accessible(address: u64, size: u64, user: bool) -> Result<(), ()> {
+    let start = address & !(PAGE_DEFAULTSIZE - 1);
+    let mut page = start;
+    while page < address + size {
+        let entry = page_directory_entry(page).ok_or(())?;
+        unsafe {
+            if user {
+                *entry |= PAGE_FLAGS_US;
+            } else {
+                *entry &= !PAGE_FLAGS_US;
+            }
+        }
+        page += PAGE_DEFAULTSIZE;
+    }
+    flush_tlb(KERNEL_PAGETABLE, start, page - start);
+    Ok(())
+}
+
+fn table_entry(table: u64, address: u64, level: u32) -> *mut u64 {
+    let index = (address >> (12 + 9 * level)) as usize & (PAGE_MAXENTRYCOUNT - 1);
+    (table as *mut u64).wrapping_add(index)
+}
+
+// Walks down to the 4KB page table entry for the address, creating missing
+// tables on the way when asked to
+fn walk(pml4: u64, address: u64, create: bool) -> Option<*mut u64> {
+    let mut table = pml4;
+    for level in (1..4).rev() {
+        let entry = table_entry(table, address, level);
+        unsafe {
+            if *entry & PAGE_FLAGS_P == 0 {
+                if !create {
+                    return None;
+                }
+                *entry = alloc_frame()? | PAGE_FLAGS_TABLE;
+            } else if *entry & PAGE_FLAGS_PS != 0 {
+                return None;
+            }
+            table = *entry & PAGE_ADDRESSMASK;
+        }
+    }
+    Some(table_entry(table, address, 0))
+}
+
+pub fn map_page(pml4: u64, address: u64, frame: u64, flags: u64) -> Result<(), ()> {
+    let entry = walk(pml4, address, true).ok_or(())?;
+    let old = unsafe { *entry };
+    unsafe { *entry = (frame & PAGE_ADDRESSMASK) | flags };
+    if old & PAGE_FLAGS_P != 0 {
+        flush_tlb(pml4, address, PAGE_SIZE);
+    }
+    Ok(())
+}
+
+// Returns the frame the page was mapped to, which the caller frees
+pub fn unmap_page(pml4: u64, address: u64) -> Result<u64, ()> {
+    let entry = walk(pml4, address, false).ok_or(())?;
+    let old = unsafe { *entry };
+    if old & PAGE_FLAGS_P == 0 {
+        return Err(());
+    }
+    unsafe { *entry = 0 };
+    flush_tlb(pml4, address, PAGE_SIZE);
+    Ok(old & PAGE_ADDRESSMASK)
+}
+
+// Adds permissions to a page that is already mapped, used when two segments
+// share a page
+pub fn update_page(pml4: u64, address: u64, flags: u64) -> Result<(), ()> {
+    let entry = walk(pml4, address, false).ok_or(())?;
+    unsafe {
+        let old = *entry;
+        let nx = old & flags & PAGE_FLAGS_NX;
+        *entry = (old & !PAGE_FLAGS_NX) | (flags & !PAGE_FLAGS_NX) | nx;
+    }
+    Ok(())
+}
+
+pub fn translate(pml4: u64, address: u64) -> Option<u64> {
+    let entry = unsafe { *walk(pml4, address, false)? };
+    if entry & PAGE_FLAGS_P == 0 {
+        None
+    } else {
+        Some((entry & PAGE_ADDRESSMASK) | (address & (PAGE_SIZE - 1)))
+    }
+}
+
+// Checks every level of the active page tables, since ring 3 can only reach
+// a page when all of them allow user access
+pub fn is_user_accessible(address: u64, size: u64) -> bool {
+    is_user_mapped(address, size, PAGE_FLAGS_P | PAGE_FLAGS_US)
+}
+
+pub fn is_user_writable(address: u64, size: u64) -> bool {
+    is_user_mapped(address, size, PAGE_FLAGS_P | PAGE_FLAGS_US | PAGE_FLAGS_RW)
+}
+
+fn is_user_mapped(address: u64, size: u64, flags: u64) -> bool {
+    let end = match address.checked_add(size) {
+        Some(end) => end,
+        None => return false,
+    };
+    let pml4 = read_CR3() & PAGE_ADDRESSMASK;
+    let mut current = address;
+    while current < end {
+        let mut table = pml4;
+        let mut page_size = PAGE_SIZE;
+        for level in (0..4).rev() {
+            let entry = unsafe { *table_entry(table, current, level) };
+            if entry & flags != flags {
+                return false;
+            }
+            if level == 0 || (level < 3 && entry & PAGE_FLAGS_PS != 0) {
+                page_size = 1 << (12 + 9 * level);
+                break;
+            }
+            table = entry & PAGE_ADDRESSMASK;
+        }
+        current = (current & !(page_size - 1)) + page_size;
+    }
+    true
+}
+
+pub fn create_address_space() -> Result<u64, ()> {
+    let pml4 = alloc_frame().ok_or(())?;
+    unsafe { *(pml4 as *mut u64) = *(KERNEL_PAGETABLE as *const u64) };
+    Ok(pml4)
+}
+
+// Frees every table and frame mapped in the user part of an address space,
+// then the PML4 itself. The shared identity mapping is left alone.
+pub fn destroy_address_space(pml4: u64) {
+    if pml4 == KERNEL_PAGETABLE {
+        return;
+    }
+    for index in 1..PAGE_MAXENTRYCOUNT {
+        let entry = unsafe { *(pml4 as *const u64).add(index) };
+        if entry & PAGE_FLAGS_P != 0 {
+            free_table(entry & PAGE_ADDRESSMASK, 2);
+        }
+    }
+    let _ = free_frame(pml4);
+}
+
+fn free_table(table: u64, level: u32) {
+    for index in 0..PAGE_MAXENTRYCOUNT {
+        let entry = unsafe { *(table as *const u64).add(index) };
+        if entry & PAGE_FLAGS_P == 0 {
+            continue;
+        }
+        if level == 0 {
+            let _ = free_frame(entry & PAGE_ADDRESSMASK);
+        } else if entry & PAGE_FLAGS_PS == 0 {
+            free_table(entry & PAGE_ADDRESSMASK, level - 1);
+        }
+    }
+    let _ = free_frame(table);
+}
+
+pub fn switch_address_space(pml4: u64) {
+    if read_CR3() & PAGE_ADDRESSMASK != pml4 {
+        write_CR3(pml4);
+    }
+}
+
+pub fn read_CR3() -> u64 {
+    let cr3: u64;
+    unsafe {
+        asm!("mov {0}, cr3", out(reg) cr3, options(nostack));
+    }
+    cr3
+}
+
+fn write_CR3(cr3: u64) {
+    unsafe {
+        asm!("mov cr3, {0}", in(reg) cr3, options(nostack));
+    }
+}
+
+pub fn invalidate_page(address: u64) {
+    unsafe {
+        asm!("invlpg [{0}]", in(reg) address, options(nostack));
+    }
+}
+
+struct TlbRange {
+    pml4: u64,
+    start: u64,
+    end: u64,
+}
+
+// Runs on every core. The identity mapping is part of every address space,
+// while a user range only matters to cores running on its page tables.
+fn invalidate_range(argument: u64) {
+    let range = unsafe { &*(argument as *const TlbRange) };
+    let cr3 = read_CR3();
+    if range.start >= USER_BASEADDRESS && cr3 & PAGE_ADDRESSMASK != range.pml4 {
+        return;
+    }
+    if (range.end - range.start) / PAGE_SIZE > PAGE_INVALIDATEMAXCOUNT {
+        write_CR3(cr3);
+        return;
+    }
+    let mut page = range.start;
+    while page < range.end {
+        invalidate_page(page);
+        page += PAGE_SIZE;
+    }
+}
+
+// TLB shootdown, called after entries of `pml4` were removed or lost
+// permissions so that no core keeps using the old ones. Must not be called
+// with a spin lock held.
+pub fn flush_tlb(pml4: u64, address: u64, size: u64) {
+    let range = TlbRange {
+        pml4,
+        start: address & !(PAGE_SIZE - 1),
+        end: address + size,
+    };
+    ipi::call_function(IpiTarget::All, invalidate_range, &range as *const TlbRange as u64);
+}
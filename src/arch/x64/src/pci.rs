@@ -0,0 +1,571 @@
+use core::ptr::{read_volatile, write_volatile};
+
+use spin::Lazy;
+
+use crate::{
+    acpi,
+    assembly::{InPortByte, InPortDword, InPortWord, OutPortByte, OutPortDword, OutPortWord},
+    page,
+    spinlock::SpinLock,
+};
+
+const PCI_CONFIGADDRESSPORT: u16 = 0xCF8;
+const PCI_CONFIGDATAPORT: u16 = 0xCFC;
+const PCI_CONFIGENABLE: u32 = 0x8000_0000;
+// The ports reach the first 256 bytes of segment 0, ECAM all 4KB of a function
+const PCI_LEGACYCONFIGSIZE: u16 = 0x100;
+const PCI_ECAMCONFIGSIZE: u16 = 0x1000;
+const PCI_ECAMBUSSIZE: u64 = 0x100000;
+
+const PCI_MAXBUSCOUNT: usize = 256;
+const PCI_MAXDEVICECOUNT: u8 = 32;
+const PCI_MAXFUNCTIONCOUNT: u8 = 8;
+const PCI_MAXECAMCOUNT: usize = 8;
+const PCI_MAXREGISTRYCOUNT: usize = 64;
+pub const PCI_MAXBARCOUNT: usize = 6;
+const PCI_BRIDGEBARCOUNT: usize = 2;
+// Capability lists are walked this far at most, so a looping list still ends
+const PCI_MAXCAPABILITYCOUNT: usize = 16;
+
+// Offsets of the configuration space header
+pub const PCI_VENDORID: u16 = 0x00;
+pub const PCI_DEVICEID: u16 = 0x02;
+pub const PCI_COMMAND: u16 = 0x04;
+pub const PCI_STATUS: u16 = 0x06;
+pub const PCI_REVISIONID: u16 = 0x08;
+pub const PCI_PROGIF: u16 = 0x09;
+pub const PCI_SUBCLASS: u16 = 0x0A;
+pub const PCI_CLASS: u16 = 0x0B;
+pub const PCI_HEADERTYPE: u16 = 0x0E;
+pub const PCI_BAR0: u16 = 0x10;
+pub const PCI_SECONDARYBUS: u16 = 0x19;
+pub const PCI_CAPABILITYPOINTER: u16 = 0x34;
+pub const PCI_INTERRUPTLINE: u16 = 0x3C;
+pub const PCI_INTERRUPTPIN: u16 = 0x3D;
+
+pub const PCI_COMMAND_IO: u16 = 0x0001;
+pub const PCI_COMMAND_MEMORY: u16 = 0x0002;
+pub const PCI_COMMAND_BUSMASTER: u16 = 0x0004;
+const PCI_STATUS_CAPABILITYLIST: u16 = 0x0010;
+
+const PCI_HEADERTYPE_MASK: u8 = 0x7F;
+const PCI_HEADERTYPE_MULTIFUNCTION: u8 = 0x80;
+pub const PCI_HEADERTYPE_GENERAL: u8 = 0x00;
+pub const PCI_HEADERTYPE_BRIDGE: u8 = 0x01;
+
+const PCI_BAR_IO: u32 = 0x01;
+const PCI_BAR_64BIT: u32 = 0x04;
+const PCI_BAR_PREFETCHABLE: u32 = 0x08;
+const PCI_BAR_IOADDRESSMASK: u32 = !0x03;
+const PCI_BAR_MEMORYADDRESSMASK: u32 = !0x0F;
+
+pub const PCI_CLASS_BRIDGE: u8 = 0x06;
+pub const PCI_SUBCLASS_PCIBRIDGE: u8 = 0x04;
+
+const PCI_INVALIDVENDORID: u16 = 0xFFFF;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum PciBar {
+    None,
+    Io {
+        port: u32,
+        size: u32,
+    },
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+}
+
+// What the scan found about one function. Capabilities are kept as their ID
+// and their offset in configuration space.
+#[derive(Clone, Copy)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bars: [PciBar; PCI_MAXBARCOUNT],
+    capabilities: [(u8, u16); PCI_MAXCAPABILITYCOUNT],
+    capability_count: usize,
+}
+
+impl PciDevice {
+    const fn empty() -> Self {
+        Self {
+            address: PciAddress::new(0, 0, 0, 0),
+            vendor_id: 0,
+            device_id: 0,
+            class: 0,
+            subclass: 0,
+            prog_if: 0,
+            revision: 0,
+            header_type: 0,
+            interrupt_line: 0,
+            interrupt_pin: 0,
+            bars: [PciBar::None; PCI_MAXBARCOUNT],
+            capabilities: [(0, 0); PCI_MAXCAPABILITYCOUNT],
+            capability_count: 0,
+        }
+    }
+
+    pub fn capabilities(&self) -> &[(u8, u16)] {
+        &self.capabilities[..self.capability_count]
+    }
+
+    // Offset of the first capability with this ID
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities()
+            .iter()
+            .find(|(capability, _)| *capability == id)
+            .map(|(_, offset)| *offset)
+    }
+
+    // Turns on decoding of its BARs, or bus mastering, for a driver
+    pub fn enable(&self, flags: u16) {
+        let command = read_config_word(self.address, PCI_COMMAND);
+        write_config_word(self.address, PCI_COMMAND, command | flags);
+    }
+}
+
+// Which devices a driver takes. Fields left as None match anything.
+#[derive(Clone, Copy)]
+pub struct PciMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl PciMatch {
+    pub const fn id(vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        Self {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if: None,
+        }
+    }
+
+    fn matches(&self, device: &PciDevice) -> bool {
+        self.vendor_id.map_or(true, |id| id == device.vendor_id)
+            && self.device_id.map_or(true, |id| id == device.device_id)
+            && self.class.map_or(true, |class| class == device.class)
+            && self.subclass.map_or(true, |subclass| subclass == device.subclass)
+            && self.prog_if.map_or(true, |prog_if| prog_if == device.prog_if)
+    }
+}
+
+struct PciRegistry {
+    devices: [PciDevice; PCI_MAXREGISTRYCOUNT],
+    count: usize,
+}
+
+impl PciRegistry {
+    const fn new() -> Self {
+        Self {
+            devices: [PciDevice::empty(); PCI_MAXREGISTRYCOUNT],
+            count: 0,
+        }
+    }
+
+    fn add(&mut self, device: PciDevice) -> Result<(), ()> {
+        let slot = self.devices.get_mut(self.count).ok_or(())?;
+        *slot = device;
+        self.count += 1;
+        Ok(())
+    }
+}
+
+static PCI_REGISTRY: SpinLock<PciRegistry> = SpinLock::new(PciRegistry::new());
+// The address and data ports are a pair, so one access goes through them at a time
+static PCI_CONFIGLOCK: SpinLock<()> = SpinLock::new(());
+
+// MCFG regions the kernel can reach, read once
+static PCI_ECAMREGIONS: Lazy<[Option<acpi::McfgEntry>; PCI_MAXECAMCOUNT]> = Lazy::new(|| {
+    let mut regions = [None; PCI_MAXECAMCOUNT];
+    let reachable = acpi::mcfg_entries().filter(|entry| {
+        let size = (entry.end_bus as u64 + 1) * PCI_ECAMBUSSIZE;
+        entry.start_bus <= entry.end_bus && page::is_identity_mapped(entry.base_address, size)
+    });
+    for (region, entry) in regions.iter_mut().zip(reachable) {
+        *region = Some(entry);
+    }
+    regions
+});
+
+// The ECAM base address always stands for bus 0, even for a region that
+// starts at a later bus
+fn ecam_address(address: PciAddress, offset: u16) -> Option<u64> {
+    let region = PCI_ECAMREGIONS.iter().flatten().find(|region| {
+        address.segment == { region.segment_group }
+            && (region.start_bus..=region.end_bus).contains(&address.bus)
+    })?;
+    Some(
+        region.base_address
+            + ((address.bus as u64) << 20
+                | (address.device as u64) << 15
+                | (address.function as u64) << 12
+                | offset as u64),
+    )
+}
+
+fn legacy_address(address: PciAddress, offset: u16) -> Option<u32> {
+    if address.segment != 0 || offset >= PCI_LEGACYCONFIGSIZE {
+        return None;
+    }
+    Some(
+        PCI_CONFIGENABLE
+            | (address.bus as u32) << 16
+            | (address.device as u32) << 11
+            | (address.function as u32) << 8
+            | (offset & 0xFC) as u32,
+    )
+}
+
+// Reads `size` bytes at a naturally aligned offset. Registers nobody answers
+// for read as all ones, like a missing device.
+fn read_config(address: PciAddress, offset: u16, size: u16) -> u32 {
+    if offset >= PCI_ECAMCONFIGSIZE || offset % size != 0 {
+        return u32::MAX;
+    }
+    if let Some(pointer) = ecam_address(address, offset) {
+        return unsafe {
+            match size {
+                1 => read_volatile(pointer as *const u8) as u32,
+                2 => read_volatile(pointer as *const u16) as u32,
+                _ => read_volatile(pointer as *const u32),
+            }
+        };
+    }
+    let config_address = match legacy_address(address, offset) {
+        Some(config_address) => config_address,
+        None => return u32::MAX,
+    };
+    let _config = PCI_CONFIGLOCK.lock();
+    OutPortDword(PCI_CONFIGADDRESSPORT, config_address);
+    let port = PCI_CONFIGDATAPORT + (offset & 0x03);
+    match size {
+        1 => InPortByte(port) as u32,
+        2 => InPortWord(port) as u32,
+        _ => InPortDword(port),
+    }
+}
+
+fn write_config(address: PciAddress, offset: u16, size: u16, value: u32) {
+    if offset >= PCI_ECAMCONFIGSIZE || offset % size != 0 {
+        return;
+    }
+    if let Some(pointer) = ecam_address(address, offset) {
+        unsafe {
+            match size {
+                1 => write_volatile(pointer as *mut u8, value as u8),
+                2 => write_volatile(pointer as *mut u16, value as u16),
+                _ => write_volatile(pointer as *mut u32, value),
+            }
+        }
+        return;
+    }
+    let config_address = match legacy_address(address, offset) {
+        Some(config_address) => config_address,
+        None => return,
+    };
+    let _config = PCI_CONFIGLOCK.lock();
+    OutPortDword(PCI_CONFIGADDRESSPORT, config_address);
+    let port = PCI_CONFIGDATAPORT + (offset & 0x03);
+    match size {
+        1 => OutPortByte(port, value as u8),
+        2 => OutPortWord(port, value as u16),
+        _ => OutPortDword(port, value),
+    }
+}
+
+pub fn read_config_byte(address: PciAddress, offset: u16) -> u8 {
+    read_config(address, offset, 1) as u8
+}
+
+pub fn read_config_word(address: PciAddress, offset: u16) -> u16 {
+    read_config(address, offset, 2) as u16
+}
+
+pub fn read_config_dword(address: PciAddress, offset: u16) -> u32 {
+    read_config(address, offset, 4)
+}
+
+pub fn write_config_byte(address: PciAddress, offset: u16, value: u8) {
+    write_config(address, offset, 1, value as u32)
+}
+
+pub fn write_config_word(address: PciAddress, offset: u16, value: u16) {
+    write_config(address, offset, 2, value as u32)
+}
+
+pub fn write_config_dword(address: PciAddress, offset: u16, value: u32) {
+    write_config(address, offset, 4, value)
+}
+
+fn is_present(address: PciAddress) -> bool {
+    read_config_word(address, PCI_VENDORID) != PCI_INVALIDVENDORID
+}
+
+// Writes all ones to the BAR and reads back which address bits stick; the
+// ones that do not give its size. Returns the BAR and how many slots it took.
+fn size_bar(address: PciAddress, index: usize, count: usize) -> (PciBar, usize) {
+    let offset = PCI_BAR0 + index as u16 * 4;
+    let original = read_config_dword(address, offset);
+    write_config_dword(address, offset, u32::MAX);
+    let mask = read_config_dword(address, offset);
+    write_config_dword(address, offset, original);
+
+    if original & PCI_BAR_IO != 0 {
+        // Only the low 16 bits of an I/O BAR have to be implemented
+        let size = (!(mask & PCI_BAR_IOADDRESSMASK) & 0xFFFF) + 1;
+        return match mask & PCI_BAR_IOADDRESSMASK {
+            0 => (PciBar::None, 1),
+            _ => (PciBar::Io { port: original & PCI_BAR_IOADDRESSMASK, size }, 1),
+        };
+    }
+
+    let prefetchable = original & PCI_BAR_PREFETCHABLE != 0;
+    if original & PCI_BAR_64BIT != 0 && index + 1 < count {
+        let high_offset = offset + 4;
+        let high_original = read_config_dword(address, high_offset);
+        write_config_dword(address, high_offset, u32::MAX);
+        let high_mask = read_config_dword(address, high_offset);
+        write_config_dword(address, high_offset, high_original);
+
+        let mask = (high_mask as u64) << 32 | (mask & PCI_BAR_MEMORYADDRESSMASK) as u64;
+        let base = (high_original as u64) << 32 | (original & PCI_BAR_MEMORYADDRESSMASK) as u64;
+        return match mask {
+            0 => (PciBar::None, 2),
+            _ => {
+                let bar = PciBar::Memory {
+                    address: base,
+                    size: (!mask).wrapping_add(1),
+                    prefetchable,
+                    is_64bit: true,
+                };
+                (bar, 2)
+            }
+        };
+    }
+
+    match mask & PCI_BAR_MEMORYADDRESSMASK {
+        0 => (PciBar::None, 1),
+        mask => {
+            let bar = PciBar::Memory {
+                address: (original & PCI_BAR_MEMORYADDRESSMASK) as u64,
+                size: (!mask).wrapping_add(1) as u64,
+                prefetchable,
+                is_64bit: false,
+            };
+            (bar, 1)
+        }
+    }
+}
+
+// Decoding stays off while the BARs hold all ones, so the device does not
+// answer at whatever address that makes up
+fn read_bars(address: PciAddress, count: usize) -> [PciBar; PCI_MAXBARCOUNT] {
+    let mut bars = [PciBar::None; PCI_MAXBARCOUNT];
+    let command = read_config_word(address, PCI_COMMAND);
+    write_config_word(address, PCI_COMMAND, command & !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY));
+
+    let mut index = 0;
+    while index < count {
+        let (bar, used) = size_bar(address, index, count);
+        bars[index] = bar;
+        index += used;
+    }
+
+    write_config_word(address, PCI_COMMAND, command);
+    bars
+}
+
+fn read_capabilities(device: &mut PciDevice) {
+    if read_config_word(device.address, PCI_STATUS) & PCI_STATUS_CAPABILITYLIST == 0 {
+        return;
+    }
+    let mut pointer = (read_config_byte(device.address, PCI_CAPABILITYPOINTER) & 0xFC) as u16;
+    while pointer != 0 && device.capability_count < PCI_MAXCAPABILITYCOUNT {
+        let id = read_config_byte(device.address, pointer);
+        device.capabilities[device.capability_count] = (id, pointer);
+        device.capability_count += 1;
+        pointer = (read_config_byte(device.address, pointer + 1) & 0xFC) as u16;
+    }
+}
+
+fn read_device(address: PciAddress) -> PciDevice {
+    let mut device = PciDevice::empty();
+    device.address = address;
+    device.vendor_id = read_config_word(address, PCI_VENDORID);
+    device.device_id = read_config_word(address, PCI_DEVICEID);
+    device.revision = read_config_byte(address, PCI_REVISIONID);
+    device.prog_if = read_config_byte(address, PCI_PROGIF);
+    device.subclass = read_config_byte(address, PCI_SUBCLASS);
+    device.class = read_config_byte(address, PCI_CLASS);
+    device.header_type = read_config_byte(address, PCI_HEADERTYPE) & PCI_HEADERTYPE_MASK;
+    device.interrupt_line = read_config_byte(address, PCI_INTERRUPTLINE);
+    device.interrupt_pin = read_config_byte(address, PCI_INTERRUPTPIN);
+    match device.header_type {
+        PCI_HEADERTYPE_GENERAL => device.bars = read_bars(address, PCI_MAXBARCOUNT),
+        PCI_HEADERTYPE_BRIDGE => device.bars = read_bars(address, PCI_BRIDGEBARCOUNT),
+        _ => {}
+    }
+    read_capabilities(&mut device);
+    device
+}
+
+// Walks the buses the firmware numbered. Buses are only scanned once, so a
+// bridge pointing back at a bus already seen ends the recursion.
+struct PciScan {
+    segment: u16,
+    scanned: [bool; PCI_MAXBUSCOUNT],
+    found: usize,
+}
+
+impl PciScan {
+    fn scan_bus(&mut self, bus: u8) {
+        if self.scanned[bus as usize] {
+            return;
+        }
+        self.scanned[bus as usize] = true;
+        for device in 0..PCI_MAXDEVICECOUNT {
+            self.scan_device(bus, device);
+        }
+    }
+
+    fn scan_device(&mut self, bus: u8, device: u8) {
+        let address = PciAddress::new(self.segment, bus, device, 0);
+        if !is_present(address) {
+            return;
+        }
+        let functions =
+            match read_config_byte(address, PCI_HEADERTYPE) & PCI_HEADERTYPE_MULTIFUNCTION {
+                0 => 1,
+                _ => PCI_MAXFUNCTIONCOUNT,
+            };
+        for function in 0..functions {
+            let address = PciAddress::new(self.segment, bus, device, function);
+            if is_present(address) {
+                self.scan_function(address);
+            }
+        }
+    }
+
+    fn scan_function(&mut self, address: PciAddress) {
+        let device = read_device(address);
+        // Devices past the end of the registry are still walked through
+        if PCI_REGISTRY.lock().add(device).is_ok() {
+            self.found += 1;
+        }
+
+        if device.class == PCI_CLASS_BRIDGE && device.subclass == PCI_SUBCLASS_PCIBRIDGE {
+            let secondary = read_config_byte(address, PCI_SECONDARYBUS);
+            if secondary != 0 {
+                self.scan_bus(secondary);
+            }
+        }
+    }
+
+    // A multi-function host bridge has one host controller per function,
+    // each with the bus of the same number behind it
+    fn scan_segment(&mut self, start_bus: u8) {
+        let host = PciAddress::new(self.segment, start_bus, 0, 0);
+        if !is_present(host)
+            || read_config_byte(host, PCI_HEADERTYPE) & PCI_HEADERTYPE_MULTIFUNCTION == 0
+        {
+            self.scan_bus(start_bus);
+            return;
+        }
+        for function in 0..PCI_MAXFUNCTIONCOUNT {
+            if is_present(PciAddress::new(self.segment, start_bus, 0, function)) {
+                self.scan_bus(start_bus.wrapping_add(function));
+            }
+        }
+    }
+}
+
+// Scans every segment the MCFG describes, or segment 0 through the ports
+// when there is none, and fills the registry. Bus numbers are taken as the
+// firmware assigned them. Returns how many devices were registered.
+pub fn init_pci() -> usize {
+    let mut scan = PciScan {
+        segment: 0,
+        scanned: [false; PCI_MAXBUSCOUNT],
+        found: 0,
+    };
+    let mut regions = PCI_ECAMREGIONS.iter().flatten().peekable();
+    if regions.peek().is_none() {
+        scan.scan_segment(0);
+        return scan.found;
+    }
+    for region in regions {
+        let segment = region.segment_group;
+        if segment != scan.segment {
+            scan.segment = segment;
+            scan.scanned = [false; PCI_MAXBUSCOUNT];
+        }
+        scan.scan_segment(region.start_bus);
+    }
+    scan.found
+}
+
+pub fn device_count() -> usize {
+    PCI_REGISTRY.lock().count
+}
+
+pub fn get_device(index: usize) -> Option<PciDevice> {
+    let registry = PCI_REGISTRY.lock();
+    registry.devices[..registry.count].get(index).copied()
+}
+
+// The `index`th device the pattern matches, so a driver can go through all
+// of its devices without holding the registry
+pub fn find_device(pattern: &PciMatch, index: usize) -> Option<PciDevice> {
+    let registry = PCI_REGISTRY.lock();
+    registry.devices[..registry.count]
+        .iter()
+        .filter(|device| pattern.matches(device))
+        .nth(index)
+        .copied()
+}
@@ -0,0 +1,205 @@
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    mem::{offset_of, size_of},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+
+use crate::{
+    assembly::{read_MSR, write_MSR},
+    descriptor::TSSSEGMENT,
+    process::{RRScheduler, PROCESS_INVALIDID},
+    smp::CPU_MAXCOUNT,
+    spinlock::SpinLock,
+};
+
+// While in the kernel GS_BASE holds the area of the core. In ring 3 it holds
+// the user value, and swapgs trades the two on every crossing.
+const MSR_IA32_GSBASE: u32 = 0xC0000101;
+const MSR_IA32_KERNELGSBASE: u32 = 0xC0000102;
+
+// Offsets the entry stubs reach through GS
+pub const PERCPU_KERNELSTACKOFFSET: usize = offset_of!(PerCpu, kernel_stack);
+pub const PERCPU_USERSTACKOFFSET: usize = offset_of!(PerCpu, user_stack);
+
+// Everything one core keeps to itself. Other cores may read it through
+// `get`, which is how the schedulers of other cores are reached.
+#[repr(C)]
+pub struct PerCpu {
+    // Address of the area itself, so that it can be found from GS in one load
+    this: AtomicU64,
+    // Top of the running task's kernel stack, and a scratch slot for the user
+    // RSP while the syscall entry switches over to it
+    kernel_stack: AtomicU64,
+    user_stack: AtomicU64,
+    cpu: AtomicUsize,
+    current_task: AtomicU64,
+    apic_id: AtomicU8,
+    online: AtomicBool,
+    // Task whose state is currently held in the FPU registers of the core
+    pub fpu_owner: AtomicU64,
+    // Ticks the core spent in its idle task out of every tick, and the load
+    // the idle task last worked out from them
+    pub idle_count: AtomicU64,
+    pub tick_count: AtomicU64,
+    pub process_load: AtomicU64,
+    pub halt_count: AtomicU64,
+    pub balance_tick: AtomicU64,
+    // Spin locks the core holds or is waiting for. Interrupts stay disabled
+    // meanwhile, so they all belong to the code running on it.
+    pub lock_count: AtomicU64,
+    scheduler: SpinLock<RRScheduler>,
+    tss: UnsafeCell<TSSSEGMENT>,
+}
+
+// The TSS is only written by its own core, or by the bootstrap processor
+// before that core is started
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    const fn new() -> Self {
+        Self {
+            this: AtomicU64::new(0),
+            kernel_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+            cpu: AtomicUsize::new(0),
+            current_task: AtomicU64::new(PROCESS_INVALIDID),
+            apic_id: AtomicU8::new(0),
+            online: AtomicBool::new(false),
+            fpu_owner: AtomicU64::new(PROCESS_INVALIDID),
+            idle_count: AtomicU64::new(0),
+            tick_count: AtomicU64::new(0),
+            process_load: AtomicU64::new(0),
+            halt_count: AtomicU64::new(0),
+            balance_tick: AtomicU64::new(0),
+            lock_count: AtomicU64::new(0),
+            scheduler: SpinLock::new(RRScheduler::new(PROCESS_INVALIDID)),
+            tss: UnsafeCell::new(TSSSEGMENT::new()),
+        }
+    }
+
+    pub fn cpu(&self) -> usize {
+        self.cpu.load(Ordering::Relaxed)
+    }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub fn set_apic_id(&self, apic_id: u8) {
+        self.apic_id.store(apic_id, Ordering::Relaxed);
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    pub fn set_online(&self) {
+        self.online.store(true, Ordering::Release);
+    }
+
+    pub fn current_task(&self) -> u64 {
+        self.current_task.load(Ordering::Relaxed)
+    }
+
+    pub fn set_current_task(&self, pid: u64) {
+        self.current_task.store(pid, Ordering::Relaxed);
+    }
+
+    pub fn scheduler(&self) -> &SpinLock<RRScheduler> {
+        &self.scheduler
+    }
+
+    pub fn tss(&self) -> *mut TSSSEGMENT {
+        self.tss.get()
+    }
+
+    // Stack the core switches to on entering the kernel from ring 3, through
+    // an interrupt or a syscall. Only the core itself sets it.
+    pub fn set_kernel_stack(&self, rsp0: u64) {
+        self.kernel_stack.store(rsp0, Ordering::Relaxed);
+        unsafe { (*self.tss()).set_kernel_stack(rsp0) };
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const PERCPU_EMPTY: PerCpu = PerCpu::new();
+static PERCPU_AREAS: [PerCpu; CPU_MAXCOUNT] = [PERCPU_EMPTY; CPU_MAXCOUNT];
+
+// Points GS at the area of core `cpu`. Each core calls it first thing, before
+// anything that looks up the running core.
+pub fn init_percpu(cpu: usize) {
+    let area = &PERCPU_AREAS[cpu];
+    let address = area as *const PerCpu as u64;
+    area.this.store(address, Ordering::Relaxed);
+    area.cpu.store(cpu, Ordering::Relaxed);
+    write_MSR(MSR_IA32_GSBASE, address);
+    write_MSR(MSR_IA32_KERNELGSBASE, 0);
+}
+
+pub fn get(cpu: usize) -> &'static PerCpu {
+    &PERCPU_AREAS[cpu]
+}
+
+// Reads a field of the running core's area in a single instruction, which a
+// task switch cannot split
+fn read_field(offset: usize) -> u64 {
+    let value: u64;
+    unsafe {
+        asm!(
+            "mov {0}, gs:[{1}]",
+            out(reg) value,
+            in(reg) offset,
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+    value
+}
+
+// Area of the running core. A task may be moved to another core whenever
+// interrupts are enabled, so callers that use it more than once keep them
+// disabled in between.
+pub fn current() -> &'static PerCpu {
+    unsafe { &*(read_field(offset_of!(PerCpu, this)) as *const PerCpu) }
+}
+
+pub fn current_cpu() -> usize {
+    read_field(offset_of!(PerCpu, cpu)) as usize
+}
+
+pub fn current_task() -> u64 {
+    read_field(offset_of!(PerCpu, current_task))
+}
+
+fn is_area(address: u64) -> bool {
+    let start = PERCPU_AREAS.as_ptr() as u64;
+    address >= start && address < start + (size_of::<PerCpu>() * CPU_MAXCOUNT) as u64
+}
+
+fn swapgs() {
+    unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+}
+
+// Handlers the CPU enters without one of our stubs in front, including NMIs
+// that can land right next to a swapgs, cannot tell from the frame which GS
+// is loaded. The guard loads the kernel one for the handler when GS_BASE is
+// not an area, and gives the other one back when it is dropped.
+pub struct KernelGs(bool);
+
+impl KernelGs {
+    pub fn enter() -> Self {
+        let swapped = !is_area(read_MSR(MSR_IA32_GSBASE));
+        if swapped {
+            swapgs();
+        }
+        Self(swapped)
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.0 {
+            swapgs();
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::assembly::OutPortByte;
+use crate::assembly::{InPortByte, OutPortByte};
 
 const PIC_MASTER_PORT1: u16 = 0x20;
 const PIC_MASTER_PORT2: u16 = 0x21;
@@ -23,6 +23,14 @@ pub fn MaskedPICInterrupt(IRQBitmask: u16) {
     OutPortByte(PIC_SLAVE_PORT2, (IRQBitmask >> 8) as u8);
 }
 
+pub fn mask_irq(irq: u8) {
+    if irq < 8 {
+        OutPortByte(PIC_MASTER_PORT2, InPortByte(PIC_MASTER_PORT2) | (1 << irq));
+    } else {
+        OutPortByte(PIC_SLAVE_PORT2, InPortByte(PIC_SLAVE_PORT2) | (1 << (irq - 8)));
+    }
+}
+
 pub fn SendEOI(IRQNumber: u16) {
     OutPortByte(PIC_MASTER_PORT1, 0x20);
     if IRQNumber >= 8 {
@@ -0,0 +1,183 @@
+use core::{hint, ptr::write_volatile};
+
+use crate::{
+    acpi::{
+        self, Fadt, ACPI_ADDRESSSPACE_IO, ACPI_ADDRESSSPACE_MEMORY, ACPI_ADDRESSSPACE_PCICONFIG,
+        FADT_FLAGS_RESETREGISTER,
+    },
+    assembly::{DisableInterrupt, InPortWord, Int3, LoadIDTR, OutPortByte, OutPortWord},
+    keyboard,
+    pci::{self, PciAddress},
+    utility::set_interrupt_flag,
+};
+
+// SLP_TYP sits in bits 10-12 of a PM1 control register and SLP_EN starts the
+// transition. SCI_EN is set once the firmware has handed over to ACPI.
+const PM1_CONTROL_SCIEN: u16 = 0x0001;
+const PM1_CONTROL_SLPTYPSHIFT: u16 = 10;
+const PM1_CONTROL_SLPTYPMASK: u16 = 0x1C00;
+const PM1_CONTROL_SLPEN: u16 = 0x2000;
+
+// QEMU and Bochs enter S5 with a sleep type of 0 in both blocks
+const POWER_DEFAULTS5TYPE: (u16, u16) = (0, 0);
+// PM1a control ports of QEMU and of Bochs/older QEMU, tried when there is no FADT
+const POWER_EMULATORPORTS: [u16; 2] = [0x604, 0xB004];
+// Rounds given to the firmware or the hardware before the next method is tried
+const POWER_WAITCOUNT: u64 = 100_000_000;
+
+const AML_ZEROOP: u8 = 0x00;
+const AML_ONEOP: u8 = 0x01;
+const AML_NAMEOP: u8 = 0x08;
+const AML_BYTEPREFIX: u8 = 0x0A;
+const AML_WORDPREFIX: u8 = 0x0B;
+const AML_PACKAGEOP: u8 = 0x12;
+const AML_ROOTPREFIX: u8 = b'\\';
+
+fn wait_until(condition: impl Fn() -> bool) -> bool {
+    for _ in 0..POWER_WAITCOUNT {
+        if condition() {
+            return true;
+        }
+        hint::spin_loop();
+    }
+    false
+}
+
+// An integer constant in AML and the index right after it
+fn aml_integer(aml: &[u8], index: usize) -> Option<(u16, usize)> {
+    match *aml.get(index)? {
+        AML_ZEROOP => Some((0, index + 1)),
+        AML_ONEOP => Some((1, index + 1)),
+        AML_BYTEPREFIX => Some((*aml.get(index + 1)? as u16, index + 2)),
+        AML_WORDPREFIX => {
+            let value = u16::from_le_bytes([*aml.get(index + 1)?, *aml.get(index + 2)?]);
+            Some((value, index + 3))
+        }
+        _ => None,
+    }
+}
+
+// Sleep types of PM1a and PM1b for S5. The DSDT is AML, but \_S5_ is a
+// package of constants that can be picked out without an interpreter:
+// NameOp _S5_ PackageOp PkgLength NumElements SLP_TYPa SLP_TYPb ...
+fn s5_sleep_type(fadt: &Fadt) -> Option<(u16, u16)> {
+    let dsdt = fadt.dsdt_address();
+    if dsdt == 0 || !acpi::is_valid_table(dsdt) {
+        return None;
+    }
+    let aml = acpi::table_data(dsdt);
+    let position = aml.windows(4).position(|name| name == b"_S5_")?;
+    let named = match position {
+        0 => false,
+        1 => aml[0] == AML_NAMEOP,
+        _ => {
+            aml[position - 1] == AML_NAMEOP
+                || (aml[position - 1] == AML_ROOTPREFIX && aml[position - 2] == AML_NAMEOP)
+        }
+    };
+    if !named || aml.get(position + 4) != Some(&AML_PACKAGEOP) {
+        return None;
+    }
+
+    // Bits 6-7 of the first PkgLength byte count the bytes that follow it
+    let index = position + 5;
+    let index = index + 1 + (*aml.get(index)? >> 6) as usize;
+    // Skip NumElements
+    let (type_a, index) = aml_integer(aml, index + 1)?;
+    let (type_b, _) = aml_integer(aml, index)?;
+    Some((type_a, type_b))
+}
+
+fn enter_sleep(port: u16, sleep_type: u16) {
+    let control = InPortWord(port) & !(PM1_CONTROL_SLPTYPMASK | PM1_CONTROL_SLPEN);
+    let control = control | (sleep_type << PM1_CONTROL_SLPTYPSHIFT) & PM1_CONTROL_SLPTYPMASK;
+    OutPortWord(port, control | PM1_CONTROL_SLPEN);
+}
+
+// Until SCI_EN is set the firmware owns the PM registers, and it takes them
+// back after being sent ACPI_ENABLE through the SMI command port
+fn enable_acpi(fadt: &Fadt, port: u16) {
+    let (smi_command, acpi_enable) = (fadt.smi_command, fadt.acpi_enable);
+    if InPortWord(port) & PM1_CONTROL_SCIEN != 0 || smi_command == 0 || acpi_enable == 0 {
+        return;
+    }
+    OutPortByte(smi_command as u16, acpi_enable);
+    wait_until(|| InPortWord(port) & PM1_CONTROL_SCIEN != 0);
+}
+
+// Enters ACPI S5. Returns only when the machine is still on.
+pub fn power_off() {
+    match acpi::fadt() {
+        Some(fadt) => {
+            let (pm1a, pm1b) = fadt.pm1_control_ports();
+            if pm1a == 0 {
+                return;
+            }
+            let (type_a, type_b) = s5_sleep_type(&fadt).unwrap_or(POWER_DEFAULTS5TYPE);
+            enable_acpi(&fadt, pm1a);
+
+            let previous_flag = set_interrupt_flag(false);
+            if pm1b != 0 {
+                enter_sleep(pm1b, type_b);
+            }
+            enter_sleep(pm1a, type_a);
+            wait_until(|| false);
+            set_interrupt_flag(previous_flag);
+        }
+        None => {
+            let previous_flag = set_interrupt_flag(false);
+            for port in POWER_EMULATORPORTS {
+                OutPortWord(port, PM1_CONTROL_SLPEN);
+            }
+            wait_until(|| false);
+            set_interrupt_flag(previous_flag);
+        }
+    }
+}
+
+// Writes the reset value to the FADT reset register. A register in PCI
+// configuration space is on bus 0, with the device in bits 32-47, the
+// function in bits 16-31 and the offset in bits 0-15 of the address.
+fn acpi_reset() {
+    let fadt = match acpi::fadt() {
+        Some(fadt) => fadt,
+        None => return,
+    };
+    let (flags, register, value) = (fadt.flags, fadt.reset_register, fadt.reset_value);
+    if fadt.header.revision < 2 || flags & FADT_FLAGS_RESETREGISTER == 0 {
+        return;
+    }
+
+    let address = register.address;
+    match register.address_space {
+        ACPI_ADDRESSSPACE_IO => OutPortByte(address as u16, value),
+        ACPI_ADDRESSSPACE_MEMORY => unsafe { write_volatile(address as *mut u8, value) },
+        ACPI_ADDRESSSPACE_PCICONFIG => {
+            let device = ((address >> 32) & 0x1F) as u8;
+            let function = ((address >> 16) & 0x07) as u8;
+            let pci_address = PciAddress::new(0, 0, device, function);
+            pci::write_config_byte(pci_address, (address & 0xFFFF) as u16, value);
+        }
+        _ => return,
+    }
+    wait_until(|| false);
+}
+
+// With an empty IDT neither the breakpoint nor the double fault it turns into
+// can be delivered, and the CPU shuts down into a reset
+fn triple_fault() -> ! {
+    let idtr = [0u64; 2];
+    LoadIDTR(&idtr as *const _ as u64);
+    Int3();
+    loop {}
+}
+
+// Tries the ACPI reset register, then the keyboard controller, then a triple
+// fault
+pub fn reboot() -> ! {
+    DisableInterrupt();
+    acpi_reset();
+    keyboard::Reboot();
+    wait_until(|| false);
+    triple_fault()
+}
@@ -0,0 +1,113 @@
+use core::sync::atomic::Ordering;
+
+use crate::{interrupt, percpu, smp, spinlock::SpinLockGuard, timer};
+
+use super::{
+    get_process_from_id, is_fpu_owner, release_fpu, Process, ProcessState, RRScheduler, Scheduler,
+    PROCESS_FLAG_ENDTASK, PROCESS_FLAG_IDLETASK,
+};
+
+// Each core looks at the others every interval, and pulls a task over when
+// the busiest one has at least this many more queued than it does
+const BALANCE_INTERVALMS: u64 = 50;
+const BALANCE_IMBALANCE: u64 = 2;
+
+// The online core `affinity` allows with the fewest queued tasks
+pub(super) fn select_cpu(affinity: u64) -> Option<usize> {
+    let allowed = affinity & smp::online_mask();
+    (0..smp::cpu_count())
+        .filter(|&cpu| allowed & 1 << cpu != 0)
+        .min_by_key(|&cpu| percpu::get(cpu).scheduler().lock().total_count())
+}
+
+fn can_run_on(process: &Process, cpu: usize) -> bool {
+    process.flags & (PROCESS_FLAG_IDLETASK | PROCESS_FLAG_ENDTASK) == 0
+        && process.affinity & 1 << cpu != 0
+}
+
+// The lower core is always locked first, so that two cores moving tasks
+// toward each other cannot deadlock
+fn lock_pair(
+    a: usize,
+    b: usize,
+) -> (SpinLockGuard<'static, RRScheduler>, SpinLockGuard<'static, RRScheduler>) {
+    if a < b {
+        let first = percpu::get(a).scheduler().lock();
+        (first, percpu::get(b).scheduler().lock())
+    } else {
+        let first = percpu::get(b).scheduler().lock();
+        (percpu::get(a).scheduler().lock(), first)
+    }
+}
+
+// Moves one queued task `movable` accepts from one core to another. The FPU
+// state a task left on this core is saved first; one left in the registers
+// of another core cannot be reached, so such tasks stay where they are.
+// The pair of guards is not let go in the reverse order it was taken, so
+// interrupts are kept off until both are gone.
+fn migrate(from: usize, to: usize, movable: &dyn Fn(&Process) -> bool) -> bool {
+    interrupt::without_interrupt(|| {
+        let local = from == smp::current_cpu();
+        let (mut source, mut target) = lock_pair(from, to);
+        let pid = match source.take(&|process| {
+            movable(process) && (local || !is_fpu_owner(from, process.id))
+        }) {
+            Some(pid) => pid,
+            None => return false,
+        };
+        let process = get_process_from_id(pid).unwrap();
+        if local {
+            release_fpu(process);
+        }
+        process.set_cpu(to);
+        let _ = target.add_ready_list(pid);
+        true
+    })
+}
+
+// Run by the idle task: takes a ready task this core may run from the first
+// other core that has one
+pub(super) fn steal() -> bool {
+    let cpu = smp::current_cpu();
+    (0..smp::cpu_count())
+        .filter(|&source| source != cpu && smp::is_online(source))
+        .any(|source| {
+            migrate(source, cpu, &|process| {
+                process.state == ProcessState::Ready && can_run_on(process, cpu)
+            })
+        })
+}
+
+// Hands queued tasks whose affinity no longer includes this core to the
+// cores it does include
+fn push_disallowed(cpu: usize) {
+    for target in (0..smp::cpu_count()).filter(|&target| target != cpu && smp::is_online(target)) {
+        while migrate(cpu, target, &|process| {
+            process.affinity & 1 << cpu == 0 && can_run_on(process, target)
+        }) {}
+    }
+}
+
+// Called on every timer tick of each core
+pub fn balance_load() {
+    let cpu = smp::current_cpu();
+    let now = timer::get_tick_count();
+    let next_tick = &percpu::get(cpu).balance_tick;
+    if now < next_tick.load(Ordering::Relaxed) {
+        return;
+    }
+    next_tick.store(now + BALANCE_INTERVALMS, Ordering::Relaxed);
+
+    push_disallowed(cpu);
+
+    let local = percpu::get(cpu).scheduler().lock().total_count();
+    let busiest = (0..smp::cpu_count())
+        .filter(|&source| source != cpu && smp::is_online(source))
+        .map(|source| (source, percpu::get(source).scheduler().lock().total_count()))
+        .max_by_key(|&(_, count)| count);
+    if let Some((source, count)) = busiest {
+        if count >= local + BALANCE_IMBALANCE {
+            migrate(source, cpu, &|process| can_run_on(process, cpu));
+        }
+    }
+}
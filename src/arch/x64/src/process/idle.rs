@@ -1,53 +1,84 @@
-use crate::{interrupt, println};
+use core::sync::atomic::Ordering;
 
-use super::{yield_next, PROCESS_POOL, SCHEDULER};
-use crate::assembly;
+use crate::{percpu, println, smp, timer, utility::set_interrupt_flag};
 
-pub(super) static mut IDLE_COUNT: u64 = 0;
-pub(super) static mut TICK_COUNT: u64 = 0;
-static mut PROCESS_LOAD: u64 = 0;
+use super::{account_ticks, balance, idle_deadline, local_scheduler, release_process, yield_next};
+use crate::assembly;
 
+// Each core runs one, pinned to it, so the area it looks at stays its own
 pub fn idle_process() {
-    let mut idle_count = unsafe { IDLE_COUNT };
-    let mut tick_count = unsafe { TICK_COUNT };
+    let area = percpu::current();
+    let mut idle_count = area.idle_count.load(Ordering::Relaxed);
+    let mut tick_count = area.tick_count.load(Ordering::Relaxed);
     loop {
-        let current_idle_count = unsafe { IDLE_COUNT };
-        let current_tick_count = unsafe { TICK_COUNT };
-        unsafe {
-            PROCESS_LOAD = if current_tick_count - tick_count == 0 {
-                0
-            } else {
-                100 - (current_idle_count - idle_count) * 100 / (current_tick_count - tick_count)
-            }
+        let current_idle_count = area.idle_count.load(Ordering::Relaxed);
+        let current_tick_count = area.tick_count.load(Ordering::Relaxed);
+        let load = if current_tick_count - tick_count == 0 {
+            0
+        } else {
+            100 - (current_idle_count - idle_count) * 100 / (current_tick_count - tick_count)
         };
+        area.process_load.store(load, Ordering::Relaxed);
         idle_count = current_idle_count;
         tick_count = current_tick_count;
-        halting(unsafe { PROCESS_LOAD });
-        if interrupt::without_interrupt(|| SCHEDULER.lock().wait.count()) != 0 {
-            while let Some(wait) =
-                interrupt::without_interrupt(|| SCHEDULER.lock().wait.pop_front())
-            {
-                println!("IDLE: Task ID [0x{wait:X}] ended");
-                PROCESS_POOL.lock().dealloc(wait);
-            }
+        // The lock is let go before each release, which takes it again
+        loop {
+            let wait = match local_scheduler().lock().wait.pop_front() {
+                Some(wait) => wait,
+                None => break,
+            };
+            println!("IDLE: Task ID [0x{wait:X}] ended");
+            release_process(wait);
+        }
+        // Work is taken from the other cores before this one goes to sleep
+        if !balance::steal() {
+            halt_until_runnable();
         }
         yield_next();
     }
 }
 
-fn halting(load: u64) {
-    if load < 40 {
-        assembly::halt();
-        assembly::halt();
-        assembly::halt();
-    } else if load < 80 {
-        assembly::halt();
-        assembly::halt();
-    } else if load < 95 {
-        assembly::halt();
+// Halts while no task but the idle task can run. In tickless mode the timer
+// is turned into a one-shot for the earliest sleep deadline instead of
+// waking the CPU up every tick. Only the bootstrap processor owns the PIT;
+// the other cores are woken by their local APIC timer.
+fn halt_until_runnable() {
+    let previous_flag = set_interrupt_flag(false);
+    let cpu = smp::current_cpu();
+    if let Some(deadline) = idle_deadline(cpu) {
+        let now = timer::get_tick_count();
+        if deadline > now {
+            let oneshot = cpu == 0 && timer::is_tickless();
+            if oneshot {
+                timer::start_oneshot(deadline - now);
+            }
+            percpu::current().halt_count.fetch_add(1, Ordering::Relaxed);
+            assembly::halt_until_interrupt();
+            if oneshot {
+                account_ticks(timer::stop_oneshot());
+            }
+        }
     }
+    set_interrupt_flag(previous_flag);
+}
+
+// Load of one core, as its idle task last worked it out
+pub fn cpu_load(cpu: usize) -> u64 {
+    percpu::get(cpu).process_load.load(Ordering::Relaxed)
 }
 
+// Average load of the online cores
 pub fn process_load() -> u64 {
-    unsafe { PROCESS_LOAD }
+    let online = (0..smp::cpu_count()).filter(|&cpu| smp::is_online(cpu));
+    let (sum, count) = online.fold((0, 0), |(sum, count), cpu| (sum + cpu_load(cpu), count + 1));
+    if count == 0 {
+        0
+    } else {
+        sum / count
+    }
+}
+
+// Times the idle tasks have halted their core
+pub fn halt_count() -> u64 {
+    (0..smp::cpu_count()).map(|cpu| percpu::get(cpu).halt_count.load(Ordering::Relaxed)).sum()
 }
@@ -1,58 +1,191 @@
-use core::{hint::black_box, mem::size_of, ptr::NonNull};
-
-use spin::{Lazy, Mutex};
+use core::{
+    mem::size_of,
+    ptr::{self, NonNull},
+    str,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::{
-    descriptor::{GDT_KERNELCODESEGMENT, GDT_KERNELDATASEGMENT, IST_SIZE, IST_STARTADDRESS},
-    interrupt, println,
-    utility::{memcpy, memset},
+    assembly::read_TSC,
+    channel,
+    descriptor::{
+        GDT_KERNELCODESEGMENT, GDT_KERNELDATASEGMENT, GDT_USERCODESEGMENT,
+        GDT_USERDATASEGMENT, SELECTOR_RPL_3,
+    },
+    fpu::{self, FPUContext},
+    interrupt, memory,
+    page, percpu,
+    println, smp,
+    spinlock::{SpinLock, SpinLockGuard},
+    timer,
+    trace::{self, TraceKind},
+    utility::memset,
 };
 
-use self::round_robin::{RRScheduler, PRIORITY_WAIT};
+use self::{
+    realtime::RealTime,
+    round_robin::PRIORITY_WAIT,
+    table::TaskTable,
+};
 
-pub use idle::{idle_process, process_load};
-pub use round_robin::{get_priority, PRIORITY_HIGHIST, PRIORITY_LOWIST, PRIORITY_MIDDLE};
+pub use balance::balance_load;
+pub use idle::{cpu_load, halt_count, idle_process, process_load};
+pub use realtime::{
+    clear_realtime, realtime_utilization, set_realtime, wait_next_period, REALTIME_MAXUTILIZATION,
+};
+pub use round_robin::{
+    get_priority, test_run_queue, PRIORITY_HIGHIST, PRIORITY_LOWIST, PRIORITY_MIDDLE,
+};
+pub(crate) use round_robin::RRScheduler;
+pub use signal::*;
+pub use wait::{wait_on, WaitQueue};
 
+mod balance;
 mod idle;
+mod realtime;
 mod round_robin;
+mod signal;
+mod table;
+mod wait;
 
 const PROCESS_REGISTERCOUNT: usize = 5 + 19;
-pub(crate) const PROCESS_MAXCOUNT: usize = 1024;
-const PROCESS_POOLADDRESS: u64 = 0x800000;
 
-const PROCESS_STACKADDRESS: u64 =
-    PROCESS_POOLADDRESS + (size_of::<Process>() * PROCESS_MAXCOUNT) as u64;
 const PROCESS_STACKSIZE: u64 = 8192;
+// Fresh stacks are filled with the pattern to find how deep they were used,
+// and the lowest word holds the canary checked on every switch
+const PROCESS_STACKPATTERN: u64 = 0x5A5A5A5A5A5A5A5A;
+const PROCESS_STACKCANARY: u64 = 0xDEADC0DEDEADC0DE;
 
-const PROCESS_INVALIDID: u64 = 0xFFFFFFFFFFFFFFFF;
+// Threads started from ring 3 get a user stack mapped with 4KB pages in the
+// address space of their process, so no other process can reach it. There is
+// a slot per task table index below PROCESS_USERSTACKCOUNT, each starting
+// with an unmapped guard page, well below where the program stack ends.
+const PROCESS_USERSTACKSIZE: u64 = 8192;
+const PROCESS_USERSTACKSLOTSIZE: u64 = PROCESS_USERSTACKSIZE + page::PAGE_SIZE;
+const PROCESS_USERSTACKCOUNT: u64 = 1024;
+const PROCESS_USERSTACKADDRESS: u64 =
+    page::USER_ENDADDRESS - 0x40000000 - PROCESS_USERSTACKSLOTSIZE * PROCESS_USERSTACKCOUNT;
+
+pub(crate) const PROCESS_INVALIDID: u64 = 0xFFFFFFFFFFFFFFFF;
+const PROCESS_MAXHANDLECOUNT: usize = 16;
+pub const PROCESS_MAXNAMELENGTH: usize = 16;
+
+pub const PROCESS_EXITCODE_KILLED: u64 = 0xFFFFFFFFFFFFFFFF;
+pub const PROCESS_EXITCODE_FAULT: u64 = 0xFFFFFFFFFFFFFFFE;
+pub const PROCESS_EXITCODE_STACKOVERFLOW: u64 = 0xFFFFFFFFFFFFFFFD;
+pub const PROCESS_EXITCODE_WATCHDOG: u64 = 0xFFFFFFFFFFFFFFFC;
 
 // flags
 const PROCESS_FLAG_ENDTASK: u64 = 0x8000000000000000;
+const PROCESS_FLAG_ZOMBIE: u64 = 0x4000000000000000;
+pub const PROCESS_FLAG_PROCESS: u64 = 0x2000000000000000;
+pub const PROCESS_FLAG_THREAD: u64 = 0x1000000000000000;
+const PROCESS_FLAG_FPUUSED: u64 = 0x0400000000000000;
+pub const PROCESS_FLAG_USER: u64 = 0x0200000000000000;
 pub const PROCESS_FLAG_IDLETASK: u64 = 0x0800000000000000;
 
+// Affinity masks have a bit for each core a task may run on
+pub const AFFINITY_ALL: u64 = 0xFFFFFFFFFFFFFFFF;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    Sleep,
+    Join,
+    Wait,
+    Key,
+    Send,
+    Receive,
+    Period,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Ready,
+    Running,
+    Blocked(BlockReason),
+    Waiting(BlockReason),
+    Stopped,
+    Zombie,
+}
+
+impl ProcessState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProcessState::Ready => "Ready",
+            ProcessState::Running => "Running",
+            ProcessState::Blocked(reason) | ProcessState::Waiting(reason) => match reason {
+                BlockReason::Sleep => "Sleeping",
+                BlockReason::Join => "Blocked(Join)",
+                BlockReason::Wait => "Blocked(Wait)",
+                BlockReason::Key => "Blocked(Key)",
+                BlockReason::Send => "Blocked(Send)",
+                BlockReason::Receive => "Blocked(Recv)",
+                BlockReason::Period => "Blocked(Period)",
+            },
+            ProcessState::Stopped => "Stopped",
+            ProcessState::Zombie => "Zombie",
+        }
+    }
+}
+
+// Registers in the order the switch paths push them, ending with the frame
+// iretq takes. A task that is not running keeps one on top of its own stack.
 #[repr(C, packed(1))]
 pub struct Context {
     pub(crate) registers: [u64; PROCESS_REGISTERCOUNT],
 }
 
 extern "C" {
-    pub fn context_switch(current: &Context, next: &Context);
+    // Saves the running task's context on its stack, stores the stack pointer
+    // through `current` unless it is null, and resumes from `next`
+    pub fn context_switch(current: *mut u64, next: u64);
 }
 
 pub struct Process {
-    pub context: Context,
+    // Stack pointer the task's saved context starts at
+    stack_pointer: u64,
+    fpu_context: FPUContext,
     pub id: u64,
     pub flags: u64,
+    pub state: ProcessState,
+    name: [u8; PROCESS_MAXNAMELENGTH],
+    name_length: usize,
+
+    pub parent: u64,
+    pub exit_code: u64,
+    child: u64,
+    sibling: u64,
+
+    pub group: u64,
+    thread: u64,
+    thread_sibling: u64,
+    pub memory_address: u64,
+    pub memory_size: u64,
+    pub page_table: u64,
+    handles: [u64; PROCESS_MAXHANDLECOUNT],
+    // Handlers live in the process, pending signals in each of its tasks
+    signal_handlers: [u64; SIGNAL_MAXCOUNT],
+    signal_pending: u64,
+    signal_delivering: bool,
+
+    pub cpu_ticks: u64,
+    pub voluntary_switches: u64,
+    pub preempted_switches: u64,
+    pub created_tick: u64,
+    pub last_run_tick: u64,
+    // Tick a task blocked in `sleep` or waiting for its period waits for
+    pub wake_tick: u64,
+    pub realtime: RealTime,
+    pub affinity: u64,
+    // Core whose queues hold the task, or that runs it. It only changes with
+    // the schedulers of both cores locked.
+    cpu: AtomicUsize,
 
     stack: u64,
     stack_size: u64,
-}
 
-pub struct ProcessPool<'a> {
-    pool: &'a mut [Process; PROCESS_MAXCOUNT],
-    max_count: usize,
-    use_count: usize,
-    alloc_count: usize,
+    run_next: u64,
+    free_next: u64,
 }
 
 impl Process {
@@ -60,117 +193,313 @@ impl Process {
     const FS: usize = 1;
     const ES: usize = 2;
     const DS: usize = 3;
-    const RBP: usize = 18;
-    const RIP: usize = 19;
-    const CS: usize = 20;
-    const RFLAGS: usize = 21;
-    const RSP: usize = 22;
+    const RSI: usize = 12;
+    const RDI: usize = 13;
+    pub(crate) const RBP: usize = 18;
+    pub(crate) const RIP: usize = 19;
+    pub(crate) const CS: usize = 20;
+    pub(crate) const RFLAGS: usize = 21;
+    pub(crate) const RSP: usize = 22;
     const SS: usize = 23;
 
-    pub fn new(flags: u64, entry_point: u64, stack: u64, stack_size: u64) -> Self {
+    pub fn new(flags: u64, entry_point: u64, arg: u64, stack: u64, stack_size: u64) -> Self {
         let mut process = Self {
-            context: Context::empty(),
+            stack_pointer: 0,
+            fpu_context: FPUContext::empty(),
             id: 0,
             flags,
+            state: ProcessState::Ready,
+            name: [0; PROCESS_MAXNAMELENGTH],
+            name_length: 0,
+            parent: PROCESS_INVALIDID,
+            exit_code: 0,
+            child: PROCESS_INVALIDID,
+            sibling: PROCESS_INVALIDID,
+            group: PROCESS_INVALIDID,
+            thread: PROCESS_INVALIDID,
+            thread_sibling: PROCESS_INVALIDID,
+            memory_address: 0,
+            memory_size: 0,
+            page_table: page::KERNEL_PAGETABLE,
+            handles: [0; PROCESS_MAXHANDLECOUNT],
+            signal_handlers: [SIGNAL_DEFAULT; SIGNAL_MAXCOUNT],
+            signal_pending: 0,
+            signal_delivering: false,
+            cpu_ticks: 0,
+            voluntary_switches: 0,
+            preempted_switches: 0,
+            created_tick: 0,
+            last_run_tick: 0,
+            wake_tick: 0,
+            realtime: RealTime::none(),
+            affinity: AFFINITY_ALL,
+            cpu: AtomicUsize::new(0),
             stack,
             stack_size,
+            run_next: PROCESS_INVALIDID,
+            free_next: PROCESS_INVALIDID,
         };
-        process.set(flags, entry_point, stack, stack_size);
+        process.set(flags, entry_point, arg, stack, stack_size);
         process
     }
 
-    pub fn set(&mut self, flags: u64, entry_point: u64, stack: u64, stack_size: u64) {
+    pub fn set(&mut self, flags: u64, entry_point: u64, arg: u64, stack: u64, stack_size: u64) {
+        let words = (stack_size / size_of::<u64>() as u64) as usize;
+        unsafe {
+            let bottom = stack as *mut u64;
+            *bottom = PROCESS_STACKCANARY;
+            for index in 1..words {
+                *bottom.add(index) = PROCESS_STACKPATTERN;
+            }
+        }
+        // Returning from the entry point lands in task_return, which exits with
+        // 0. The first context sits right below that return address.
+        let return_address = stack + stack_size - size_of::<u64>() as u64;
+        unsafe { *(return_address as *mut u64) = task_return as u64 };
+        self.stack_pointer = return_address - size_of::<Context>() as u64;
+        let context = self.saved_context();
         memset(
-            &mut self.context as *mut Context as *mut u8,
+            context as *mut Context as *mut u8,
             0,
             size_of::<Context>() as isize,
         );
-        self.context.registers[Process::RSP] = stack + stack_size;
-        self.context.registers[Process::RBP] = stack + stack_size;
+        context.registers[Process::RSP] = return_address;
+        context.registers[Process::RBP] = stack + stack_size;
+        context.registers[Process::RDI] = arg;
 
-        self.context.registers[Process::CS] = GDT_KERNELCODESEGMENT as u64;
-        self.context.registers[Process::DS] = GDT_KERNELDATASEGMENT as u64;
-        self.context.registers[Process::ES] = GDT_KERNELDATASEGMENT as u64;
-        self.context.registers[Process::FS] = GDT_KERNELDATASEGMENT as u64;
-        self.context.registers[Process::GS] = GDT_KERNELDATASEGMENT as u64;
-        self.context.registers[Process::SS] = GDT_KERNELDATASEGMENT as u64;
+        context.registers[Process::CS] = GDT_KERNELCODESEGMENT as u64;
+        context.registers[Process::DS] = GDT_KERNELDATASEGMENT as u64;
+        context.registers[Process::ES] = GDT_KERNELDATASEGMENT as u64;
+        context.registers[Process::FS] = GDT_KERNELDATASEGMENT as u64;
+        context.registers[Process::GS] = GDT_KERNELDATASEGMENT as u64;
+        context.registers[Process::SS] = GDT_KERNELDATASEGMENT as u64;
 
-        self.context.registers[Process::RIP] = entry_point;
+        context.registers[Process::RIP] = entry_point;
 
-        self.context.registers[Process::RFLAGS] |= 0x0200;
+        context.registers[Process::RFLAGS] |= 0x0200;
 
         self.flags = flags;
+        self.state = ProcessState::Ready;
+        self.name_length = 0;
+        self.parent = PROCESS_INVALIDID;
+        self.exit_code = 0;
+        self.child = PROCESS_INVALIDID;
+        self.sibling = PROCESS_INVALIDID;
+        self.group = PROCESS_INVALIDID;
+        self.thread = PROCESS_INVALIDID;
+        self.thread_sibling = PROCESS_INVALIDID;
+        self.memory_address = 0;
+        self.memory_size = 0;
+        self.page_table = page::KERNEL_PAGETABLE;
+        self.handles = [0; PROCESS_MAXHANDLECOUNT];
+        self.signal_handlers = [SIGNAL_DEFAULT; SIGNAL_MAXCOUNT];
+        self.signal_pending = 0;
+        self.signal_delivering = false;
+        self.cpu_ticks = 0;
+        self.voluntary_switches = 0;
+        self.preempted_switches = 0;
+        self.created_tick = timer::get_tick_count();
+        self.last_run_tick = self.created_tick;
+        self.wake_tick = 0;
+        self.affinity = AFFINITY_ALL;
         self.stack = stack;
         self.stack_size = stack_size;
     }
-}
 
-impl Context {
-    pub const fn empty() -> Self {
-        Self {
-            registers: [0u64; PROCESS_REGISTERCOUNT],
+    pub fn name(&self) -> &str {
+        str::from_utf8(&self.name[..self.name_length]).unwrap_or("")
+    }
+
+    // Names longer than PROCESS_MAXNAMELENGTH are cut at a character boundary
+    pub fn set_name(&mut self, name: &str) {
+        let mut length = name.len().min(PROCESS_MAXNAMELENGTH);
+        while !name.is_char_boundary(length) {
+            length -= 1;
         }
+        self.name[..length].copy_from_slice(&name.as_bytes()[..length]);
+        self.name_length = length;
     }
-}
 
-impl<'a> ProcessPool<'a> {
-    unsafe fn new(address: u64) -> Self {
-        let address = address as *mut [Process; PROCESS_MAXCOUNT];
-        memset(
-            address as *mut u8,
-            0,
-            (size_of::<Process>() * PROCESS_MAXCOUNT) as isize,
-        );
-        for (idx, process) in (*address).iter_mut().enumerate() {
-            process.id = idx as u64;
+    pub fn is_ended(&self) -> bool {
+        self.flags & PROCESS_FLAG_ENDTASK != 0
+    }
+
+    pub fn is_zombie(&self) -> bool {
+        self.flags & PROCESS_FLAG_ZOMBIE != 0
+    }
+
+    // Turns a freshly set up task into one that starts in ring 3 on the given
+    // stack. The stack set up by `set` stays as its kernel stack.
+    pub fn set_user_mode(&mut self, stack_pointer: u64) {
+        let context = self.saved_context();
+        context.registers[Process::RSP] = stack_pointer;
+        context.registers[Process::RBP] = 0;
+
+        let code = (GDT_USERCODESEGMENT | SELECTOR_RPL_3) as u64;
+        let data = (GDT_USERDATASEGMENT | SELECTOR_RPL_3) as u64;
+        context.registers[Process::CS] = code;
+        context.registers[Process::DS] = data;
+        context.registers[Process::ES] = data;
+        context.registers[Process::FS] = data;
+        context.registers[Process::GS] = data;
+        context.registers[Process::SS] = data;
+
+        self.flags |= PROCESS_FLAG_USER;
+    }
+
+    // Context the task resumes from; only meaningful while it is not running
+    pub(crate) fn saved_context<'a>(&self) -> &'a mut Context {
+        unsafe { &mut *(self.stack_pointer as *mut Context) }
+    }
+
+    pub fn kernel_stack_top(&self) -> u64 {
+        self.stack + self.stack_size
+    }
+
+    pub fn stack_size(&self) -> u64 {
+        self.stack_size
+    }
+
+    // Bytes between the top of the stack and the deepest word that no longer
+    // holds the fill pattern
+    pub fn stack_usage(&self) -> u64 {
+        if self.stack_size == 0 {
+            return 0;
         }
-        Self {
-            pool: &mut *address,
-            max_count: PROCESS_MAXCOUNT,
-            use_count: 0,
-            alloc_count: 1,
+        let bottom = self.stack as *const u64;
+        let words = (self.stack_size / size_of::<u64>() as u64) as usize;
+        let mut index = 1;
+        while index < words && unsafe { *bottom.add(index) } == PROCESS_STACKPATTERN {
+            index += 1;
+        }
+        if !self.is_stack_intact() {
+            index = 0;
         }
+        self.stack_size - (index * size_of::<u64>()) as u64
     }
 
-    pub fn get(&mut self, idx: usize) -> *mut Process {
-        &mut self.pool[idx] as *mut Process
+    // The first task runs on the boot stack, which has no canary
+    pub fn is_stack_intact(&self) -> bool {
+        self.stack_size == 0 || unsafe { *(self.stack as *const u64) } == PROCESS_STACKCANARY
     }
 
-    pub fn alloc(&mut self) -> Option<*mut Process> {
-        let mut i = 0;
-        if self.max_count == self.use_count {
+    pub fn cpu(&self) -> usize {
+        self.cpu.load(Ordering::Acquire)
+    }
+
+    fn set_cpu(&self, cpu: usize) {
+        self.cpu.store(cpu, Ordering::Release);
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.flags & PROCESS_FLAG_USER != 0
+    }
+
+    pub fn is_thread(&self) -> bool {
+        self.flags & PROCESS_FLAG_THREAD != 0
+    }
+
+    // Stopped tasks wait to be continued and waiting tasks to be woken, off
+    // the run queues
+    fn is_queued_on_switch(&self) -> bool {
+        !matches!(self.state, ProcessState::Stopped | ProcessState::Waiting(_))
+    }
+
+    pub fn children(&self) -> Children {
+        Children { next: self.child }
+    }
+
+    pub fn threads(&self) -> Threads {
+        Threads { next: self.thread }
+    }
+
+    // The timer interrupt charges ticks behind the back of whoever reads
+    // them, so a task waiting on its own count has to read it afresh
+    pub fn read_cpu_ticks(&self) -> u64 {
+        unsafe { ptr::read_volatile(&self.cpu_ticks) }
+    }
+
+    // Share of the CPU over the task's whole lifetime, in percent
+    pub fn cpu_usage(&self) -> u64 {
+        let lifetime = timer::get_tick_count() - self.created_tick;
+        if lifetime == 0 {
+            0
+        } else {
+            self.cpu_ticks.min(lifetime) * 100 / lifetime
+        }
+    }
+}
+
+// Memory a user process is started in, prepared by the program loader
+pub struct UserSpace {
+    pub page_table: u64,
+    pub stack_pointer: u64,
+    pub memory_address: u64,
+    pub memory_size: u64,
+}
+
+pub struct Children {
+    next: u64,
+}
+
+impl Iterator for Children {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.next == PROCESS_INVALIDID {
             return None;
         }
-        for (idx, process) in self.pool.iter().enumerate() {
-            if (process.id >> 32) == 0 {
-                i = idx;
-                break;
-            }
+        let pid = self.next;
+        self.next = get_process_from_id(pid)?.sibling;
+        Some(pid)
+    }
+}
+
+pub struct Threads {
+    next: u64,
+}
+
+impl Iterator for Threads {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.next == PROCESS_INVALIDID {
+            return None;
         }
-        let target = &mut self.pool[i];
-        target.id = ((self.alloc_count << 32) | i) as u64;
-        self.alloc_count = self.alloc_count.overflowing_add(1).0;
-        self.use_count += 1;
-        Some(target as *mut Process)
+        let pid = self.next;
+        self.next = get_process_from_id(pid)?.thread_sibling;
+        Some(pid)
     }
+}
 
-    pub fn dealloc(&mut self, id: u64) -> Option<()> {
-        let idx = id & 0xFFFFFFFF;
-        memset(
-            &mut self.pool[idx as usize].context as *mut Context as *mut u8,
-            0,
-            size_of::<Context>() as isize,
+// Entry points return nothing, so whatever is left in RAX is no exit code.
+// A task that has one to report ends through `exit`.
+#[naked]
+extern "C" fn task_return() {
+    use core::arch::asm;
+    unsafe {
+        asm!(
+            "xor edi, edi",
+            "call {func}",
+            func = sym task_exit,
+            options(noreturn)
         );
-        self.pool[idx as usize].id = idx;
-        self.use_count -= 0;
-        Some(())
     }
 }
 
-unsafe impl<'a> Send for ProcessPool<'a> {}
+extern "C" fn task_exit(exit_code: u64) {
+    exit(exit_code);
+}
+
+impl Context {
+    pub const fn empty() -> Self {
+        Self {
+            registers: [0u64; PROCESS_REGISTERCOUNT],
+        }
+    }
+}
 
-pub(crate) static PROCESS_POOL: Lazy<Mutex<ProcessPool>> =
-    Lazy::new(|| Mutex::new(unsafe { ProcessPool::new(PROCESS_POOLADDRESS) }));
+pub(crate) static TASK_TABLE: SpinLock<TaskTable> = SpinLock::new(TaskTable::new());
 
 pub trait Scheduler {
     fn next(&mut self) -> Option<u64>;
@@ -179,31 +508,179 @@ pub trait Scheduler {
     fn set_running(&mut self, pid: u64);
     fn decrease_time(&mut self);
     fn reset_processtime(&mut self);
+    fn expire(&mut self);
     fn change_priority(&mut self, pid: u64, priority: u64) -> Result<(), ()>;
     fn is_expired(&self) -> bool;
     fn remove_process(&mut self, pid: u64) -> Result<u64, ()>;
+    // Takes a queued task that `movable` accepts out of the queues, so that it
+    // can be handed to another core
+    fn take(&mut self, movable: &dyn Fn(&Process) -> bool) -> Option<u64>;
     fn total_count(&self) -> u64;
 }
 
-pub(crate) static SCHEDULER: Lazy<Mutex<RRScheduler>> = Lazy::new(|| {
-    let first = unsafe { &mut *PROCESS_POOL.lock().alloc().unwrap() };
-    first.flags = PRIORITY_HIGHIST;
-    Mutex::new(RRScheduler::new(first.id & 0xFFFFFFFF))
-});
+// Every core schedules from queues of its own, kept in its per-CPU area.
+// Holding the lock keeps interrupts disabled, so the caller cannot be moved
+// to another core while it uses the local one.
+fn local_scheduler() -> &'static SpinLock<RRScheduler> {
+    percpu::current().scheduler()
+}
+
+// The per-CPU area keeps a copy of the running task that can be read without
+// the lock
+fn set_running(scheduler: &mut RRScheduler, pid: u64) {
+    scheduler.set_running(pid);
+    percpu::current().set_current_task(pid);
+}
+
+// Switches away from the idle task count as idle ticks of the core
+fn count_switch(current: &Process) {
+    let area = percpu::current();
+    if current.flags & PROCESS_FLAG_IDLETASK != 0 {
+        area.idle_count.fetch_add(1, Ordering::Relaxed);
+    }
+    area.tick_count.fetch_add(1, Ordering::Relaxed);
+}
+
+// Locks the scheduler of the core a task belongs to. The core is checked
+// again once locked, since the task may have moved while it was waited for.
+fn lock_scheduler_of(process: &Process) -> SpinLockGuard<'static, RRScheduler> {
+    loop {
+        let cpu = process.cpu();
+        let scheduler = percpu::get(cpu).scheduler().lock();
+        if process.cpu() == cpu {
+            return scheduler;
+        }
+    }
+}
+
+// Makes the code running on a core's boot stack its first task
+fn adopt_boot_stack(name: &str, flags: u64, affinity: u64, cpu: usize) {
+    let first = unsafe { &mut *TASK_TABLE.lock().alloc().unwrap() };
+    first.flags = flags;
+    first.state = ProcessState::Running;
+    first.set_name(name);
+    first.group = first.id;
+    first.affinity = affinity;
+    first.set_cpu(cpu);
+    set_running(&mut percpu::get(cpu).scheduler().lock(), first.id);
+}
 
 pub fn init_scheduler() {
-    black_box(SCHEDULER.lock());
+    adopt_boot_stack("shell", PRIORITY_HIGHIST | PROCESS_FLAG_PROCESS, AFFINITY_ALL, 0);
+}
+
+// Called by an application processor once it is online. What runs on its
+// boot stack becomes its idle task, which is pinned to it.
+pub fn init_idle_task(cpu: usize) {
+    adopt_boot_stack(
+        "idle",
+        PRIORITY_LOWIST | PROCESS_FLAG_PROCESS | PROCESS_FLAG_IDLETASK,
+        1 << cpu,
+        cpu,
+    );
 }
 
-pub fn create_task(flags: u64, entry: u64) -> Result<u64, ()> {
-    if let Some(process) = PROCESS_POOL.lock().alloc() {
-        let pid = unsafe { (*process).id & 0xFFFFFFFF };
-        let stack_address = PROCESS_STACKADDRESS + (PROCESS_STACKSIZE * pid as u64);
+// The task goes to the least loaded core its affinity allows
+pub fn create_task(name: &str, flags: u64, entry: u64, arg: u64, affinity: u64) -> Result<u64, ()> {
+    spawn(name, flags | PROCESS_FLAG_PROCESS, entry, arg, affinity, None)
+}
+
+// Threads start with the affinity of the task creating them
+pub fn create_thread(name: &str, flags: u64, entry: u64, arg: u64) -> Result<u64, ()> {
+    let affinity = get_process_from_id(get_pid()).map_or(AFFINITY_ALL, |creator| creator.affinity);
+    spawn(name, flags | PROCESS_FLAG_THREAD, entry, arg, affinity, None)
+}
+
+// Starts a process in its own address space. The stack pointer is expected to
+// point at argc followed by the argv array, which is also passed in RDI/RSI.
+pub fn create_user_process(
+    name: &str,
+    flags: u64,
+    entry: u64,
+    space: &UserSpace,
+) -> Result<u64, ()> {
+    let argc = unsafe {
+        *(page::translate(space.page_table, space.stack_pointer).ok_or(())? as *const u64)
+    };
+    spawn(
+        name,
+        flags | PROCESS_FLAG_PROCESS | PROCESS_FLAG_USER,
+        entry,
+        argc,
+        AFFINITY_ALL,
+        Some(space),
+    )
+}
+
+fn spawn(
+    name: &str,
+    flags: u64,
+    entry: u64,
+    arg: u64,
+    affinity: u64,
+    space: Option<&UserSpace>,
+) -> Result<u64, ()> {
+    let process = TASK_TABLE.lock().alloc();
+    if let Some(process) = process {
+        let pid = unsafe { (*process).id };
+        let stack_address = match memory::alloc_frames(PROCESS_STACKSIZE / memory::FRAME_SIZE) {
+            Some(address) => address,
+            None => {
+                TASK_TABLE.lock().dealloc(pid);
+                return Err(());
+            }
+        };
         unsafe {
-            (*process).set(flags, entry, stack_address, PROCESS_STACKSIZE);
-            if let Err(_) = interrupt::without_interrupt(|| SCHEDULER.lock().add_ready_list(pid)) {
+            (*process).set(flags, entry, arg, stack_address, PROCESS_STACKSIZE);
+            (*process).set_name(name);
+            (*process).affinity = affinity;
+            if let Some(space) = space {
+                (*process).set_user_mode(space.stack_pointer);
+                (*process).saved_context().registers[Process::RSI] =
+                    space.stack_pointer + size_of::<u64>() as u64;
+                (*process).page_table = space.page_table;
+                (*process).memory_address = space.memory_address;
+                (*process).memory_size = space.memory_size;
+            } else if flags & PROCESS_FLAG_USER != 0 {
+                let page_table = get_process_from_id(get_pid())
+                    .and_then(|creator| get_process_from_id(creator.group))
+                    .map_or(page::KERNEL_PAGETABLE, |group| group.page_table);
+                let stack_top = match map_user_stack(page_table, pid) {
+                    Ok(stack_top) => stack_top,
+                    Err(()) => {
+                        TASK_TABLE.lock().dealloc(pid);
+                        return Err(());
+                    }
+                };
+                (*process).set_user_mode(stack_top - size_of::<u64>() as u64);
+            }
+            interrupt::without_interrupt(|| {
+                let parent_id = get_pid();
+                add_child(parent_id, pid);
+                if flags & PROCESS_FLAG_THREAD != 0 {
+                    let group = get_process_from_id(parent_id).unwrap().group;
+                    add_thread(group, pid);
+                    (*process).page_table = get_process_from_id(group).unwrap().page_table;
+                } else {
+                    (*process).group = pid;
+                }
+            });
+            let queued = balance::select_cpu(affinity).ok_or(()).and_then(|cpu| {
+                (*process).set_cpu(cpu);
+                percpu::get(cpu).scheduler().lock().add_ready_list(pid)
+            });
+            if let Err(_) = queued {
+                interrupt::without_interrupt(|| {
+                    remove_child(get_pid(), pid);
+                    remove_thread((*process).group, pid);
+                });
+                if space.is_none() && flags & PROCESS_FLAG_USER != 0 {
+                    unmap_user_stack((*process).page_table, pid);
+                }
+                TASK_TABLE.lock().dealloc(pid);
                 return Err(());
             }
+            trace::record(TraceKind::TaskCreate, pid, (*process).parent);
         }
         Ok(pid)
     } else {
@@ -211,118 +688,698 @@ pub fn create_task(flags: u64, entry: u64) -> Result<u64, ()> {
     }
 }
 
-pub fn schedule() {
-    unsafe {
-        if let Some(next_id) = interrupt::without_interrupt(|| SCHEDULER.lock().next()) {
-            let context_address =
-                (IST_STARTADDRESS + IST_SIZE) as u64 - size_of::<Context>() as u64;
-            let current_id = interrupt::without_interrupt(|| SCHEDULER.lock().running());
-            let current = get_process_from_id(current_id).unwrap();
-            let next = get_process_from_id(next_id).unwrap();
-
-            interrupt::without_interrupt(|| SCHEDULER.lock().set_running(next_id));
-
-            if current.flags & PROCESS_FLAG_ENDTASK == 0 {
-                if current.flags & PROCESS_FLAG_IDLETASK != 0 {
-                    idle::IDLE_COUNT += 1;
-                }
-                idle::TICK_COUNT += 1;
+fn user_stack_address(pid: u64) -> Option<u64> {
+    let index = table::index_of(pid) as u64;
+    (index < PROCESS_USERSTACKCOUNT)
+        .then(|| PROCESS_USERSTACKADDRESS + PROCESS_USERSTACKSLOTSIZE * index + page::PAGE_SIZE)
+}
 
-                memcpy(
-                    &mut current.context as *mut Context as *mut u8,
-                    context_address as *mut u8,
-                    size_of::<Context>() as isize,
-                );
+// Maps zeroed frames for the user stack of a thread into the address space of
+// its process and returns the top. Ring 3 only runs in processes of its own,
+// so there is nothing to map into the kernel address space.
+fn map_user_stack(page_table: u64, pid: u64) -> Result<u64, ()> {
+    let stack = user_stack_address(pid).ok_or(())?;
+    if page_table == page::KERNEL_PAGETABLE {
+        return Err(());
+    }
+    for address in (stack..stack + PROCESS_USERSTACKSIZE).step_by(page::PAGE_SIZE as usize) {
+        let frame = match memory::alloc_frame() {
+            Some(frame) => frame,
+            None => {
+                unmap_user_stack(page_table, pid);
+                return Err(());
             }
+        };
+        if page::map_page(page_table, address, frame, page::PAGE_FLAGS_USERDATA).is_err() {
+            let _ = memory::free_frame(frame);
+            unmap_user_stack(page_table, pid);
+            return Err(());
+        }
+    }
+    Ok(stack + PROCESS_USERSTACKSIZE)
+}
+
+fn unmap_user_stack(page_table: u64, pid: u64) {
+    let stack = match user_stack_address(pid) {
+        Some(stack) if page_table != page::KERNEL_PAGETABLE => stack,
+        _ => return,
+    };
+    for address in (stack..stack + PROCESS_USERSTACKSIZE).step_by(page::PAGE_SIZE as usize) {
+        if let Ok(frame) = page::unmap_page(page_table, address) {
+            let _ = memory::free_frame(frame);
+        }
+    }
+}
+
+// Called from the timer interrupt with the stack pointer its entry saved the
+// interrupted context at, on the running task's own stack. Returns the stack
+// pointer of the task to resume, which the entry switches to before it
+// restores the registers.
+pub fn schedule(stack_pointer: u64) -> u64 {
+    let mut resume = stack_pointer;
+    // Ending a task takes scheduler locks, so the stack is checked first
+    let current_id = get_pid();
+    let current = get_process_from_id(current_id).unwrap();
+    check_stack(current_id, current);
+
+    let mut scheduler = local_scheduler().lock();
+    if let Some(next_id) = scheduler.next() {
+        let next = get_process_from_id(next_id).unwrap();
+        current.stack_pointer = stack_pointer;
+        trace::record(TraceKind::Switch, current_id, next_id);
+        signal::prepare_delivery(next);
 
-            interrupt::without_interrupt(|| SCHEDULER.lock().add_ready_list(current_id));
+        set_running(&mut scheduler, next_id);
+        switch_kernel_stack(next);
+        next.last_run_tick = timer::get_tick_count();
+        switch_state(current, next);
 
-            memcpy(
-                context_address as *mut u8,
-                &mut next.context as *mut Context as *mut u8,
-                size_of::<Context>() as isize,
-            );
+        if current.flags & PROCESS_FLAG_ENDTASK == 0 {
+            current.preempted_switches += 1;
+            count_switch(current);
         }
-        interrupt::without_interrupt(|| SCHEDULER.lock().reset_processtime());
+
+        if current.is_queued_on_switch() {
+            let _ = scheduler.add_ready_list(current_id);
+        }
+
+        update_task_switched(next_id);
+        resume = next.stack_pointer;
     }
+    scheduler.reset_processtime();
+    resume
 }
 
+// The running task is queued again before context_switch saves its stack
+// pointer, so the timer is kept from switching in between
 pub fn yield_next() {
-    if let Some(next_id) = interrupt::without_interrupt(|| SCHEDULER.lock().next()) {
-        let current_id = interrupt::without_interrupt(|| SCHEDULER.lock().running());
+    interrupt::without_interrupt(|| {
+        let current_id = get_pid();
         let current = get_process_from_id(current_id).unwrap();
+        check_stack(current_id, current);
+
+        let mut scheduler = local_scheduler().lock();
+        scheduler.reset_processtime();
+        let next_id = match scheduler.next() {
+            Some(next_id) => next_id,
+            None => return,
+        };
         let next = get_process_from_id(next_id).unwrap();
+        trace::record(TraceKind::Switch, current_id, next_id);
+        signal::prepare_delivery(next);
 
-        interrupt::without_interrupt(|| SCHEDULER.lock().set_running(next_id));
-        interrupt::without_interrupt(|| SCHEDULER.lock().add_ready_list(current_id));
+        set_running(&mut scheduler, next_id);
+        if current.is_queued_on_switch() {
+            let _ = scheduler.add_ready_list(current_id);
+        }
+        drop(scheduler);
+
+        switch_kernel_stack(next);
+        update_task_switched(next_id);
+        next.last_run_tick = timer::get_tick_count();
+        switch_state(current, next);
         if current.flags & PROCESS_FLAG_ENDTASK != 0 {
-            unsafe { context_switch(&*(0 as *const Context), &next.context) };
+            unsafe { context_switch(ptr::null_mut(), next.stack_pointer) };
         } else {
-            if current.flags & PROCESS_FLAG_IDLETASK != 0 {
-                unsafe { idle::IDLE_COUNT += 1 };
+            current.voluntary_switches += 1;
+            count_switch(current);
+            unsafe { context_switch(&mut current.stack_pointer, next.stack_pointer) };
+        }
+    })
+}
+
+static mut BENCHMARK_RUNNINGSTACK: u64 = 0;
+static mut BENCHMARK_PARTNERSTACK: u64 = 0;
+
+extern "C" fn benchmark_partner() -> ! {
+    loop {
+        unsafe {
+            context_switch(ptr::addr_of_mut!(BENCHMARK_PARTNERSTACK), BENCHMARK_RUNNINGSTACK)
+        };
+    }
+}
+
+// Average TSC cycles one context_switch takes, timed by bouncing `rounds`
+// times between the running task and a bare stack that switches straight
+// back. The scheduler is left out, so only the switch itself is measured.
+pub fn measure_switch(rounds: u64) -> Result<u64, ()> {
+    if rounds == 0 {
+        return Err(());
+    }
+    let stack = memory::alloc_frames(PROCESS_STACKSIZE / memory::FRAME_SIZE).ok_or(())?;
+    let top = stack + PROCESS_STACKSIZE - size_of::<u64>() as u64;
+    let stack_pointer = top - size_of::<Context>() as u64;
+    let context = unsafe { &mut *(stack_pointer as *mut Context) };
+    *context = Context::empty();
+    context.registers[Process::RSP] = top;
+    context.registers[Process::RIP] = benchmark_partner as u64;
+    context.registers[Process::CS] = GDT_KERNELCODESEGMENT as u64;
+    context.registers[Process::DS] = GDT_KERNELDATASEGMENT as u64;
+    context.registers[Process::ES] = GDT_KERNELDATASEGMENT as u64;
+    context.registers[Process::FS] = GDT_KERNELDATASEGMENT as u64;
+    context.registers[Process::GS] = GDT_KERNELDATASEGMENT as u64;
+    context.registers[Process::SS] = GDT_KERNELDATASEGMENT as u64;
+    // Reserved bit 1 only, so the partner runs with interrupts disabled
+    context.registers[Process::RFLAGS] = 0x0002;
+
+    let cycles = interrupt::without_interrupt(|| unsafe {
+        BENCHMARK_PARTNERSTACK = stack_pointer;
+        let start = read_TSC();
+        for _ in 0..rounds {
+            context_switch(ptr::addr_of_mut!(BENCHMARK_RUNNINGSTACK), BENCHMARK_PARTNERSTACK);
+        }
+        read_TSC() - start
+    });
+    let _ = memory::free_frames(stack, PROCESS_STACKSIZE / memory::FRAME_SIZE);
+    Ok(cycles / (rounds * 2))
+}
+
+// A task that ran past the bottom of its stack has already overwritten
+// memory below it, so it is ended (along with its threads) while being
+// switched out instead of being allowed to run again
+fn check_stack(pid: u64, current: &mut Process) {
+    if current.flags & PROCESS_FLAG_ENDTASK != 0 || current.is_stack_intact() {
+        return;
+    }
+    if !current.is_thread() {
+        for thread_id in current.threads() {
+            end_task(thread_id, PROCESS_EXITCODE_STACKOVERFLOW);
+        }
+    }
+    mark_ended(pid, PROCESS_EXITCODE_STACKOVERFLOW);
+}
+
+// Blocked tasks still get picked to poll their condition, so only tasks that
+// are ready or running change state on a switch. Waiting tasks are parked on
+// a wait queue and are not picked until woken.
+fn switch_state(current: &mut Process, next: &mut Process) {
+    if current.state == ProcessState::Running {
+        current.state = ProcessState::Ready;
+    }
+    if next.state == ProcessState::Ready {
+        next.state = ProcessState::Running;
+    }
+}
+
+// Marks the running task as blocked until `unblock` is called
+fn block(reason: BlockReason) {
+    if let Some(process) = get_process_from_id(get_pid()) {
+        process.state = ProcessState::Blocked(reason);
+    }
+}
+
+fn unblock() {
+    if let Some(process) = get_process_from_id(get_pid()) {
+        process.state = ProcessState::Running;
+    }
+}
+
+// Blocks the running task while `poll` returns None, yielding in between.
+// The state is set again on every round since a stop and continue in the
+// meantime leaves the task Ready.
+pub fn block_on<T>(reason: BlockReason, mut poll: impl FnMut() -> Option<T>) -> T {
+    let result = loop {
+        block(reason);
+        if let Some(result) = poll() {
+            break result;
+        }
+        yield_next();
+    };
+    unblock();
+    result
+}
+
+fn switch_kernel_stack(next: &Process) {
+    percpu::current().set_kernel_stack(next.kernel_stack_top());
+    page::switch_address_space(next.page_table);
+}
+
+fn update_task_switched(next_id: u64) {
+    if percpu::current().fpu_owner.load(Ordering::Relaxed) == next_id {
+        fpu::clear_TS();
+    } else {
+        fpu::set_TS();
+    }
+}
+
+// #NM handler: hands the FPU over to the running task, saving the state of
+// the task that used it last and restoring (or initializing) its own.
+pub fn device_not_available() {
+    fpu::clear_TS();
+
+    let owner = &percpu::current().fpu_owner;
+    let last_id = owner.load(Ordering::Relaxed);
+    let current_id = get_pid();
+    if last_id == current_id {
+        return;
+    }
+    if let Some(last) = get_process_from_id(last_id) {
+        if last.flags & PROCESS_FLAG_ENDTASK == 0 && is_process_exist(last_id) {
+            fpu::save_FPU_context(&mut last.fpu_context);
+        }
+    }
+
+    let current = get_process_from_id(current_id).unwrap();
+    if current.flags & PROCESS_FLAG_FPUUSED == 0 {
+        fpu::init_FPU();
+        current.flags |= PROCESS_FLAG_FPUUSED;
+    } else {
+        fpu::load_FPU_context(&current.fpu_context);
+    }
+    owner.store(current_id, Ordering::Relaxed);
+}
+
+// Saves the FPU state a task left in this core's registers, so that it can
+// be resumed on another core
+fn release_fpu(process: &mut Process) {
+    let owner = &percpu::current().fpu_owner;
+    if owner.load(Ordering::Relaxed) != process.id {
+        return;
+    }
+    fpu::clear_TS();
+    fpu::save_FPU_context(&mut process.fpu_context);
+    fpu::set_TS();
+    owner.store(PROCESS_INVALIDID, Ordering::Relaxed);
+}
+
+fn is_fpu_owner(cpu: usize, pid: u64) -> bool {
+    percpu::get(cpu).fpu_owner.load(Ordering::Relaxed) == pid
+}
+
+pub fn sleep(millisecond: u64) {
+    let deadline = timer::get_tick_count() + millisecond;
+    if let Some(process) = get_process_from_id(get_pid()) {
+        process.wake_tick = deadline;
+    }
+    block_on(BlockReason::Sleep, || {
+        (timer::get_tick_count() >= deadline).then_some(())
+    })
+}
+
+// Charges elapsed timer ticks to the running task. A real-time job that
+// runs out of budget is switched out at once.
+pub fn account_ticks(count: u64) {
+    let mut scheduler = local_scheduler().lock();
+    if let Some(process) = get_process_from_id(scheduler.running()) {
+        process.cpu_ticks += count;
+        if process.realtime.charge(count) {
+            scheduler.expire();
+        }
+    }
+}
+
+// While every task of the core but its idle task is blocked or waiting, the
+// tick the earliest sleeper is due at, or u64::MAX when only an interrupt can
+// wake anything. Sleepers on every core count, since the tick wakes them all.
+// Tasks of the core polling for another task to end are looked at again on
+// the next tick, since an exit on another core raises no interrupt here.
+fn idle_deadline(cpu: usize) -> Option<u64> {
+    let mut deadline = u64::MAX;
+    let next_tick = timer::get_tick_count() + 1;
+    for pid in task_ids() {
+        let process = match get_process_from_id(pid) {
+            Some(process) => process,
+            None => continue,
+        };
+        if process.flags & (PROCESS_FLAG_IDLETASK | PROCESS_FLAG_ENDTASK) != 0 {
+            continue;
+        }
+        match process.state {
+            ProcessState::Blocked(BlockReason::Sleep | BlockReason::Period) => {
+                deadline = deadline.min(process.wake_tick)
             }
-            unsafe { idle::TICK_COUNT += 1 };
-            unsafe { context_switch(&current.context, &next.context) };
+            ProcessState::Blocked(BlockReason::Join | BlockReason::Wait)
+                if process.cpu() == cpu =>
+            {
+                deadline = deadline.min(next_tick)
+            }
+            ProcessState::Blocked(_)
+            | ProcessState::Waiting(_)
+            | ProcessState::Stopped
+            | ProcessState::Zombie => {}
+            ProcessState::Ready | ProcessState::Running if process.cpu() == cpu => return None,
+            ProcessState::Ready | ProcessState::Running => {}
         }
     }
-    interrupt::without_interrupt(|| SCHEDULER.lock().reset_processtime());
+    Some(deadline)
 }
 
 pub fn decrease_time() {
-    interrupt::without_interrupt(|| SCHEDULER.lock().decrease_time())
+    local_scheduler().lock().decrease_time()
 }
 
 pub fn is_expired() -> bool {
-    interrupt::without_interrupt(|| SCHEDULER.lock().is_expired())
+    local_scheduler().lock().is_expired()
 }
 
 pub fn get_pid() -> u64 {
-    interrupt::without_interrupt(|| SCHEDULER.lock().running())
+    percpu::current_task()
+}
+
+// For callers that must not wait, such as NMI handlers; None before the core
+// has a task
+pub fn try_get_pid() -> Option<u64> {
+    match percpu::current_task() {
+        PROCESS_INVALIDID => None,
+        pid => Some(pid),
+    }
+}
+
+// Task a core is running, and the number of tasks in its queues
+pub fn get_cpu_tasks(cpu: usize) -> (u64, u64) {
+    let scheduler = percpu::get(cpu).scheduler().lock();
+    (scheduler.running(), scheduler.total_count())
 }
 
+// Restricts the cores a task may run on to `affinity`. Queued tasks are moved
+// by the next load balancing on their core, and running ones once they are
+// switched out.
+pub fn set_affinity(pid: u64, affinity: u64) -> Result<(), ()> {
+    let target = get_process_from_id(pid).ok_or(())?;
+    if target.is_ended()
+        || target.flags & PROCESS_FLAG_IDLETASK != 0
+        || affinity & smp::online_mask() == 0
+    {
+        return Err(());
+    }
+    target.affinity = affinity;
+    Ok(())
+}
+
+// IDs carry the generation of their entry in the upper 32 bits, so an ID
+// whose task has been freed, or whose entry has been reused, finds nothing
 pub fn get_process_from_id<'a>(pid: u64) -> Option<&'a mut Process> {
-    let pid = pid & 0xFFFFFFFF;
-    if pid > PROCESS_MAXCOUNT as u64 {
-        None
+    let process = table::entry(table::index_of(pid))?;
+    if table::generation_of(pid) != 0 && process.id == pid {
+        Some(process)
     } else {
-        unsafe {
-            let address = &mut *(PROCESS_POOLADDRESS as *mut [Process; PROCESS_MAXCOUNT]);
-            Some(&mut address[pid as usize])
+        None
+    }
+}
+
+// IDs of every task in the table, in table order
+pub fn task_ids() -> impl Iterator<Item = u64> {
+    (0..table::capacity()).filter_map(|index| {
+        let process = table::entry(index)?;
+        (table::generation_of(process.id) != 0).then_some(process.id)
+    })
+}
+
+// Tasks in use and entries the table has grown to
+pub fn task_table_usage() -> (usize, usize) {
+    let table = TASK_TABLE.lock();
+    (table.use_count(), table.capacity())
+}
+
+pub fn end_process(pid: u64, exit_code: u64) {
+    let target = match get_process_from_id(pid) {
+        Some(target) if is_process_exist(pid) => target,
+        _ => return,
+    };
+    if target.flags & PROCESS_FLAG_ENDTASK != 0 {
+        return;
+    }
+    let running = get_pid();
+
+    // Ending a process takes every thread in it down as well. When the caller
+    // is one of those threads it has to go last, since ending it never returns
+    let mut last = pid;
+    if !target.is_thread() {
+        for thread_id in target.threads() {
+            if thread_id == running {
+                last = running;
+            } else {
+                end_task(thread_id, exit_code);
+            }
+        }
+        if last != pid {
+            end_task(pid, exit_code);
         }
     }
+    end_task(last, exit_code);
 }
 
-pub fn end_process(pid: u64) {
-    let target = get_process_from_id(pid);
-    if interrupt::without_interrupt(|| SCHEDULER.lock().running()) == pid {
-        let target = target.unwrap();
-        target.flags |= PROCESS_FLAG_ENDTASK;
-        round_robin::set_priority(&mut target.flags, PRIORITY_WAIT);
+fn end_task(pid: u64, exit_code: u64) {
+    let target = get_process_from_id(pid).unwrap();
+    if target.flags & PROCESS_FLAG_ENDTASK != 0 {
+        return;
+    }
+    if get_pid() == pid {
+        mark_ended(pid, exit_code);
         yield_next();
         loop {}
-    } else if let Some(target) = target {
-        interrupt::without_interrupt(|| SCHEDULER.lock().remove_process(pid));
-        target.flags |= PROCESS_FLAG_ENDTASK;
-        round_robin::set_priority(&mut target.flags, PRIORITY_WAIT);
-        interrupt::without_interrupt(|| SCHEDULER.lock().add_ready_list(pid));
     }
+    let mut scheduler = lock_scheduler_of(target);
+    // A task running on another core goes to the wait list when that core
+    // switches it out
+    if scheduler.running() == pid {
+        mark_ended(pid, exit_code);
+        return;
+    }
+    let _ = scheduler.remove_process(pid);
+    mark_ended(pid, exit_code);
+    let _ = scheduler.add_ready_list(pid);
+}
+
+// Moves the task to the wait priority, so the next time it is queued it goes
+// to the idle task for release
+fn mark_ended(pid: u64, exit_code: u64) {
+    let target = get_process_from_id(pid).unwrap();
+    target.exit_code = exit_code;
+    target.flags |= PROCESS_FLAG_ENDTASK;
+    target.state = ProcessState::Zombie;
+    round_robin::set_priority(&mut target.flags, PRIORITY_WAIT);
+    trace::record(TraceKind::TaskExit, pid, exit_code);
 }
 
 pub fn change_priority(pid: u64, priority: u64) -> Result<(), ()> {
-    interrupt::without_interrupt(|| SCHEDULER.lock().change_priority(pid, priority))
+    let target = get_process_from_id(pid).ok_or(())?;
+    lock_scheduler_of(target).change_priority(pid, priority)
+}
+
+// Ends the running task with an exit code for `join` and `wait`
+pub fn exit(exit_code: u64) {
+    end_process(get_pid(), exit_code);
+}
+
+fn add_child(parent_id: u64, child_id: u64) {
+    if let (Some(parent), Some(child)) = (
+        get_process_from_id(parent_id),
+        get_process_from_id(child_id),
+    ) {
+        child.parent = parent_id;
+        child.sibling = parent.child;
+        parent.child = child_id;
+    }
+}
+
+fn remove_child(parent_id: u64, child_id: u64) {
+    let parent = match get_process_from_id(parent_id) {
+        Some(parent) => parent,
+        None => return,
+    };
+    let child_sibling = match get_process_from_id(child_id) {
+        Some(child) => child.sibling,
+        None => return,
+    };
+    if parent.child == child_id {
+        parent.child = child_sibling;
+    } else {
+        let mut current = parent.child;
+        while let Some(process) = get_process_from_id(current) {
+            if process.sibling == child_id {
+                process.sibling = child_sibling;
+                break;
+            }
+            current = process.sibling;
+        }
+    }
+    if let Some(child) = get_process_from_id(child_id) {
+        child.parent = PROCESS_INVALIDID;
+        child.sibling = PROCESS_INVALIDID;
+    }
+}
+
+fn add_thread(group_id: u64, thread_id: u64) {
+    if let (Some(group), Some(thread)) = (
+        get_process_from_id(group_id),
+        get_process_from_id(thread_id),
+    ) {
+        thread.group = group_id;
+        thread.thread_sibling = group.thread;
+        group.thread = thread_id;
+    }
+}
+
+fn remove_thread(group_id: u64, thread_id: u64) {
+    let group = match get_process_from_id(group_id) {
+        Some(group) if group_id != thread_id => group,
+        _ => return,
+    };
+    let thread_sibling = match get_process_from_id(thread_id) {
+        Some(thread) => thread.thread_sibling,
+        None => return,
+    };
+    if group.thread == thread_id {
+        group.thread = thread_sibling;
+    } else {
+        let mut current = group.thread;
+        while let Some(process) = get_process_from_id(current) {
+            if process.thread_sibling == thread_id {
+                process.thread_sibling = thread_sibling;
+                break;
+            }
+            current = process.thread_sibling;
+        }
+    }
+    if let Some(thread) = get_process_from_id(thread_id) {
+        thread.thread_sibling = PROCESS_INVALIDID;
+    }
+}
+
+// Called by the idle task for every ended task taken from the wait list.
+// Children are handed over to the idle task, and the task itself either
+// stays as a zombie until its parent collects it or is freed right away.
+// A process is kept on the wait list until all of its threads are gone.
+pub(super) fn release_process(pid: u64) {
+    let reaper_id = get_pid();
+    let target = match get_process_from_id(pid) {
+        Some(target) => target,
+        None => return,
+    };
+
+    if !target.is_thread() && target.thread != PROCESS_INVALIDID {
+        let _ = lock_scheduler_of(target).add_ready_list(pid);
+        return;
+    }
+    if target.is_thread() {
+        if target.is_user() {
+            unmap_user_stack(target.page_table, pid);
+        }
+        interrupt::without_interrupt(|| remove_thread(target.group, pid));
+    } else {
+        page::destroy_address_space(target.page_table);
+        target.page_table = page::KERNEL_PAGETABLE;
+        for slot in target.handles.iter_mut().filter(|slot| **slot != 0) {
+            let _ = channel::release(core::mem::replace(slot, 0));
+        }
+    }
+
+    interrupt::without_interrupt(|| {
+        while target.child != PROCESS_INVALIDID {
+            let child_id = target.child;
+            remove_child(pid, child_id);
+            add_child(reaper_id, child_id);
+            if let Some(child) = get_process_from_id(child_id) {
+                if child.is_zombie() {
+                    let _ = lock_scheduler_of(child).add_ready_list(child_id);
+                }
+            }
+        }
+    });
+
+    let parent_id = target.parent;
+    if parent_id != PROCESS_INVALIDID && parent_id != reaper_id && is_process_exist(parent_id) {
+        target.flags |= PROCESS_FLAG_ZOMBIE;
+    } else {
+        interrupt::without_interrupt(|| remove_child(parent_id, pid));
+        TASK_TABLE.lock().dealloc(pid);
+    }
 }
 
-pub fn exit() {
-    end_process(interrupt::without_interrupt(|| SCHEDULER.lock().running()));
+fn reap(parent_id: u64, child_id: u64) -> u64 {
+    let exit_code = get_process_from_id(child_id).unwrap().exit_code;
+    interrupt::without_interrupt(|| remove_child(parent_id, child_id));
+    TASK_TABLE.lock().dealloc(child_id);
+    exit_code
+}
+
+pub fn join(pid: u64) -> Result<u64, ()> {
+    let parent_id = get_pid();
+    block_on(BlockReason::Join, || match get_process_from_id(pid) {
+        Some(child) if is_process_exist(pid) && child.parent == parent_id => {
+            if child.is_zombie() {
+                Some(Ok(reap(parent_id, pid)))
+            } else {
+                None
+            }
+        }
+        _ => Some(Err(())),
+    })
+}
+
+pub fn wait() -> Result<(u64, u64), ()> {
+    let parent_id = get_pid();
+    block_on(BlockReason::Wait, || {
+        let parent = get_process_from_id(parent_id).unwrap();
+        if parent.child == PROCESS_INVALIDID {
+            return Some(Err(()));
+        }
+        let zombie = parent
+            .children()
+            .find(|&child_id| get_process_from_id(child_id).unwrap().is_zombie());
+        zombie.map(|child_id| Ok((child_id, reap(parent_id, child_id))))
+    })
 }
 
 pub fn process_count() -> u64 {
-    interrupt::without_interrupt(|| SCHEDULER.lock().total_count())
+    (0..smp::cpu_count()).map(|cpu| get_cpu_tasks(cpu).1).sum()
 }
 
-pub fn is_process_exist(pid: u64) -> bool {
-    match get_process_from_id(pid) {
-        Some(process) => process.id != pid,
-        None => false,
+pub fn alloc_handle(value: u64) -> Result<u64, ()> {
+    alloc_handle_for(get_pid(), value)
+}
+// Handles belong to the process a task is part of
+pub fn alloc_handle_for(pid: u64, value: u64) -> Result<u64, ()> {
+    let group = get_process_from_id(pid).ok_or(())?.group;
+    let process = get_process_from_id(group).ok_or(())?;
+    interrupt::without_interrupt(|| {
+        let (handle, slot) = process
+            .handles
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| **slot == 0)
+            .ok_or(())?;
+        *slot = value;
+        Ok(handle as u64)
+    })
+}
+
+pub fn get_handle(handle: u64) -> Option<u64> {
+    let group = get_process_from_id(get_pid())?.group;
+    match get_process_from_id(group)?.handles.get(handle as usize) {
+        Some(&value) if value != 0 => Some(value),
+        _ => None,
+    }
+}
+
+pub fn free_handle(handle: u64) -> Result<u64, ()> {
+    let group = get_process_from_id(get_pid()).unwrap().group;
+    let process = get_process_from_id(group).ok_or(())?;
+    match process.handles.get_mut(handle as usize) {
+        Some(slot) if *slot != 0 => Ok(core::mem::replace(slot, 0)),
+        _ => Err(()),
     }
 }
+
+// Looks a task up by name; processes are preferred over threads of the same
+// name
+pub fn find_process(name: &str) -> Option<u64> {
+    let mut found = None;
+    for pid in task_ids() {
+        match get_process_from_id(pid) {
+            Some(process) if process.name() == name => {
+                if !process.is_thread() {
+                    return Some(pid);
+                }
+                found = found.or(Some(pid));
+            }
+            _ => {}
+        }
+    }
+    found
+}
+
+pub fn is_process_exist(pid: u64) -> bool {
+    get_process_from_id(pid).is_some()
+}
@@ -0,0 +1,183 @@
+use crate::{interrupt, spinlock::SpinLock, timer};
+
+use super::{
+    block_on, get_pid, get_process_from_id, lock_scheduler_of, task_ids, BlockReason, Process,
+    ProcessState, Scheduler, PROCESS_FLAG_IDLETASK, PROCESS_INVALIDID,
+};
+
+// Utilization is kept in parts per million. Admission leaves a share of the
+// CPU to the priority queues, which the idle task and the shell live in.
+const REALTIME_UTILIZATIONSCALE: u64 = 1000000;
+pub const REALTIME_MAXUTILIZATION: u64 = 900000;
+
+static REALTIME_ADMISSION: SpinLock<()> = SpinLock::new(());
+
+// Parameters of a periodic real-time task, in timer ticks. A task whose
+// period is 0 belongs to the priority queues.
+#[derive(Clone, Copy)]
+pub struct RealTime {
+    pub period: u64,
+    pub runtime: u64,
+    pub deadline: u64,
+    // Release tick and absolute deadline of the current job
+    pub release: u64,
+    pub absolute_deadline: u64,
+    // Ticks the current job may still run before it is throttled
+    pub budget: u64,
+    pub job_count: u64,
+    pub miss_count: u64,
+}
+
+impl RealTime {
+    pub const fn none() -> Self {
+        Self {
+            period: 0,
+            runtime: 0,
+            deadline: 0,
+            release: 0,
+            absolute_deadline: 0,
+            budget: 0,
+            job_count: 0,
+            miss_count: 0,
+        }
+    }
+
+    pub fn is_admitted(&self) -> bool {
+        self.period != 0
+    }
+
+    // Jobs that used up their budget run from the priority queues until the
+    // next period
+    pub fn is_scheduled_by_deadline(&self) -> bool {
+        self.is_admitted() && self.budget != 0
+    }
+
+    // Charges ticks to the current job; true when its budget just ran out
+    pub(super) fn charge(&mut self, count: u64) -> bool {
+        if !self.is_scheduled_by_deadline() {
+            return false;
+        }
+        self.budget = self.budget.saturating_sub(count);
+        self.budget == 0
+    }
+
+    // Density of the task, rounded up so that admission stays on the safe side
+    fn utilization(&self) -> u64 {
+        let window = self.deadline.min(self.period);
+        (self.runtime * REALTIME_UTILIZATIONSCALE + window - 1) / window
+    }
+}
+
+// A task waiting for its next period is left in the queue but cannot be
+// picked before the period starts
+pub(super) fn is_released(process: &Process, now: u64) -> bool {
+    process.state != ProcessState::Blocked(BlockReason::Period) || now >= process.realtime.release
+}
+
+// Tasks blocked on anything else are only picked to poll, so they do not
+// preempt the running task
+pub(super) fn is_runnable(process: &Process, now: u64) -> bool {
+    match process.state {
+        ProcessState::Ready => true,
+        ProcessState::Blocked(BlockReason::Period) => now >= process.realtime.release,
+        _ => false,
+    }
+}
+
+// Sum of the densities of every admitted task, in parts per million
+pub fn realtime_utilization() -> u64 {
+    utilization_except(PROCESS_INVALIDID)
+}
+
+fn utilization_except(except: u64) -> u64 {
+    task_ids()
+        .filter(|pid| *pid != except)
+        .filter_map(|pid| get_process_from_id(pid))
+        .filter(|process| process.realtime.is_admitted() && !process.is_ended())
+        .map(|process| process.realtime.utilization())
+        .sum()
+}
+
+// Moves a task into the deadline class. The first job is released right
+// away. `deadline` is relative to each release, 0 meaning the period, and
+// the task is refused when the densities of all real-time tasks would add
+// up to more than REALTIME_MAXUTILIZATION.
+pub fn set_realtime(pid: u64, period: u64, runtime: u64, deadline: u64) -> Result<(), ()> {
+    let deadline = if deadline == 0 { period } else { deadline };
+    if runtime == 0 || runtime > deadline || deadline > period {
+        return Err(());
+    }
+    let target = get_process_from_id(pid).ok_or(())?;
+    if target.is_ended() || target.flags & PROCESS_FLAG_IDLETASK != 0 {
+        return Err(());
+    }
+    let now = timer::get_tick_count();
+    let realtime = RealTime {
+        period,
+        runtime,
+        deadline,
+        release: now,
+        absolute_deadline: now + deadline,
+        budget: runtime,
+        job_count: 0,
+        miss_count: 0,
+    };
+    // Admissions are serialized so that two of them cannot both fit
+    let _admission = REALTIME_ADMISSION.lock();
+    if utilization_except(pid) + realtime.utilization() > REALTIME_MAXUTILIZATION {
+        return Err(());
+    }
+    let mut scheduler = lock_scheduler_of(target);
+    let queued = scheduler.remove_process(pid).is_ok();
+    target.realtime = realtime;
+    if queued {
+        scheduler.add_ready_list(pid)?;
+    }
+    Ok(())
+}
+
+// Puts a task back into its priority queue
+pub fn clear_realtime(pid: u64) -> Result<(), ()> {
+    let target = get_process_from_id(pid).ok_or(())?;
+    let mut scheduler = lock_scheduler_of(target);
+    let queued = scheduler.remove_process(pid).is_ok();
+    target.realtime = RealTime::none();
+    if queued {
+        scheduler.add_ready_list(pid)?;
+    }
+    Ok(())
+}
+
+// Ends the running task's current job and blocks until the next one is
+// released. A job that finishes after its deadline counts as a miss, and
+// jobs whose deadline went by before they could start are skipped and
+// counted as missed too.
+pub fn wait_next_period() -> Result<(), ()> {
+    let current = get_process_from_id(get_pid()).ok_or(())?;
+    if !current.realtime.is_admitted() {
+        return Err(());
+    }
+    let now = timer::get_tick_count();
+    let release = interrupt::without_interrupt(|| {
+        let realtime = &mut current.realtime;
+        realtime.job_count += 1;
+        if now > realtime.absolute_deadline {
+            realtime.miss_count += 1;
+        }
+        let mut release = realtime.release + realtime.period;
+        if release + realtime.deadline < now {
+            let skipped = (now - release - realtime.deadline + realtime.period - 1) / realtime.period;
+            release += skipped * realtime.period;
+            realtime.miss_count += skipped;
+        }
+        realtime.release = release;
+        realtime.absolute_deadline = release + realtime.deadline;
+        realtime.budget = realtime.runtime;
+        current.wake_tick = release;
+        release
+    });
+    block_on(BlockReason::Period, || {
+        (timer::get_tick_count() >= release).then_some(())
+    });
+    Ok(())
+}
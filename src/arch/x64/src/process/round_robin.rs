@@ -1,6 +1,8 @@
-use crate::{print, println};
+use crate::{print, println, timer};
 
-use super::{get_process_from_id, Scheduler, PROCESS_MAXCOUNT};
+use super::{
+    get_process_from_id, realtime, Process, ProcessState, Scheduler, PROCESS_INVALIDID, TASK_TABLE,
+};
 
 const PROCESS_TIME: i64 = 5;
 const PROCESS_READYLISTCOUNT: usize = 5;
@@ -10,7 +12,19 @@ pub const PRIORITY_MIDDLE: u64 = 2;
 pub const PRIORITY_LOWIST: u64 = 4;
 pub const PRIORITY_WAIT: u64 = 0xFF;
 
-static mut RUNQUEUE_POOL: [Option<u64>; PROCESS_MAXCOUNT] = [None; PROCESS_MAXCOUNT];
+// Queues are linked through the tasks themselves
+fn next_of(pid: u64) -> Option<u64> {
+    match get_process_from_id(pid)?.run_next {
+        PROCESS_INVALIDID => None,
+        next => Some(next),
+    }
+}
+
+fn set_next_of(pid: u64, next: Option<u64>) {
+    if let Some(process) = get_process_from_id(pid) {
+        process.run_next = next.unwrap_or(PROCESS_INVALIDID);
+    }
+}
 
 pub fn get_priority(flag: u64) -> u64 {
     flag & 0xFF
@@ -27,10 +41,16 @@ pub(crate) struct RunQueue {
     count: u64,
 }
 
+// Real-time tasks with budget left are kept in a queue of their own, which is
+// served earliest deadline first ahead of the priority queues
 pub struct RRScheduler {
     running: u64,
+    // Task switched out last. Until the core switches again its context may
+    // not be saved yet, so it is not handed to another core.
+    previous: u64,
     processor_time: i64,
     pub(crate) wait: RunQueue,
+    realtime: RunQueue,
     ready: [RunQueue; PROCESS_READYLISTCOUNT],
     execute_count: [u64; PROCESS_READYLISTCOUNT],
 }
@@ -39,12 +59,32 @@ impl RRScheduler {
     pub const fn new(run_id: u64) -> Self {
         Self {
             running: run_id,
+            previous: PROCESS_INVALIDID,
             processor_time: PROCESS_TIME,
             wait: RunQueue::new(),
+            realtime: RunQueue::new(),
             ready: [RunQueue::new(); PROCESS_READYLISTCOUNT],
             execute_count: [0; PROCESS_READYLISTCOUNT],
         }
     }
+
+    // Queued real-time task with the earliest absolute deadline. Only tasks
+    // that would run instead of polling count when `runnable` is set.
+    fn earliest_deadline(&self, runnable: bool) -> Option<(u64, u64)> {
+        let now = timer::get_tick_count();
+        self.realtime
+            .iter()
+            .filter_map(|pid| Some((pid, get_process_from_id(pid)?)))
+            .filter(|(_, process)| {
+                if runnable {
+                    realtime::is_runnable(process, now)
+                } else {
+                    realtime::is_released(process, now)
+                }
+            })
+            .map(|(pid, process)| (pid, process.realtime.absolute_deadline))
+            .min_by_key(|(_, deadline)| *deadline)
+    }
 }
 
 impl Scheduler for RRScheduler {
@@ -53,6 +93,9 @@ impl Scheduler for RRScheduler {
     }
 
     fn next(&mut self) -> Option<u64> {
+        if let Some((pid, _)) = self.earliest_deadline(false) {
+            return self.realtime.remove(pid).ok();
+        }
         for _ in 0..2 {
             for idx in 0..PROCESS_READYLISTCOUNT {
                 let len = self.ready[idx].count();
@@ -68,6 +111,7 @@ impl Scheduler for RRScheduler {
     }
 
     fn set_running(&mut self, run_id: u64) {
+        self.previous = self.running;
         self.running = run_id;
     }
 
@@ -75,9 +119,10 @@ impl Scheduler for RRScheduler {
         if let Some(ready) = get_process_from_id(ready_id) {
             let priority = get_priority(ready.flags);
             if priority == PRIORITY_WAIT {
-                // println!("{:?}", unsafe { RUNQUEUE_POOL[ready_id as usize] });
                 // self.ready[4].print();
                 self.wait.push_back(ready_id);
+            } else if ready.realtime.is_scheduled_by_deadline() {
+                self.realtime.push_back(ready_id);
             } else if priority < PROCESS_READYLISTCOUNT as u64 {
                 self.ready[priority as usize].push_back(ready_id);
             } else {
@@ -87,27 +132,48 @@ impl Scheduler for RRScheduler {
         Ok(())
     }
 
+    fn take(&mut self, movable: &dyn Fn(&Process) -> bool) -> Option<u64> {
+        let previous = self.previous;
+        let is_movable = |pid: &u64| {
+            *pid != previous && get_process_from_id(*pid).map_or(false, |process| movable(process))
+        };
+        if let Some(pid) = self.realtime.iter().find(is_movable) {
+            return self.realtime.remove(pid).ok();
+        }
+        for queue in self.ready.iter_mut() {
+            if let Some(pid) = queue.iter().find(is_movable) {
+                return queue.remove(pid).ok();
+            }
+        }
+        None
+    }
+
     fn total_count(&self) -> u64 {
-        self.ready.iter().fold(0, |acc, x| acc + x.count())
+        self.ready.iter().fold(self.realtime.count(), |acc, x| acc + x.count())
     }
 
     fn remove_process(&mut self, pid: u64) -> Result<u64, ()> {
+        if self.realtime.remove(pid).is_ok() {
+            return Ok(pid);
+        }
         if let Some(process) = super::get_process_from_id(pid) {
-            if pid != process.id {
-                let priority = get_priority(process.flags);
-                self.ready[priority as usize].remove(pid)
-            } else {
-                Err(())
+            let priority = get_priority(process.flags);
+            match self.ready.get_mut(priority as usize) {
+                Some(queue) => queue.remove(pid),
+                None => Err(()),
             }
         } else {
             Err(())
         }
     }
 
+    // The task is taken out of the queue of its old priority before the new
+    // one is set, and queued again only when it was queued before
     fn change_priority(&mut self, pid: u64, priority: u64) -> Result<(), ()> {
         if let Some(process) = get_process_from_id(pid) {
+            let queued = self.remove_process(pid).is_ok();
             set_priority(&mut process.flags, priority);
-            if let Ok(_) = self.remove_process(pid) {
+            if queued {
                 self.add_ready_list(pid)?;
             }
             Ok(())
@@ -125,8 +191,25 @@ impl Scheduler for RRScheduler {
         }
     }
 
+    fn expire(&mut self) {
+        self.processor_time = 0;
+    }
+
+    // A real-time task keeps the CPU until a job with an earlier deadline is
+    // released, and any released job preempts the priority queues
     fn is_expired(&self) -> bool {
-        self.processor_time <= 0
+        let running = get_process_from_id(self.running);
+        let deadline = match running {
+            Some(process) if process.realtime.is_scheduled_by_deadline() => {
+                process.realtime.absolute_deadline
+            }
+            _ if self.processor_time <= 0 => return true,
+            _ => u64::MAX,
+        };
+        match self.earliest_deadline(true) {
+            Some((_, earliest)) => earliest < deadline,
+            None => false,
+        }
     }
 }
 
@@ -142,7 +225,7 @@ impl RunQueue {
     pub fn push_back(&mut self, node: u64) {
         if let Some(process) = self.tail {
             self.tail = Some(node);
-            unsafe { RUNQUEUE_POOL[process as usize] = self.tail };
+            set_next_of(process, self.tail);
         } else {
             self.head = Some(node);
             self.tail = Some(node);
@@ -156,9 +239,9 @@ impl RunQueue {
                 self.head = None;
                 self.tail = None;
             } else {
-                unsafe { self.head = RUNQUEUE_POOL[process as usize] };
+                self.head = next_of(process);
             }
-            unsafe { RUNQUEUE_POOL[process as usize] = None };
+            set_next_of(process, None);
             self.count -= 1;
             Some(process)
         } else {
@@ -170,6 +253,13 @@ impl RunQueue {
         self.count
     }
 
+    pub fn iter(&self) -> RunQueueIter {
+        RunQueueIter {
+            next: self.head,
+            remaining: self.count,
+        }
+    }
+
     pub fn remove(&mut self, pid: u64) -> Result<u64, ()> {
         let node = match self.head {
             Some(head) => {
@@ -177,16 +267,18 @@ impl RunQueue {
                     self.pop_front();
                     return Ok(pid);
                 } else {
+                    // Walks to the node before `pid`, failing at the tail
                     let mut current = head;
-                    while let Some(next) = unsafe { RUNQUEUE_POOL[current as usize] } {
-                        if next == pid {
-                            break;
-                        } else if next == self.tail.unwrap() {
+                    loop {
+                        if Some(current) == self.tail {
                             return Err(());
                         }
-                        current = next;
+                        match next_of(current) {
+                            Some(next) if next == pid => break current,
+                            Some(next) => current = next,
+                            None => return Err(()),
+                        }
                     }
-                    current
                 }
             }
             None => return Err(()),
@@ -195,12 +287,73 @@ impl RunQueue {
         if self.tail.unwrap() == pid {
             self.tail = Some(node);
         }
-        unsafe {
-            let next = RUNQUEUE_POOL[node as usize].unwrap();
-            RUNQUEUE_POOL[node as usize] = RUNQUEUE_POOL[next as usize];
-            RUNQUEUE_POOL[next as usize] = None;
-        };
+        let next = next_of(node).unwrap();
+        set_next_of(node, next_of(next));
+        set_next_of(next, None);
+        self.count -= 1;
 
         Ok(pid)
     }
 }
+
+pub(crate) struct RunQueueIter {
+    next: Option<u64>,
+    remaining: u64,
+}
+
+impl Iterator for RunQueueIter {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let pid = self.next?;
+        self.next = next_of(pid);
+        self.remaining -= 1;
+        Some(pid)
+    }
+}
+
+// Checks removal of a task that is not queued against queues of one and two
+// spare task entries. The entries are kept stopped so nothing else picks
+// them up while the test runs.
+pub fn test_run_queue() -> Result<(), ()> {
+    let mut pids = [PROCESS_INVALIDID; 3];
+    for pid in pids.iter_mut() {
+        match TASK_TABLE.lock().alloc() {
+            Some(process) => {
+                let process = unsafe { &mut *process };
+                process.state = ProcessState::Stopped;
+                process.set_name("testqueue");
+                *pid = process.id;
+            }
+            None => break,
+        }
+    }
+    let result = if pids.contains(&PROCESS_INVALIDID) {
+        Err(())
+    } else {
+        check_remove(pids[0], pids[1], pids[2])
+    };
+    for pid in pids.iter().filter(|pid| **pid != PROCESS_INVALIDID) {
+        TASK_TABLE.lock().dealloc(*pid);
+    }
+    result
+}
+
+fn check_remove(first: u64, second: u64, missing: u64) -> Result<(), ()> {
+    let mut queue = RunQueue::new();
+    queue.push_back(first);
+    let one = queue.remove(second).is_err() && queue.count() == 1;
+    let one = one && queue.pop_front() == Some(first) && queue.pop_front().is_none();
+
+    queue.push_back(first);
+    queue.push_back(second);
+    let two = queue.remove(missing).is_err() && queue.count() == 2;
+    let two = two && queue.remove(second) == Ok(second) && queue.count() == 1;
+    let two = two && queue.pop_front() == Some(first) && queue.pop_front().is_none();
+    while queue.pop_front().is_some() {}
+
+    (one && two).then_some(()).ok_or(())
+}
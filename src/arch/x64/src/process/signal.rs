@@ -0,0 +1,277 @@
+use core::{mem::size_of, ptr};
+
+use crate::interrupt;
+
+use super::{
+    end_process, get_pid, get_process_from_id, table, yield_next, Context, Process, ProcessState,
+    lock_scheduler_of, Scheduler, PROCESS_FLAG_IDLETASK,
+};
+
+pub const SIGNAL_MAXCOUNT: usize = 32;
+
+pub const SIGNAL_INTERRUPT: u64 = 2;
+pub const SIGNAL_KILL: u64 = 9;
+pub const SIGNAL_USER1: u64 = 10;
+pub const SIGNAL_USER2: u64 = 12;
+pub const SIGNAL_TERMINATE: u64 = 15;
+pub const SIGNAL_CHILD: u64 = 17;
+pub const SIGNAL_CONTINUE: u64 = 18;
+pub const SIGNAL_STOP: u64 = 19;
+
+// Handler values other than a function address
+pub const SIGNAL_DEFAULT: u64 = 0;
+pub const SIGNAL_IGNORE: u64 = 1;
+
+// Tasks ended by a signal exit with this code ORed with the signal number
+pub const PROCESS_EXITCODE_SIGNAL: u64 = 0xFFFFFFFFFFFFFF00;
+
+pub static SIGNAL_NAMES: &[(&str, u64)] = &[
+    ("int", SIGNAL_INTERRUPT),
+    ("kill", SIGNAL_KILL),
+    ("usr1", SIGNAL_USER1),
+    ("usr2", SIGNAL_USER2),
+    ("term", SIGNAL_TERMINATE),
+    ("chld", SIGNAL_CHILD),
+    ("cont", SIGNAL_CONTINUE),
+    ("stop", SIGNAL_STOP),
+];
+
+enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+fn default_action(signal: u64) -> DefaultAction {
+    match signal {
+        SIGNAL_CHILD => DefaultAction::Ignore,
+        SIGNAL_STOP => DefaultAction::Stop,
+        SIGNAL_CONTINUE => DefaultAction::Continue,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+// KILL and STOP always take their default action
+fn is_catchable(signal: u64) -> bool {
+    signal != SIGNAL_KILL && signal != SIGNAL_STOP
+}
+
+fn handler_of(target: &Process, signal: u64) -> u64 {
+    match get_process_from_id(target.group) {
+        Some(group) => group.signal_handlers[signal as usize],
+        None => SIGNAL_DEFAULT,
+    }
+}
+
+// Registers `handler` for a signal on behalf of the running task's process,
+// returning the one it replaces. A handler is an `fn(u64)` taking the signal
+// number, or SIGNAL_DEFAULT or SIGNAL_IGNORE.
+pub fn set_signal_handler(signal: u64, handler: u64) -> Result<u64, ()> {
+    if signal == 0 || signal >= SIGNAL_MAXCOUNT as u64 || !is_catchable(signal) {
+        return Err(());
+    }
+    let group = get_process_from_id(get_pid()).ok_or(())?.group;
+    let process = get_process_from_id(group).ok_or(())?;
+    Ok(core::mem::replace(&mut process.signal_handlers[signal as usize], handler))
+}
+
+// Whether a ring-3 task may signal `pid`: only user tasks of its own process,
+// or of a process it started directly or through others. Kernel tasks like
+// the shell and the keyboard task are out of its reach.
+pub fn can_signal(sender: u64, pid: u64) -> bool {
+    let (sender, target) = match (get_process_from_id(sender), get_process_from_id(pid)) {
+        (Some(sender), Some(target)) => (sender, target),
+        _ => return false,
+    };
+    if !target.is_user() {
+        return false;
+    }
+    // Bounded, so that a parent ID reused by a child cannot loop forever
+    let mut group = target.group;
+    for _ in 0..table::capacity() {
+        if group == sender.group {
+            return true;
+        }
+        let parent = get_process_from_id(group).map(|process| process.parent);
+        match parent.and_then(get_process_from_id) {
+            Some(parent) => group = parent.group,
+            None => return false,
+        }
+    }
+    false
+}
+
+// Default actions are taken right away, so that a stopped task can still be
+// continued; signals with a handler stay pending until the target task is
+// next switched in
+pub fn send_signal(pid: u64, signal: u64) -> Result<(), ()> {
+    if signal == 0 || signal >= SIGNAL_MAXCOUNT as u64 {
+        return Err(());
+    }
+    let target = get_process_from_id(pid).ok_or(())?;
+    if target.is_ended() || target.flags & PROCESS_FLAG_IDLETASK != 0 {
+        return Err(());
+    }
+    if signal == SIGNAL_CONTINUE {
+        resume(pid);
+    }
+    let handler = if is_catchable(signal) {
+        handler_of(target, signal)
+    } else {
+        SIGNAL_DEFAULT
+    };
+    match handler {
+        SIGNAL_IGNORE => {}
+        SIGNAL_DEFAULT => match default_action(signal) {
+            DefaultAction::Terminate => end_process(pid, PROCESS_EXITCODE_SIGNAL | signal),
+            DefaultAction::Stop => stop(pid),
+            DefaultAction::Ignore | DefaultAction::Continue => {}
+        },
+        _ => interrupt::without_interrupt(|| target.signal_pending |= 1 << signal),
+    }
+    Ok(())
+}
+
+// Takes the task off the run queues until it is continued. The scheduler
+// leaves a stopped task out when it switches away from it, on whichever core
+// it is running.
+fn stop(pid: u64) {
+    let target = get_process_from_id(pid).unwrap();
+    let running = {
+        let mut scheduler = lock_scheduler_of(target);
+        if scheduler.running() != pid {
+            let _ = scheduler.remove_process(pid);
+        }
+        target.state = ProcessState::Stopped;
+        scheduler.running() == pid
+    };
+    if running && get_pid() == pid {
+        yield_next();
+    }
+}
+
+fn resume(pid: u64) {
+    let target = get_process_from_id(pid).unwrap();
+    let mut scheduler = lock_scheduler_of(target);
+    // A task stopped on another core may not have been switched out yet
+    if target.state == ProcessState::Stopped && scheduler.running() == pid {
+        target.state = ProcessState::Running;
+    } else if target.state == ProcessState::Stopped {
+        target.state = ProcessState::Ready;
+        let _ = scheduler.add_ready_list(pid);
+    }
+}
+
+// Called while switching to `next`. With a handler pending, the task is made
+// to enter signal_trampoline in place of where it left off, with that
+// address pushed as the return address. Only tasks stopped in ring 0 can be
+// diverted, since the handlers are kernel functions.
+pub(super) fn prepare_delivery(next: &mut Process) {
+    if next.signal_pending == 0
+        || next.signal_delivering
+        || next.saved_context().registers[Process::CS] & 0x3 != 0
+    {
+        return;
+    }
+    // The saved context lies on the same stack right below where the task
+    // left off, so it is moved down to make room for the return address
+    let context = next.saved_context();
+    let return_address = context.registers[Process::RSP] - size_of::<u64>() as u64;
+    let rip = context.registers[Process::RIP];
+    let stack_pointer = (return_address - size_of::<Context>() as u64) & !0xF;
+    unsafe {
+        ptr::copy(next.stack_pointer as *const Context, stack_pointer as *mut Context, 1);
+        *(return_address as *mut u64) = rip;
+    }
+    next.stack_pointer = stack_pointer;
+    let context = next.saved_context();
+    context.registers[Process::RSP] = return_address;
+    context.registers[Process::RIP] = signal_trampoline as u64;
+    next.signal_delivering = true;
+}
+
+// Runs in the diverted task: every register and the flags are kept, the stack
+// is aligned for the call, and `ret` resumes the interrupted code
+#[naked]
+extern "C" fn signal_trampoline() {
+    use core::arch::asm;
+    unsafe {
+        asm!(
+        "pushfq",
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rbp, rsp",
+        "and rsp, -16",
+        "call {func}",
+        "mov rsp, rbp",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "popfq",
+        "ret",
+        func = sym run_signal_handlers,
+        options(noreturn));
+    }
+}
+
+extern "C" fn run_signal_handlers() {
+    let current = match get_process_from_id(get_pid()) {
+        Some(current) => current,
+        None => return,
+    };
+    loop {
+        let signal = interrupt::without_interrupt(|| {
+            let pending = current.signal_pending;
+            if pending == 0 {
+                current.signal_delivering = false;
+                return None;
+            }
+            let signal = pending.trailing_zeros() as u64;
+            current.signal_pending &= !(1 << signal);
+            Some(signal)
+        });
+        let signal = match signal {
+            Some(signal) => signal,
+            None => break,
+        };
+        // The handler may have been reset since the signal was sent
+        match handler_of(current, signal) {
+            SIGNAL_IGNORE => {}
+            SIGNAL_DEFAULT => {
+                if let DefaultAction::Terminate = default_action(signal) {
+                    end_process(current.id, PROCESS_EXITCODE_SIGNAL | signal);
+                }
+            }
+            handler => {
+                let handler: fn(u64) = unsafe { core::mem::transmute(handler as usize) };
+                handler(signal);
+            }
+        }
+    }
+}
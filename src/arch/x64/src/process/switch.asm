@@ -36,8 +36,8 @@ SECTION .text
 ; 콘텍스트를 복원하는 매크로
 %macro KLOADCONTEXT 0   ; 파라미터를 전달받지 않는 KSAVECONTEXT 매크로 정의
     ; GS 세그먼트 셀렉터부터 RBP 레지스터까지 모두 스택에서 꺼내 복원
+    ; GS는 다시 로드하면 코어별 데이터 영역을 가리키는 베이스가 초기화되므로 건너뜀
     pop rax
-    mov gs, ax
     pop rax
     mov fs, ax
     pop rax
@@ -62,61 +62,44 @@ SECTION .text
     pop rbp        
 %endmacro       ; 매크로 끝
 
-; Current Context에 현재 콘텍스트를 저장하고 Next Task에서 콘텍스트를 복구
-;   PARAM: Current Context, Next Context
+; 실행 중인 태스크의 콘텍스트를 자신의 스택에 Context 자료구조 형태로 저장하고
+; 다음 태스크의 스택으로 전환하여 콘텍스트를 복원
+;   PARAM: 현재 스택 포인터를 저장할 주소(NULL이면 저장하지 않음), Next Stack Pointer
 context_switch:
-    push rbp        ; 스택에 RBP 레지스터를 저장하고 RSP 레지스터를 RBP에 저장
-    mov rbp, rsp
-    
-    ; Current Context가 NULL이면 콘텍스트를 저장할 필요 없음
-    pushfq          ; 아래의 cmp의 결과로 RFLAGS 레지스터가 변하지 않도록 스택에 저장
-    cmp rdi, 0      ; Current Context가 NULL이면 콘텍스트 복원으로 바로 이동
-    je .LoadContext
-    popfq           ; 스택에 저장한 RFLAGS 레지스터를 복원
-    
-    ;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;
-    ; 현재 태스크의 콘텍스트를 저장
-    ;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;
-    push rax            ; 콘텍스트 영역의 오프셋으로 사용할 RAX 레지스터를 스택에 저장
-    
-    ; SS, RSP, RFLAGS, CS, RIP 레지스터 순서대로 삽입
-    mov ax, ss                          ; SS 레지스터 저장
-    mov qword[ rdi + ( 23 * 8 ) ], rax
+    ; 호출한 위치로 돌아가는 인터럽트 스택 프레임을 스택에 생성
+    ; RAX, RCX, R11은 호출자가 저장하는 레지스터이므로 그대로 사용
+    pop r11             ; Return Address를 꺼내면 RSP는 호출 이전의 값이 됨
+    mov rax, rsp
 
-    mov rax, rbp                        ; RBP에 저장된 RSP 레지스터 저장
-    add rax, 16                         ; RSP 레지스터는 push rbp와 Return Address를
-    mov qword[ rdi + ( 22 * 8 ) ], rax  ; 제외한 값으로 저장
-    
-    pushfq                              ; RFLAGS 레지스터 저장
-    pop rax
-    mov qword[ rdi + ( 21 * 8 ) ], rax
+    xor ecx, ecx
+    mov cx, ss
+    push rcx            ; SS
+    push rax            ; RSP
+    pushfq              ; RFLAGS
+    mov cx, cs
+    push rcx            ; CS
+    push r11            ; RIP
 
-    mov ax, cs                          ; CS 레지스터 저장
-    mov qword[ rdi + ( 20 * 8 ) ], rax
-
-    mov rax, qword[ rbp + 8 ]           ; RIP 레지스터를 Return Address로 설정하여 
-    mov qword[ rdi + ( 19 * 8 ) ], rax  ; 다음 콘텍스트 복원 시에 이 함수를 호출한 
-                                        ; 위치로 이동하게 함
-    
-    ; 저장한 레지스터를 복구한 후 인터럽트가 발생했을 때처럼 나머지 콘텍스트를 모두 저장
-    pop rax
-    pop rbp
-    
-    ; 가장 끝부분에 SS, RSP, RFLAGS, CS, RIP 레지스터를 저장했으므로, 이전 영역에
-    ; push 명령어로 콘텍스트를 저장하기 위해 스택을 변경
-    add rdi, ( 19 * 8 )
-    mov rsp, rdi
-    sub rdi, ( 19 * 8 )
-    
-    ; 나머지 레지스터를 모두 Context 자료구조에 저장
+    ; 나머지 레지스터를 모두 스택에 저장
     KSAVECONTEXT
 
+    ; Current가 NULL이면 스택 포인터를 저장하지 않음
+    test rdi, rdi
+    jz .LoadContext
+    mov qword[ rdi ], rsp
+
     ;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;
-    ; 다음 태스크의 콘텍스트 복원
+    ; 다음 태스크의 스택으로 전환한 후 콘텍스트 복원
     ;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;
 .LoadContext:
     mov rsp, rsi
-    
-    ; Context 자료구조에서 레지스터를 복원
+
     KLOADCONTEXT
-    iretq
\ No newline at end of file
+
+    ; 다음 태스크가 유저 레벨(Ring 3)에서 실행 중이었다면 유저 GS로 교체
+    test qword [ rsp + 8 ], 3
+    jz .Return
+    swapgs
+
+.Return:
+    iretq
@@ -0,0 +1,148 @@
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+    memory::{alloc_frames, free_frames, FRAME_SIZE},
+    page::KERNEL_PAGETABLE,
+    percpu, smp,
+    utility::memset,
+};
+
+use super::{Process, ProcessState, PROCESS_INVALIDID};
+
+// The table grows one chunk of contiguous frames at a time. Chunks are never
+// given back, so lookups can go through the directory without the lock.
+const TASKTABLE_CHUNKFRAMECOUNT: u64 = 16;
+const TASKTABLE_CHUNKENTRYCOUNT: usize =
+    (TASKTABLE_CHUNKFRAMECOUNT * FRAME_SIZE) as usize / size_of::<Process>();
+const TASKTABLE_MAXCHUNKCOUNT: usize = 512;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_CHUNK: AtomicU64 = AtomicU64::new(0);
+static TASKTABLE_CHUNKS: [AtomicU64; TASKTABLE_MAXCHUNKCOUNT] =
+    [EMPTY_CHUNK; TASKTABLE_MAXCHUNKCOUNT];
+static TASKTABLE_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+
+const GENERATION_MASK: u64 = 0xFFFFFFFF;
+
+pub(super) fn index_of(id: u64) -> usize {
+    (id & GENERATION_MASK) as usize
+}
+
+pub(super) fn generation_of(id: u64) -> u64 {
+    id >> 32
+}
+
+// Entry behind an index whether or not it is in use
+pub(super) fn entry<'a>(index: usize) -> Option<&'a mut Process> {
+    if index >= TASKTABLE_CAPACITY.load(Ordering::Acquire) {
+        return None;
+    }
+    let chunk = TASKTABLE_CHUNKS[index / TASKTABLE_CHUNKENTRYCOUNT].load(Ordering::Acquire);
+    let process = (chunk as *mut Process).wrapping_add(index % TASKTABLE_CHUNKENTRYCOUNT);
+    Some(unsafe { &mut *process })
+}
+
+pub(super) fn capacity() -> usize {
+    TASKTABLE_CAPACITY.load(Ordering::Acquire)
+}
+
+pub struct TaskTable {
+    chunk_count: usize,
+    free_head: u64,
+    use_count: usize,
+    generation: u64,
+}
+
+impl TaskTable {
+    pub const fn new() -> Self {
+        Self {
+            chunk_count: 0,
+            free_head: PROCESS_INVALIDID,
+            use_count: 0,
+            generation: 1,
+        }
+    }
+
+    fn grow(&mut self) -> Result<(), ()> {
+        if self.chunk_count == TASKTABLE_MAXCHUNKCOUNT {
+            return Err(());
+        }
+        let chunk = alloc_frames(TASKTABLE_CHUNKFRAMECOUNT).ok_or(())?;
+        let first = self.chunk_count * TASKTABLE_CHUNKENTRYCOUNT;
+        // Entries are pushed in reverse so that lower indices are handed out first
+        for offset in (0..TASKTABLE_CHUNKENTRYCOUNT).rev() {
+            let process = unsafe { &mut *(chunk as *mut Process).add(offset) };
+            process.reset((first + offset) as u64);
+            process.free_next = self.free_head;
+            self.free_head = (first + offset) as u64;
+        }
+        TASKTABLE_CHUNKS[self.chunk_count].store(chunk, Ordering::Release);
+        self.chunk_count += 1;
+        TASKTABLE_CAPACITY.store(self.chunk_count * TASKTABLE_CHUNKENTRYCOUNT, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn alloc(&mut self) -> Option<*mut Process> {
+        if self.free_head == PROCESS_INVALIDID {
+            self.grow().ok()?;
+        }
+        let index = self.free_head as usize;
+        let target = entry(index)?;
+        self.free_head = target.free_next;
+        target.free_next = PROCESS_INVALIDID;
+        target.id = (self.generation << 32) | index as u64;
+        // Generation 0 marks a free entry, so it is skipped on wrap around
+        self.generation = self.generation % GENERATION_MASK + 1;
+        self.use_count += 1;
+        Some(target as *mut Process)
+    }
+
+    // Stale IDs are refused, so an entry cannot be freed twice
+    pub fn dealloc(&mut self, id: u64) -> Option<()> {
+        let target = entry(index_of(id))?;
+        if generation_of(id) == 0 || target.id != id {
+            return None;
+        }
+        if target.stack_size != 0 {
+            let _ = free_frames(target.stack, target.stack_size / FRAME_SIZE);
+        }
+        for cpu in 0..smp::cpu_count() {
+            let owner = &percpu::get(cpu).fpu_owner;
+            let _ = owner.compare_exchange(id, PROCESS_INVALIDID, Ordering::Relaxed, Ordering::Relaxed);
+        }
+        target.reset(index_of(id) as u64);
+        target.free_next = self.free_head;
+        self.free_head = index_of(id) as u64;
+        self.use_count -= 1;
+        Some(())
+    }
+
+    pub fn use_count(&self) -> usize {
+        self.use_count
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.chunk_count * TASKTABLE_CHUNKENTRYCOUNT
+    }
+}
+
+impl Process {
+    // Puts an entry back into the free state; the ID keeps only its index
+    fn reset(&mut self, index: u64) {
+        memset(self as *mut Process as *mut u8, 0, size_of::<Process>() as isize);
+        self.id = index;
+        self.state = ProcessState::Ready;
+        self.parent = PROCESS_INVALIDID;
+        self.child = PROCESS_INVALIDID;
+        self.sibling = PROCESS_INVALIDID;
+        self.group = PROCESS_INVALIDID;
+        self.thread = PROCESS_INVALIDID;
+        self.thread_sibling = PROCESS_INVALIDID;
+        self.page_table = KERNEL_PAGETABLE;
+        self.run_next = PROCESS_INVALIDID;
+        self.free_next = PROCESS_INVALIDID;
+    }
+}
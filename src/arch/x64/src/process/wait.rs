@@ -0,0 +1,101 @@
+use crate::spinlock::SpinLock;
+
+use super::{
+    get_pid, get_process_from_id, lock_scheduler_of, yield_next, BlockReason, ProcessState,
+    Scheduler, PROCESS_INVALIDID,
+};
+
+const WAITQUEUE_MAXCOUNT: usize = 16;
+
+// Tasks waiting for an event, kept next to what the event changes. A task
+// parked here is off the run queues until it is woken, so it takes no time
+// while it waits. Entries of tasks that ended are left for the next wake,
+// which finds their IDs gone.
+#[derive(Clone, Copy)]
+pub struct WaitQueue {
+    waiters: [u64; WAITQUEUE_MAXCOUNT],
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: [PROCESS_INVALIDID; WAITQUEUE_MAXCOUNT],
+        }
+    }
+
+    // False while every slot is taken
+    fn add(&mut self, pid: u64) -> bool {
+        if self.waiters.contains(&pid) {
+            return true;
+        }
+        match self.waiters.iter_mut().find(|waiter| **waiter == PROCESS_INVALIDID) {
+            Some(waiter) => {
+                *waiter = pid;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Makes every task parked for `reason` runnable again. Waiters check
+    // their condition once more, so waking one too many is harmless.
+    pub fn wake_all(&mut self, reason: BlockReason) {
+        for waiter in self.waiters.iter_mut() {
+            if *waiter != PROCESS_INVALIDID {
+                wake(*waiter, reason);
+                *waiter = PROCESS_INVALIDID;
+            }
+        }
+    }
+}
+
+// A task that has not been switched out yet only has its state changed, and
+// the switch queues it again
+fn wake(pid: u64, reason: BlockReason) {
+    let process = match get_process_from_id(pid) {
+        Some(process) => process,
+        None => return,
+    };
+    let mut scheduler = lock_scheduler_of(process);
+    if process.state != ProcessState::Waiting(reason) {
+        return;
+    }
+    if scheduler.running() == pid {
+        process.state = ProcessState::Running;
+    } else {
+        process.state = ProcessState::Ready;
+        let _ = scheduler.add_ready_list(pid);
+    }
+}
+
+// Parks the running task while `poll` returns the wait queue to park on.
+// `poll` runs with `lock` held, and a waker takes it to wake the queue, so a
+// change made after the check always finds the task parked. With the queue
+// full the task stays runnable and checks again after yielding.
+pub fn wait_on<Q, T>(
+    reason: BlockReason,
+    lock: &SpinLock<Q>,
+    mut poll: impl FnMut(&mut Q) -> Result<T, &mut WaitQueue>,
+) -> T {
+    let pid = get_pid();
+    let process = get_process_from_id(pid).unwrap();
+    loop {
+        {
+            let mut guard = lock.lock();
+            match poll(&mut guard) {
+                Ok(result) => {
+                    if process.state == ProcessState::Waiting(reason) {
+                        process.state = ProcessState::Running;
+                    }
+                    return result;
+                }
+                Err(queue) => {
+                    if queue.add(pid) {
+                        process.state = ProcessState::Waiting(reason);
+                    }
+                }
+            }
+        }
+        yield_next();
+    }
+}
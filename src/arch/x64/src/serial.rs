@@ -0,0 +1,74 @@
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::assembly::{InPortByte, OutPortByte};
+
+const SERIAL_PORT_COM1: u16 = 0x3F8;
+
+const SERIAL_PORT_INDEX_DATA: u16 = 0;
+const SERIAL_PORT_INDEX_INTERRUPTENABLE: u16 = 1;
+const SERIAL_PORT_INDEX_DIVISORLATCHLSB: u16 = 0;
+const SERIAL_PORT_INDEX_DIVISORLATCHMSB: u16 = 1;
+const SERIAL_PORT_INDEX_FIFOCONTROL: u16 = 2;
+const SERIAL_PORT_INDEX_LINECONTROL: u16 = 3;
+const SERIAL_PORT_INDEX_MODEMCONTROL: u16 = 4;
+const SERIAL_PORT_INDEX_LINESTATUS: u16 = 5;
+
+const SERIAL_LINECONTROL_8BIT: u8 = 0x03;
+const SERIAL_LINECONTROL_DLAB: u8 = 0x80;
+const SERIAL_FIFOCONTROL_ENABLE14BYTE: u8 = 0xC7;
+const SERIAL_MODEMCONTROL_DTRRTSOUT2: u8 = 0x0B;
+const SERIAL_LINESTATUS_TRANSMITEMPTY: u8 = 0x20;
+
+// Divisor of the 115200 Hz base clock
+const SERIAL_DIVISORLATCH_115200: u16 = 1;
+
+// Polling driver for COM1, used to get data out of the machine. A trace dump
+// keeps the port for seconds, which is too long to go without interrupts, so
+// it stays a plain mutex; interrupt context only ever tries to take it.
+pub struct SerialPort {
+    port: u16,
+}
+
+pub static SERIAL: Mutex<SerialPort> = Mutex::new(SerialPort {
+    port: SERIAL_PORT_COM1,
+});
+
+impl SerialPort {
+    fn init(&mut self) {
+        OutPortByte(self.port + SERIAL_PORT_INDEX_INTERRUPTENABLE, 0x00);
+        OutPortByte(self.port + SERIAL_PORT_INDEX_LINECONTROL, SERIAL_LINECONTROL_DLAB);
+        OutPortByte(
+            self.port + SERIAL_PORT_INDEX_DIVISORLATCHLSB,
+            (SERIAL_DIVISORLATCH_115200 & 0xFF) as u8,
+        );
+        OutPortByte(
+            self.port + SERIAL_PORT_INDEX_DIVISORLATCHMSB,
+            (SERIAL_DIVISORLATCH_115200 >> 8) as u8,
+        );
+        OutPortByte(self.port + SERIAL_PORT_INDEX_LINECONTROL, SERIAL_LINECONTROL_8BIT);
+        OutPortByte(self.port + SERIAL_PORT_INDEX_FIFOCONTROL, SERIAL_FIFOCONTROL_ENABLE14BYTE);
+        OutPortByte(self.port + SERIAL_PORT_INDEX_MODEMCONTROL, SERIAL_MODEMCONTROL_DTRRTSOUT2);
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        while InPortByte(self.port + SERIAL_PORT_INDEX_LINESTATUS) & SERIAL_LINESTATUS_TRANSMITEMPTY
+            == 0
+        {}
+        OutPortByte(self.port + SERIAL_PORT_INDEX_DATA, byte);
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+pub fn init_serial() {
+    SERIAL.lock().init();
+}
@@ -1,13 +1,22 @@
-use core::{hint::black_box, str};
+use core::{hint::black_box, mem::size_of, str};
 
 use crate::{
+    acpi,
     assembly::{read_TSC, DisableInterrupt, EnableInterrupt},
-    console::{clear_screen, get_curser, getch, set_curser},
-    keyboard::{KeySpecial, Reboot},
-    print, print_string, println,
-    process::{self, create_task, process_count, PRIORITY_HIGHIST, PRIORITY_LOWIST},
-    timer::{convert_from_ms, init_PIT, wait, wait_using_PIT, Date, Time},
-    utility::{get_ram_size, memset},
+    channel,
+    console::{clear_screen, get_curser, getch, set_curser, try_getch},
+    elf::{self, ELF_MAXARGCOUNT},
+    pci::{self, PciBar},
+    keyboard::KeySpecial,
+    power, print, print_string, println,
+    process::{self, create_task, process_count, AFFINITY_ALL, PRIORITY_HIGHIST, PRIORITY_LOWIST},
+    smp, spinlock,
+    timer::{
+        self, convert_from_ms, get_tick_count, init_PIT, wait, wait_using_PIT, Date, Time,
+    },
+    trace,
+    utility::{get_ram_size, memcpy, memset},
+    watchdog::{self, WatchdogAction},
 };
 
 const CONSOLE_MAXCOMMANDBUFFERSIZE: usize = 300;
@@ -44,9 +53,14 @@ static COMMAND_TABLE: &[Command] = &[
     },
     Command {
         command: "shutdown",
-        help: "Shutdown And Reboot OS",
+        help: "Power Off The Machine",
         command_function: shut_down,
     },
+    Command {
+        command: "reboot",
+        help: "Reboot The Machine",
+        command_function: reboot,
+    },
     Command {
         command: "settimer",
         help: "Set PIT Controller Counter0",
@@ -72,6 +86,21 @@ static COMMAND_TABLE: &[Command] = &[
         help: "Create Test Task",
         command_function: test_create_task,
     },
+    Command {
+        command: "createthread",
+        help: "Create Test Process With Threads",
+        command_function: test_create_thread,
+    },
+    Command {
+        command: "testchannel",
+        help: "Pass Messages Between Tasks Through A Channel",
+        command_function: test_channel,
+    },
+    Command {
+        command: "testrunqueue",
+        help: "Check Removing Tasks That Are Not Queued",
+        command_function: test_run_queue,
+    },
     Command {
         command: "listtask",
         help: "Get List of Task",
@@ -79,19 +108,89 @@ static COMMAND_TABLE: &[Command] = &[
     },
     Command {
         command: "killtask",
-        help: "Kill Task",
+        help: "Kill Task, Or Every Thread Of A Process",
         command_function: kill_task,
     },
+    Command {
+        command: "signal",
+        help: "Send Signal To Task",
+        command_function: send_signal,
+    },
     Command {
         command: "changepriority",
         help: "Change Process Priority",
         command_function: change_priority,
     },
+    Command {
+        command: "setaffinity",
+        help: "Set The Cores A Task May Run On",
+        command_function: set_affinity,
+    },
+    Command {
+        command: "cpus",
+        help: "Show Online Cores",
+        command_function: show_cpus,
+    },
+    Command {
+        command: "acpi",
+        help: "List ACPI Tables And Their Main Fields",
+        command_function: show_acpi,
+    },
+    Command {
+        command: "lspci",
+        help: "List PCI Devices, ex)lspci [-v]",
+        command_function: list_pci,
+    },
     Command {
         command: "cpuload",
         help: "Get CPU Load",
         command_function: cpu_load,
     },
+    Command {
+        command: "top",
+        help: "Show Task CPU Usage Until A Key Is Pressed",
+        command_function: top,
+    },
+    Command {
+        command: "stackusage",
+        help: "Show Deepest Stack Use Of Each Task",
+        command_function: stack_usage,
+    },
+    Command {
+        command: "run",
+        help: "Run User Program",
+        command_function: run_program,
+    },
+    Command {
+        command: "trace",
+        help: "Record Scheduler Events And Dump Them To Serial",
+        command_function: trace_event,
+    },
+    Command {
+        command: "tickless",
+        help: "Switch Tickless Idle And Count Timer Wakeups",
+        command_function: tickless,
+    },
+    Command {
+        command: "watchdog",
+        help: "Configure The Lockup Watchdog",
+        command_function: watchdog_config,
+    },
+    Command {
+        command: "lockdebug",
+        help: "Switch Spin Lock Holder Tracking And Misuse Checks",
+        command_function: lock_debug,
+    },
+    Command {
+        command: "realtime",
+        help: "Start A Periodic Real-Time Task Or Show Deadline Misses",
+        command_function: realtime,
+    },
+    Command {
+        command: "benchmark",
+        help: "Measure Kernel Paths In CPU Cycles",
+        command_function: benchmark,
+    },
 ];
 
 pub fn start_shell() {
@@ -176,9 +275,14 @@ fn total_ram_size(_args: &mut Parameter) {
 }
 fn shut_down(_args: &mut Parameter) {
     println!("System Shutdown start...");
-    println!("Press Any Key To Reboot PC");
+    power::power_off();
+    println!("ACPI Power Off Failed, Press Any Key To Reboot PC");
     getch();
-    Reboot();
+    power::reboot();
+}
+fn reboot(_args: &mut Parameter) {
+    println!("System Reboot start...");
+    power::reboot();
 }
 
 fn show_date_and_time(_args: &mut Parameter) {
@@ -285,98 +389,399 @@ fn test_task() {
     }
 }
 
+fn test_thread_process(thread_count: u64) {
+    for _ in 0..thread_count {
+        if let Err(_) = process::create_thread("testthread", PRIORITY_LOWIST, test_task as u64, 0) {
+            break;
+        }
+    }
+    test_task();
+}
+
+fn test_create_thread(args: &mut Parameter) {
+    let count: u64 = match args.next() {
+        Some(string) => match string.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                println!("createthread [count]");
+                return;
+            }
+        },
+        None => {
+            println!("createthread [count]");
+            return;
+        }
+    };
+    if let Err(_) = create_task(
+        "testprocess",
+        PRIORITY_LOWIST,
+        test_thread_process as u64,
+        count,
+        AFFINITY_ALL,
+    ) {
+        println!("Cannot create process");
+    }
+}
+
+const CHANNELTEST_CAPACITY: u64 = 4;
+const CHANNELTEST_MESSAGECOUNT: u64 = 100;
+
+fn test_channel_producer(channel_id: u64) {
+    for value in 1..=CHANNELTEST_MESSAGECOUNT {
+        if let Err(()) = channel::send(channel_id, value) {
+            break;
+        }
+    }
+}
+
+// The queue is kept short so that the producer has to wait for the shell
+fn test_channel(_args: &mut Parameter) {
+    let channel_id = match channel::create(CHANNELTEST_CAPACITY) {
+        Ok(id) => id,
+        Err(()) => {
+            println!("Cannot create channel");
+            return;
+        }
+    };
+    match create_task(
+        "testproducer",
+        PRIORITY_LOWIST,
+        test_channel_producer as u64,
+        channel_id,
+        AFFINITY_ALL,
+    ) {
+        Ok(pid) => {
+            let mut sum = 0;
+            for _ in 0..CHANNELTEST_MESSAGECOUNT {
+                match channel::receive(channel_id) {
+                    Ok(message) if message.sender == pid => sum += message.data,
+                    _ => break,
+                }
+            }
+            // A producer still waiting on the queue gives up once it is gone
+            let _ = channel::release(channel_id);
+            let _ = process::join(pid);
+            println!(
+                "Received {} Messages From Task [0x{pid:X}], Sum {}",
+                CHANNELTEST_MESSAGECOUNT, sum
+            );
+        }
+        Err(()) => {
+            println!("Cannot create task");
+            let _ = channel::release(channel_id);
+        }
+    }
+}
+
+fn test_run_queue(_args: &mut Parameter) {
+    match process::test_run_queue() {
+        Ok(()) => println!("Run Queue Test Passed"),
+        Err(()) => println!("Run Queue Test Failed"),
+    }
+}
+
 fn test_create_task(args: &mut Parameter) {
     let count: u64 = match args.next() {
         Some(string) => match string.parse() {
             Ok(value) => value,
             Err(_) => {
-                println!("createtask [count]");
+                println!("createtask [count] [affinity]");
                 return;
             }
         },
         None => {
-            println!("createtask [count]");
+            println!("createtask [count] [affinity]");
             return;
         }
     };
+    let affinity = match args.next() {
+        Some(string) => match parse_affinity(string) {
+            Some(value) => value,
+            None => {
+                println!("createtask [count] [affinity]");
+                return;
+            }
+        },
+        None => AFFINITY_ALL,
+    };
     for _ in 0..count {
-        if let Err(_) = create_task(PRIORITY_LOWIST, test_task as u64) {
+        if let Err(_) = create_task("testtask", PRIORITY_LOWIST, test_task as u64, 0, affinity) {
             break;
         }
     }
 }
 
-fn list_task(_args: &mut Parameter) {
+fn list_task(args: &mut Parameter) {
+    let filter = args.next();
     let mut count = 0;
-    println!("\n         ---      Task List      ---\n");
-    for pid in 0..process::PROCESS_MAXCOUNT as u64 {
+    let (used, capacity) = process::task_table_usage();
+    println!("\n         ---      Task List [{used}/{capacity}]      ---\n");
+    for pid in process::task_ids() {
         if let Some(process) = process::get_process_from_id(pid) {
-            if process.id >> 32 != 0 {
-                if count != 0 && (count % 10) == 0 {
-                    print!("Press any key to continue ('q' is exit)");
-                    if getch() == b'q' {
+            if !process.is_thread() {
+                let tasks = core::iter::once(pid).chain(process.threads());
+                for (depth, tid) in tasks.enumerate() {
+                    let task = process::get_process_from_id(tid).unwrap();
+                    if filter.map_or(false, |name| name != task.name()) {
+                        continue;
+                    }
+                    if count != 0 && (count % 10) == 0 {
+                        print!("Press any key to continue ('q' is exit)");
+                        if getch() == b'q' {
+                            println!();
+                            return;
+                        }
                         println!();
-                        break;
                     }
-                    println!();
+                    println!(
+                        "[{}] {}{} [{}] ID[0x{:X}], Priority[0x{:X}], State[{}], CPU[{}%]",
+                        count + 1,
+                        if depth == 0 { "" } else { "  +- " },
+                        if depth == 0 { "Process" } else { "Thread" },
+                        task.name(),
+                        tid,
+                        process::get_priority(task.flags),
+                        task.state.as_str(),
+                        task.cpu_usage()
+                    );
+                    println!(
+                        "    {}Switch[{} Yield, {} Preempt], Created[{}], LastRun[{}]",
+                        if depth == 0 { "" } else { "     " },
+                        task.voluntary_switches,
+                        task.preempted_switches,
+                        task.created_tick,
+                        task.last_run_tick
+                    );
+                    println!(
+                        "    {}Core[{}], Affinity[0x{:X}]",
+                        if depth == 0 { "" } else { "     " },
+                        task.cpu(),
+                        task.affinity
+                    );
+                    if task.realtime.is_admitted() {
+                        println!(
+                            "    {}RealTime[Period {}, Runtime {}, Deadline {}], Jobs[{}], Misses[{}]",
+                            if depth == 0 { "" } else { "     " },
+                            task.realtime.period,
+                            task.realtime.runtime,
+                            task.realtime.deadline,
+                            task.realtime.job_count,
+                            task.realtime.miss_count
+                        );
+                    }
+                    count += 1;
                 }
+            }
+        }
+    }
+}
+
+const TOP_MAXLINECOUNT: usize = 20;
+const TOP_MAXSAMPLECOUNT: usize = 256;
+
+// Redraws every second with the CPU share each task got since the previous
+// frame; tasks that were not there yet show their lifetime average
+fn top(_args: &mut Parameter) {
+    let mut samples = [(0u64, 0u64); TOP_MAXSAMPLECOUNT];
+    let mut sample_count = 0;
+    let mut last_tick = get_tick_count();
+    loop {
+        let now = get_tick_count();
+        let elapsed = now - last_tick;
+        clear_screen();
+        set_curser(0, 1);
+        println!(
+            "top - Tick[{}], CPU Load[{}%], Tasks[{}], Press any key to exit",
+            now,
+            process::process_load(),
+            process_count()
+        );
+        println!("\n ID           Name              Pri   State          CPU%  Yield     Preempt");
+        let mut line = 0;
+        let mut new_samples = [(0u64, 0u64); TOP_MAXSAMPLECOUNT];
+        let mut new_sample_count = 0;
+        for pid in process::task_ids() {
+            let task = match process::get_process_from_id(pid) {
+                Some(task) => task,
+                None => continue,
+            };
+            let last = samples[..sample_count].iter().find(|(id, _)| *id == pid);
+            let usage = match last {
+                Some((_, last_cpu_ticks)) if elapsed != 0 => {
+                    (task.cpu_ticks - last_cpu_ticks).min(elapsed) * 100 / elapsed
+                }
+                _ => task.cpu_usage(),
+            };
+            if new_sample_count < TOP_MAXSAMPLECOUNT {
+                new_samples[new_sample_count] = (pid, task.cpu_ticks);
+                new_sample_count += 1;
+            }
+            if line < TOP_MAXLINECOUNT {
                 println!(
-                    "[{}] Task ID[0x{:X}], Priority[0x{:X}], Flags[0x{:X}]",
-                    count + 1,
+                    " 0x{:<10X} {:<16}  0x{:<2X}  {:<13}  {:>3}%  {:<8}  {}",
                     pid,
-                    process::get_priority(process.flags),
-                    process.flags
+                    task.name(),
+                    process::get_priority(task.flags),
+                    task.state.as_str(),
+                    usage,
+                    task.voluntary_switches,
+                    task.preempted_switches
                 );
-                count += 1;
             }
+            line += 1;
+        }
+        if line > TOP_MAXLINECOUNT {
+            println!(" ... {} more", line - TOP_MAXLINECOUNT);
+        }
+        samples = new_samples;
+        sample_count = new_sample_count;
+        last_tick = now;
+
+        while get_tick_count() - now < 1000 {
+            if try_getch().is_some() {
+                clear_screen();
+                println!();
+                return;
+            }
+            process::sleep(50);
         }
     }
 }
 
+fn stack_usage(_args: &mut Parameter) {
+    let mut count = 0;
+    println!("\n ID           Name              Used    Total   Usage");
+    for pid in process::task_ids() {
+        let task = match process::get_process_from_id(pid) {
+            Some(task) if task.stack_size() != 0 => task,
+            _ => continue,
+        };
+        if count != 0 && (count % 20) == 0 {
+            print!("Press any key to continue ('q' is exit)");
+            if getch() == b'q' {
+                println!();
+                return;
+            }
+            println!();
+        }
+        let used = task.stack_usage();
+        println!(
+            " 0x{:<10X} {:<16}  {:<6}  {:<6}  {:>3}%{}",
+            pid,
+            task.name(),
+            used,
+            task.stack_size(),
+            used * 100 / task.stack_size(),
+            if task.is_stack_intact() { "" } else { " Overflowed" }
+        );
+        count += 1;
+    }
+}
+
+// Accepts a decimal ID, a hex ID with a 0x prefix or a task name
+fn parse_task(string: &str) -> Option<u64> {
+    if let Ok(value) = string.parse() {
+        Some(value)
+    } else if let Some(Ok(value)) = string
+        .strip_prefix("0x")
+        .map(|hex| u64::from_str_radix(hex, 16))
+    {
+        Some(value)
+    } else {
+        process::find_process(string)
+    }
+}
+
 fn kill_task(args: &mut Parameter) {
-    let pid = if let Some(string) = args.next() {
-        if let Ok(value) = string.parse() {
-            value
-        } else if let Ok(value) = u64::from_str_radix(&string[2..], 16) {
-            value
-        } else {
-            println!("kill [pid]");
+    let pid = match args.next() {
+        Some(string) => match parse_task(string) {
+            Some(pid) => pid,
+            None => {
+                println!("there are no Task [{string}]");
+                return;
+            }
+        },
+        None => {
+            println!("killtask [pid or name]");
             return;
         }
-    } else {
-        println!("kill [pid]");
-        return;
     };
+    // A core without its idle task has nothing left to reap its tasks, and the
+    // console goes with the shell
+    match process::get_process_from_id(pid) {
+        Some(task) if task.flags & process::PROCESS_FLAG_IDLETASK != 0 => {
+            println!("Cannot kill the idle Task [0x{pid:X}]");
+            return;
+        }
+        _ if pid == process::get_pid() => {
+            println!("Cannot kill the shell Task [0x{pid:X}]");
+            return;
+        }
+        _ => {}
+    }
     if process::is_process_exist(pid) {
-        process::end_process(pid);
+        process::end_process(pid, process::PROCESS_EXITCODE_KILLED);
+        if let Ok(exit_code) = process::join(pid) {
+            println!("Task [0x{pid:X}] exited with code 0x{exit_code:X}");
+        }
     } else {
         println!("there are no Process [0x{pid:X}]");
     }
 }
 
+fn send_signal(args: &mut Parameter) {
+    let (pid, signal) = match (args.next(), args.next()) {
+        (Some(task), Some(name)) => {
+            let signal = name.parse().ok().or_else(|| {
+                process::SIGNAL_NAMES
+                    .iter()
+                    .find(|(signal_name, _)| *signal_name == name)
+                    .map(|(_, number)| *number)
+            });
+            match (parse_task(task), signal) {
+                (Some(pid), Some(signal)) => (pid, signal),
+                (None, _) => {
+                    println!("there are no Task [{task}]");
+                    return;
+                }
+                (_, None) => {
+                    println!("there are no Signal [{name}]");
+                    return;
+                }
+            }
+        }
+        _ => {
+            print!("signal [pid or name] [signal], signals:");
+            for (name, number) in process::SIGNAL_NAMES {
+                print!(" {name}({number})");
+            }
+            println!();
+            return;
+        }
+    };
+    match process::send_signal(pid, signal) {
+        Ok(()) => println!("Signal {signal} sent to Task [0x{pid:X}]"),
+        Err(()) => println!("Cannot send Signal {signal} to Task [0x{pid:X}]"),
+    }
+}
+
 fn change_priority(args: &mut Parameter) {
-    let pid = if let Some(string) = args.next() {
-        if let Ok(value) = string.parse() {
-            value
-        } else if let Ok(value) = u64::from_str_radix(&string[2..], 16) {
-            value
-        } else {
-            println!("changepriority [pid] [priority]");
+    let pid = match args.next().and_then(parse_task) {
+        Some(pid) => pid,
+        None => {
+            println!("changepriority [pid or name] [priority]");
             return;
         }
-    } else {
-        println!("changepriority [pid] [priority]");
-        return;
     };
     let priority = if let Some(string) = args.next() {
         if let Ok(value) = string.parse() {
             value
         } else {
-            println!("changepriority [pid] [priority]");
+            println!("changepriority [pid or name] [priority]");
             return;
         }
     } else {
-        println!("changepriority [pid] [priority]");
+        println!("changepriority [pid or name] [priority]");
         return;
     };
     if process::is_process_exist(pid) {
@@ -390,8 +795,47 @@ fn change_priority(args: &mut Parameter) {
     }
 }
 
+// Masks are taken in hex with a 0x prefix, or in decimal
+fn parse_affinity(string: &str) -> Option<u64> {
+    match string.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => string.parse().ok(),
+    }
+}
+
+fn set_affinity(args: &mut Parameter) {
+    let pid = match args.next().and_then(parse_task) {
+        Some(pid) => pid,
+        None => {
+            println!("setaffinity [pid or name] [mask]");
+            return;
+        }
+    };
+    let affinity = match args.next().and_then(parse_affinity) {
+        Some(value) => value,
+        None => {
+            println!("setaffinity [pid or name] [mask]");
+            return;
+        }
+    };
+    if process::is_process_exist(pid) {
+        if let Err(()) = process::set_affinity(pid, affinity) {
+            println!("Cannot set affinity");
+        } else {
+            println!("Set Task Affinity ID[0x{pid:X}] Affinity[0x{affinity:X}]");
+        }
+    } else {
+        println!("There are no Process ID [0x{pid:X}]");
+    }
+}
+
 fn cpu_load(_args: &mut Parameter) {
     println!("CPU Load: {}%", process::process_load());
+    if smp::online_count() > 1 {
+        for cpu in (0..smp::cpu_count()).filter(|&cpu| smp::is_online(cpu)) {
+            println!("    Core[{}] Load: {}%", cpu, process::cpu_load(cpu));
+        }
+    }
 }
 
 impl<'a> Parameter<'a> {
@@ -411,3 +855,477 @@ impl<'a> Parameter<'a> {
         return Some(arg);
     }
 }
+
+fn run_program(args: &mut Parameter) {
+    let mut argv = [""; ELF_MAXARGCOUNT];
+    let mut argc = 0;
+    while let Some(arg) = args.next() {
+        if argc == ELF_MAXARGCOUNT {
+            println!("run: too many arguments");
+            return;
+        }
+        argv[argc] = arg;
+        argc += 1;
+    }
+    if argc == 0 {
+        println!("run [program] [args]");
+        print!("programs:");
+        for program in elf::PROGRAM_TABLE {
+            print!(" {}", program.name);
+        }
+        println!();
+        return;
+    }
+    let image = match elf::find_program(argv[0]) {
+        Some(image) => image,
+        None => {
+            println!("there are no Program [{}]", argv[0]);
+            return;
+        }
+    };
+    match elf::execute(image, &argv[..argc], PRIORITY_LOWIST) {
+        Ok(pid) => {
+            if let Ok(exit_code) = process::join(pid) {
+                println!("Program [0x{pid:X}] exited with code 0x{exit_code:X}");
+            }
+        }
+        Err(()) => println!("Cannot load Program [{}]", argv[0]),
+    }
+}
+
+fn trace_event(args: &mut Parameter) {
+    match args.next() {
+        Some("start") => match trace::start() {
+            Ok(()) => println!("Trace started"),
+            Err(()) => println!("Cannot allocate trace buffer"),
+        },
+        Some("stop") => {
+            trace::stop();
+            println!("Trace stopped");
+        }
+        Some("dump") => {
+            let count = trace::dump();
+            println!("{} events sent to serial port", count);
+        }
+        _ => println!(
+            "trace [start|stop|dump], now {}",
+            if trace::is_enabled() { "recording" } else { "stopped" }
+        ),
+    }
+}
+
+const TICKLESS_SAMPLEMS: u64 = 1000;
+
+// Sleeps with the system otherwise idle and reports how often the timer
+// interrupted it, for comparing the tickless and periodic modes
+fn tickless(args: &mut Parameter) {
+    match args.next() {
+        Some("on") => timer::set_tickless(true),
+        Some("off") => timer::set_tickless(false),
+        Some(_) => {
+            println!("tickless [on|off]");
+            return;
+        }
+        None => {}
+    }
+    let interrupt_count = timer::get_interrupt_count();
+    let halt_count = process::halt_count();
+    let tick = get_tick_count();
+    process::sleep(TICKLESS_SAMPLEMS);
+    println!(
+        "Tickless[{}], {} Timer Interrupts And {} Halts In {} ms",
+        if timer::is_tickless() { "On" } else { "Off" },
+        timer::get_interrupt_count() - interrupt_count,
+        process::halt_count() - halt_count,
+        get_tick_count() - tick
+    );
+}
+
+fn watchdog_config(args: &mut Parameter) {
+    let usage = "watchdog [on|off|timeout ms|action kill|panic]";
+    match args.next() {
+        Some("on") => watchdog::set_enabled(true),
+        Some("off") => watchdog::set_enabled(false),
+        Some("timeout") => match args.next().map(|arg| arg.parse::<u64>()) {
+            Some(Ok(millisecond)) => watchdog::set_timeout(millisecond),
+            _ => {
+                println!("{}", usage);
+                return;
+            }
+        },
+        Some("action") => match args.next() {
+            Some("kill") => watchdog::set_action(WatchdogAction::Kill),
+            Some("panic") => watchdog::set_action(WatchdogAction::Panic),
+            _ => {
+                println!("{}", usage);
+                return;
+            }
+        },
+        Some(_) => {
+            println!("{}", usage);
+            return;
+        }
+        None => {}
+    }
+    println!(
+        "Watchdog[{}], Timeout {} ms, Action {}",
+        if watchdog::is_enabled() { "On" } else { "Off" },
+        watchdog::get_timeout(),
+        match watchdog::get_action() {
+            WatchdogAction::Kill => "Kill",
+            WatchdogAction::Panic => "Panic",
+        }
+    );
+}
+
+// Recursive acquires panic in debug mode; locks held or waited on for too
+// long are reported on the console and counted
+fn lock_debug(args: &mut Parameter) {
+    match args.next() {
+        Some("on") => spinlock::set_debug(true),
+        Some("off") => spinlock::set_debug(false),
+        Some(_) => {
+            println!("lockdebug [on|off]");
+            return;
+        }
+        None => {}
+    }
+    println!(
+        "Lock Debug[{}], {} Long Holds Or Waits Reported",
+        if spinlock::is_debug() { "On" } else { "Off" },
+        spinlock::get_report_count()
+    );
+}
+
+// Each job spins for `work` ticks of its own CPU time
+fn realtime_task(work: u64) {
+    let current = process::get_process_from_id(process::get_pid()).unwrap();
+    loop {
+        let start = current.read_cpu_ticks();
+        while current.read_cpu_ticks() - start < work {}
+        if let Err(()) = process::wait_next_period() {
+            break;
+        }
+    }
+}
+
+fn realtime(args: &mut Parameter) {
+    let usage = "realtime [period ms] [runtime ms] [deadline ms]";
+    let mut values = [0u64; 3];
+    let mut count = 0;
+    while let Some(string) = args.next() {
+        match string.parse() {
+            Ok(value) if count < values.len() => values[count] = value,
+            _ => {
+                println!("{}", usage);
+                return;
+            }
+        }
+        count += 1;
+    }
+    if count == 0 {
+        let mut task_count = 0;
+        for pid in process::task_ids() {
+            let task = match process::get_process_from_id(pid) {
+                Some(task) if task.realtime.is_admitted() && !task.is_ended() => task,
+                _ => continue,
+            };
+            println!(
+                "[{}] ID[0x{:X}], Period[{}], Runtime[{}], Deadline[{}], Jobs[{}], Misses[{}]",
+                task.name(),
+                pid,
+                task.realtime.period,
+                task.realtime.runtime,
+                task.realtime.deadline,
+                task.realtime.job_count,
+                task.realtime.miss_count
+            );
+            task_count += 1;
+        }
+        let utilization = process::realtime_utilization();
+        println!(
+            "{} Real-Time Tasks, Utilization {}.{}% Of {}%",
+            task_count,
+            utilization / 10000,
+            utilization / 1000 % 10,
+            process::REALTIME_MAXUTILIZATION / 10000
+        );
+        return;
+    } else if count < 2 {
+        println!("{}", usage);
+        return;
+    }
+    let [period, runtime, deadline] = values;
+    // The job leaves some of its budget unused
+    let work = (runtime * 3 / 4).max(1);
+    let pid = match create_task("rttask", PRIORITY_LOWIST, realtime_task as u64, work, AFFINITY_ALL) {
+        Ok(pid) => pid,
+        Err(()) => {
+            println!("Cannot create task");
+            return;
+        }
+    };
+    match process::set_realtime(pid, period, runtime, deadline) {
+        Ok(()) => println!("Real-Time Task [0x{pid:X}] Started"),
+        Err(()) => {
+            process::end_process(pid, process::PROCESS_EXITCODE_KILLED);
+            let _ = process::join(pid);
+            println!("Task Set Not Admitted");
+        }
+    }
+}
+
+const BENCHMARK_ROUNDCOUNT: u64 = 10000;
+
+fn benchmark(args: &mut Parameter) {
+    match args.next() {
+        Some("ctxswitch") => benchmark_context_switch(),
+        _ => println!("benchmark ctxswitch"),
+    }
+}
+
+// The timer path used to copy the saved context out of the IST into the task
+// and the next task's back in on every preemption. That path is gone, so the
+// before figure is an estimate: the two copies are timed between hot buffers
+// and added to the stack switch that replaced them, which is measured.
+fn benchmark_context_switch() {
+    let switch = match process::measure_switch(BENCHMARK_ROUNDCOUNT) {
+        Ok(cycles) => cycles,
+        Err(()) => {
+            println!("Not enough memory");
+            return;
+        }
+    };
+    let mut saved = process::Context::empty();
+    let mut stacked = process::Context::empty();
+    let saved = &mut saved as *mut process::Context as *mut u8;
+    let stacked = &mut stacked as *mut process::Context as *mut u8;
+    let size = size_of::<process::Context>() as isize;
+    DisableInterrupt();
+    let start = read_TSC();
+    for _ in 0..BENCHMARK_ROUNDCOUNT {
+        memcpy(black_box(saved), stacked, size);
+        memcpy(black_box(stacked), saved, size);
+    }
+    let copy = (read_TSC() - start) / BENCHMARK_ROUNDCOUNT;
+    EnableInterrupt();
+    println!("Context Switch, Average Of {} Rounds", BENCHMARK_ROUNDCOUNT);
+    println!("  After (Stack Switch, Measured)       : {} Cycles", switch);
+    println!("  Context Copies Through IST (Measured): {} Cycles", copy);
+    println!("  Before (Switch + Copies, Estimated)  : {} Cycles", switch + copy);
+}
+
+fn show_cpus(_args: &mut Parameter) {
+    println!("{} Of {} Cores Online", smp::online_count(), smp::cpu_count());
+    for cpu in 0..smp::cpu_count() {
+        let (running, count) = process::get_cpu_tasks(cpu);
+        println!(
+            "Core[{}] APIC ID[{}] {} [{}] Running[0x{:X}], Queued[{}]",
+            cpu,
+            smp::get_apic_id(cpu),
+            if cpu == 0 { "BSP" } else { "AP " },
+            if smp::is_online(cpu) { "Online" } else { "Offline" },
+            running,
+            count
+        );
+    }
+}
+
+fn ascii(bytes: &[u8]) -> &str {
+    str::from_utf8(bytes).unwrap_or("?")
+}
+
+// Every table the root table lists, with what the decoded ones hold below it
+fn show_acpi(_args: &mut Parameter) {
+    let (address, rsdp) = match acpi::rsdp() {
+        Some(rsdp) => rsdp,
+        None => {
+            println!("No ACPI Tables Found");
+            return;
+        }
+    };
+    println!(
+        "RSDP Address[0x{:X}] Revision[{}] OEM[{}]",
+        address,
+        rsdp.revision,
+        ascii(&rsdp.oem_id)
+    );
+    for (address, header) in acpi::tables() {
+        let valid = acpi::is_valid_table(address);
+        println!(
+            "{} Address[0x{:X}] Length[{}] Revision[{}] OEM[{}] Checksum[{}]",
+            ascii(&header.signature),
+            address,
+            { header.length },
+            header.revision,
+            ascii(&header.oem_id),
+            if valid { "OK" } else { "Bad" }
+        );
+        if !valid {
+            continue;
+        }
+        match &header.signature {
+            b"APIC" => show_madt(),
+            b"FACP" => show_fadt(),
+            b"HPET" => show_hpet(),
+            b"MCFG" => show_mcfg(),
+            _ => {}
+        }
+    }
+}
+
+fn show_madt() {
+    println!(
+        "  Local APIC[0x{:X}] Flags[0x{:X}] Processors[{}]",
+        acpi::local_apic_address().unwrap_or(0),
+        acpi::madt_flags(),
+        acpi::local_apic_ids().count()
+    );
+    for ioapic in acpi::io_apics() {
+        println!(
+            "  I/O APIC ID[{}] Address[0x{:X}] GSI Base[{}]",
+            ioapic.id, ioapic.address, ioapic.gsi_base
+        );
+    }
+    for entry in acpi::interrupt_overrides() {
+        println!("  IRQ[{}] To GSI[{}] Flags[0x{:X}]", entry.irq, entry.gsi, entry.flags);
+    }
+}
+
+fn show_fadt() {
+    let fadt = match acpi::fadt() {
+        Some(fadt) => fadt,
+        None => return,
+    };
+    println!(
+        "  SCI[{}] SMI Command[0x{:X}] DSDT[0x{:X}] Flags[0x{:X}]",
+        { fadt.sci_interrupt },
+        { fadt.smi_command },
+        fadt.dsdt_address(),
+        { fadt.flags }
+    );
+    println!(
+        "  PM1a Control[0x{:X}] PM1b Control[0x{:X}] PM Timer[0x{:X}] Century[0x{:X}]",
+        { fadt.pm1a_control_block },
+        { fadt.pm1b_control_block },
+        { fadt.pm_timer_block },
+        fadt.century
+    );
+    let reset = fadt.reset_register;
+    if reset.address != 0 {
+        println!(
+            "  Reset Register[0x{:X}] Space[{}] Value[0x{:X}]",
+            { reset.address },
+            reset.address_space,
+            fadt.reset_value
+        );
+    }
+}
+
+fn show_hpet() {
+    if let Some(hpet) = acpi::hpet() {
+        let base = hpet.base_address;
+        println!(
+            "  Address[0x{:X}] Comparators[{}] Counter[{} Bit] Vendor[0x{:X}] Minimum Tick[{}]",
+            { base.address },
+            hpet.comparator_count(),
+            if hpet.is_64bit() { 64 } else { 32 },
+            hpet.vendor_id(),
+            { hpet.minimum_tick }
+        );
+    }
+}
+
+fn show_mcfg() {
+    for entry in acpi::mcfg_entries() {
+        println!(
+            "  Segment[{}] Bus[{}-{}] Address[0x{:X}]",
+            { entry.segment_group },
+            entry.start_bus,
+            entry.end_bus,
+            { entry.base_address }
+        );
+    }
+}
+
+fn pci_class_name(class: u8) -> &'static str {
+    match class {
+        0x01 => "Storage",
+        0x02 => "Network",
+        0x03 => "Display",
+        0x04 => "Multimedia",
+        0x05 => "Memory",
+        0x06 => "Bridge",
+        0x07 => "Communication",
+        0x08 => "System",
+        0x09 => "Input",
+        0x0C => "Serial Bus",
+        _ => "Other",
+    }
+}
+
+// One line per function, with its BARs and capabilities below it given -v
+fn list_pci(args: &mut Parameter) {
+    let verbose = args.next() == Some("-v");
+    let count = pci::device_count();
+    if count == 0 {
+        println!("No PCI Devices Found");
+        return;
+    }
+    for index in 0..count {
+        let device = match pci::get_device(index) {
+            Some(device) => device,
+            None => break,
+        };
+        if index != 0 && (index % 10) == 0 {
+            print!("Press any key to continue ('q' is exit)");
+            if getch() == b'q' {
+                println!();
+                return;
+            }
+            println!();
+        }
+        let address = device.address;
+        println!(
+            "{:04X}:{:02X}:{:02X}.{} [{:04X}:{:04X}] Class[{:02X}:{:02X}:{:02X}] Rev[{:02X}] IRQ[{}] {}",
+            address.segment,
+            address.bus,
+            address.device,
+            address.function,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if,
+            device.revision,
+            device.interrupt_line,
+            pci_class_name(device.class)
+        );
+        if !verbose {
+            continue;
+        }
+        for (bar_index, bar) in device.bars.iter().enumerate() {
+            match *bar {
+                PciBar::None => {}
+                PciBar::Io { port, size } => {
+                    println!("  BAR{} I/O[0x{:X}] Size[0x{:X}]", bar_index, port, size)
+                }
+                PciBar::Memory { address, size, prefetchable, is_64bit } => println!(
+                    "  BAR{} Memory[0x{:X}] Size[0x{:X}]{}{}",
+                    bar_index,
+                    address,
+                    size,
+                    if is_64bit { " 64-Bit" } else { "" },
+                    if prefetchable { " Prefetchable" } else { "" }
+                ),
+            }
+        }
+        if !device.capabilities().is_empty() {
+            print!("  Capabilities");
+            for (id, offset) in device.capabilities() {
+                print!("[{:02X}@0x{:X}]", id, offset);
+            }
+            println!();
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use core::{
+    hint,
+    ptr::addr_of,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use crate::{
+    acpi, apic, assembly, descriptor, fpu,
+    memory::{alloc_frames, FRAME_SIZE},
+    percpu::{self, init_percpu},
+    process, syscall, timer,
+    utility::memcpy,
+};
+
+pub const CPU_MAXCOUNT: usize = 16;
+
+// The startup IPI starts a core in real mode at the page its vector names
+const AP_TRAMPOLINEADDRESS: u64 = 0x8000;
+const AP_STACKFRAMECOUNT: u64 = 4;
+const AP_INITDELAYMS: u64 = 10;
+const AP_STARTUPDELAYMS: u64 = 1;
+const AP_ONLINETIMEOUTMS: u64 = 100;
+const AP_TIMERCALIBRATIONMS: u64 = 10;
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_cpu: u8;
+}
+
+// Cores are numbered in the order they were started, the bootstrap processor
+// being 0
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+// Local APIC timer counts in a PIT tick, measured once on the bootstrap
+// processor since every core runs off the same bus clock
+static LOCAL_TIMER_TICKCOUNT: AtomicU32 = AtomicU32::new(0);
+
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+pub fn online_count() -> usize {
+    (0..cpu_count()).filter(|&cpu| is_online(cpu)).count()
+}
+
+// Bits of the cores that are online
+pub fn online_mask() -> u64 {
+    (0..cpu_count())
+        .filter(|&cpu| is_online(cpu))
+        .fold(0, |mask, cpu| mask | 1 << cpu)
+}
+
+pub fn is_online(cpu: usize) -> bool {
+    percpu::get(cpu).is_online()
+}
+
+pub fn get_apic_id(cpu: usize) -> u8 {
+    percpu::get(cpu).apic_id()
+}
+
+pub fn current_cpu() -> usize {
+    percpu::current_cpu()
+}
+
+// Where a variable of the trampoline ended up in the copy below 1MB
+unsafe fn trampoline_variable(variable: *const u8) -> *mut u64 {
+    let start = addr_of!(ap_trampoline_start) as u64;
+    (variable as u64 - start + AP_TRAMPOLINEADDRESS) as *mut u64
+}
+
+// Busy waits on the tick count, so interrupts have to be enabled
+fn delay(millisecond: u64) {
+    let start = timer::get_tick_count();
+    while timer::get_tick_count() - start < millisecond {
+        hint::spin_loop();
+    }
+}
+
+fn calibrate_local_timer() {
+    let start = timer::get_tick_count();
+    while timer::get_tick_count() == start {
+        hint::spin_loop();
+    }
+    apic::start_local_timer(u32::MAX, false);
+    delay(AP_TIMERCALIBRATIONMS);
+    let elapsed = u32::MAX - apic::get_local_timer_count();
+    apic::stop_local_timer();
+    LOCAL_TIMER_TICKCOUNT.store((elapsed / AP_TIMERCALIBRATIONMS as u32).max(1), Ordering::Relaxed);
+}
+
+// Wakes every enabled processor of the MADT one at a time and returns the
+// number of cores online
+pub fn start_application_processors() -> usize {
+    let bootstrap = apic::local_apic_id();
+    percpu::get(0).set_apic_id(bootstrap);
+    percpu::get(0).set_online();
+    apic::enable_local_apic(true);
+    calibrate_local_timer();
+
+    unsafe {
+        let start = addr_of!(ap_trampoline_start);
+        let size = addr_of!(ap_trampoline_end) as u64 - start as u64;
+        memcpy(AP_TRAMPOLINEADDRESS as *mut u8, start, size as isize);
+        *trampoline_variable(addr_of!(ap_trampoline_entry)) = ap_entry as u64;
+    }
+
+    for apic_id in acpi::local_apic_ids() {
+        let cpu = cpu_count();
+        if cpu == CPU_MAXCOUNT {
+            break;
+        }
+        if apic_id != bootstrap && start_processor(cpu, apic_id).is_ok() {
+            CPU_COUNT.store(cpu + 1, Ordering::Release);
+        }
+    }
+    online_count()
+}
+
+// INIT puts the core in wait-for-SIPI state. A second startup IPI is only
+// sent when the first one went missing. Fails only before any IPI is sent,
+// so that the index can go to the next core. A core that misses the timeout
+// may still come up late on its stack and per-CPU area, so it is put back
+// into reset and both stay retired with it; it is left counted as offline.
+fn start_processor(cpu: usize, apic_id: u8) -> Result<(), ()> {
+    let stack = alloc_frames(AP_STACKFRAMECOUNT).ok_or(())?;
+    unsafe {
+        *trampoline_variable(addr_of!(ap_trampoline_stack)) = stack + AP_STACKFRAMECOUNT * FRAME_SIZE;
+        *trampoline_variable(addr_of!(ap_trampoline_cpu)) = cpu as u64;
+    }
+    percpu::get(cpu).set_apic_id(apic_id);
+
+    let vector = (AP_TRAMPOLINEADDRESS / FRAME_SIZE) as u8;
+    apic::send_init(apic_id);
+    delay(AP_INITDELAYMS);
+    apic::send_startup(apic_id, vector);
+    delay(AP_STARTUPDELAYMS);
+    if !is_online(cpu) {
+        apic::send_startup(apic_id, vector);
+    }
+
+    let start = timer::get_tick_count();
+    while !is_online(cpu) && timer::get_tick_count() - start < AP_ONLINETIMEOUTMS {
+        hint::spin_loop();
+    }
+    if !is_online(cpu) {
+        apic::send_init(apic_id);
+    }
+    Ok(())
+}
+
+// The trampoline lands here in IA-32e mode on the stack it was given. The
+// core loads the tables the bootstrap processor built, with a TSS of its own,
+// and then becomes its own idle task.
+extern "C" fn ap_entry(cpu: u64) -> ! {
+    let cpu = cpu as usize;
+    init_percpu(cpu);
+    assembly::LoadGDTR(descriptor::GDTR_STARTADDRESS);
+    assembly::LoadTR(descriptor::get_tss_segment(cpu));
+    assembly::LoadIDTR(descriptor::IDTR_STARTADDRESS);
+    syscall::init_syscall();
+    fpu::init_FPU_unit();
+    apic::enable_local_apic(false);
+    process::init_idle_task(cpu);
+    percpu::current().set_online();
+
+    apic::start_local_timer(LOCAL_TIMER_TICKCOUNT.load(Ordering::Relaxed), true);
+    assembly::EnableInterrupt();
+    loop {
+        process::idle_process();
+    }
+}
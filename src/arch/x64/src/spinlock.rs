@@ -0,0 +1,225 @@
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    hint,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+    assembly::read_TSC,
+    console::report_line,
+    percpu,
+    trace::{self, TraceKind},
+    utility::set_interrupt_flag,
+};
+
+// Holding or waiting for a lock longer than this many TSC cycles, about a
+// tenth of a second on the machines we run on, is reported in debug mode
+const SPINLOCK_HOLDLIMITCYCLES: u64 = 200_000_000;
+const SPINLOCK_NOHOLDER: usize = usize::MAX;
+// Screen row reports are written to. They run with interrupts disabled and
+// may be about the writer's own lock, so they go around it.
+const SPINLOCK_REPORTLINE: i32 = 21;
+
+static SPINLOCK_DEBUG: AtomicBool = AtomicBool::new(false);
+static SPINLOCK_REPORTCOUNT: AtomicU64 = AtomicU64::new(0);
+
+// Ticket lock that keeps interrupts disabled on the core while it is held, so
+// an interrupt handler taking the same lock cannot deadlock against the code
+// it interrupted. Waiters are served in the order they arrived.
+pub struct SpinLock<T> {
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    // Only kept up to date in debug mode
+    holder_cpu: AtomicUsize,
+    holder_rip: AtomicU64,
+    acquired_tsc: AtomicU64,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+// Gives the lock up and then restores the interrupt flag the core had before
+// taking it. Guards of nested locks have to be dropped in the reverse order
+// they were taken, or interrupts come back on while the inner one is held.
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    previous_flag: bool,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            holder_cpu: AtomicUsize::new(SPINLOCK_NOHOLDER),
+            holder_rip: AtomicU64::new(0),
+            acquired_tsc: AtomicU64::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Inlined so that the RIP recorded in debug mode is the caller's
+    #[inline(always)]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let rip = current_rip();
+        let previous_flag = set_interrupt_flag(false);
+        let debug = is_debug();
+        if debug {
+            self.check_recursive(rip);
+        }
+
+        // Counted from before the ticket is drawn, since a core waiting in
+        // line can no more walk away from the lock than its holder
+        percpu::current().lock_count.fetch_add(1, Ordering::Relaxed);
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        if self.now_serving.load(Ordering::Acquire) != ticket {
+            self.wait(ticket, debug);
+        }
+
+        if debug {
+            self.holder_cpu.store(percpu::current_cpu(), Ordering::Relaxed);
+            self.holder_rip.store(rip, Ordering::Relaxed);
+            self.acquired_tsc.store(read_TSC(), Ordering::Relaxed);
+        }
+        SpinLockGuard { lock: self, previous_flag }
+    }
+
+    #[inline(always)]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let rip = current_rip();
+        let previous_flag = set_interrupt_flag(false);
+        let lock_count = &percpu::current().lock_count;
+        lock_count.fetch_add(1, Ordering::Relaxed);
+        let ticket = self.now_serving.load(Ordering::Relaxed);
+        if self
+            .next_ticket
+            .compare_exchange(ticket, ticket.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            lock_count.fetch_sub(1, Ordering::Relaxed);
+            set_interrupt_flag(previous_flag);
+            return None;
+        }
+
+        if is_debug() {
+            self.holder_cpu.store(percpu::current_cpu(), Ordering::Relaxed);
+            self.holder_rip.store(rip, Ordering::Relaxed);
+            self.acquired_tsc.store(read_TSC(), Ordering::Relaxed);
+        }
+        Some(SpinLockGuard { lock: self, previous_flag })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    // Interrupts are already off, so a holder on this core can never let go
+    #[inline(never)]
+    fn check_recursive(&self, rip: u64) {
+        if self.is_locked() && self.holder_cpu.load(Ordering::Relaxed) == percpu::current_cpu() {
+            panic!(
+                "[SPINLOCK] Recursive Acquire Of Lock[0x{:X}] At RIP[0x{:X}], Held Since RIP[0x{:X}]",
+                self as *const Self as u64,
+                rip,
+                self.holder_rip.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    #[inline(never)]
+    fn wait(&self, ticket: u32, debug: bool) {
+        let start = read_TSC();
+        let mut reported = false;
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            if debug && !reported && read_TSC() - start > SPINLOCK_HOLDLIMITCYCLES {
+                reported = true;
+                self.report("Waited On", read_TSC() - start);
+            }
+            hint::spin_loop();
+        }
+        trace::record(TraceKind::LockWait, self as *const Self as u64, read_TSC() - start);
+    }
+
+    fn report(&self, what: &str, cycles: u64) {
+        SPINLOCK_REPORTCOUNT.fetch_add(1, Ordering::Relaxed);
+        report_line(
+            SPINLOCK_REPORTLINE,
+            format_args!(
+                "[SPINLOCK] Core[{}] {} Lock[0x{:X}] For {} Cycles, Holder Core[{}] RIP[0x{:X}]",
+                percpu::current_cpu(),
+                what,
+                self as *const Self as u64,
+                cycles,
+                self.holder_cpu.load(Ordering::Relaxed) as isize,
+                self.holder_rip.load(Ordering::Relaxed)
+            ),
+        );
+    }
+
+    fn unlock(&self) {
+        // Locks taken before debug mode was switched on have no start
+        let acquired_tsc = self.acquired_tsc.load(Ordering::Relaxed);
+        if is_debug() && acquired_tsc != 0 {
+            let held = read_TSC().wrapping_sub(acquired_tsc);
+            if held > SPINLOCK_HOLDLIMITCYCLES {
+                self.report("Held", held);
+            }
+        }
+        // Cleared even with debug mode off, so that a holder left over from
+        // before it was switched off is never taken for the current one
+        self.holder_cpu.store(SPINLOCK_NOHOLDER, Ordering::Relaxed);
+        self.acquired_tsc.store(0, Ordering::Relaxed);
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        self.now_serving.store(serving.wrapping_add(1), Ordering::Release);
+        percpu::current().lock_count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+        set_interrupt_flag(self.previous_flag);
+    }
+}
+
+#[inline(always)]
+fn current_rip() -> u64 {
+    let rip: u64;
+    unsafe { asm!("lea {}, [rip]", out(reg) rip, options(nomem, nostack, preserves_flags)) };
+    rip
+}
+
+pub fn is_debug() -> bool {
+    SPINLOCK_DEBUG.load(Ordering::Relaxed)
+}
+
+pub fn set_debug(enable: bool) {
+    SPINLOCK_DEBUG.store(enable, Ordering::Relaxed);
+}
+
+// Spin locks the running core holds or is waiting for
+pub fn held_count() -> u64 {
+    percpu::current().lock_count.load(Ordering::Relaxed)
+}
+
+// Times debug mode caught a lock held or waited on for too long
+pub fn get_report_count() -> u64 {
+    SPINLOCK_REPORTCOUNT.load(Ordering::Relaxed)
+}
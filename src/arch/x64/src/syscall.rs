@@ -0,0 +1,341 @@
+use core::{mem::size_of, str};
+
+use crate::{
+    assembly::{read_MSR, write_MSR},
+    descriptor::{GDT_KERNELCODESEGMENT, GDT_KERNELDATASEGMENT},
+    channel::{self, Message},
+    console, page,
+    percpu::{PERCPU_KERNELSTACKOFFSET, PERCPU_USERSTACKOFFSET},
+    print,
+    process::{self, PRIORITY_LOWIST, PROCESS_FLAG_USER},
+};
+
+const MSR_IA32_EFER: u32 = 0xC0000080;
+const MSR_IA32_STAR: u32 = 0xC0000081;
+const MSR_IA32_LSTAR: u32 = 0xC0000082;
+const MSR_IA32_FMASK: u32 = 0xC0000084;
+
+const EFER_SCE: u64 = 0x01;
+
+// IF, TF, DF and AC are cleared on entry
+const SYSCALL_FLAGMASK: u64 = 0x0200 | 0x0100 | 0x0400 | 0x40000;
+
+pub const SYSCALL_EXIT: u64 = 0;
+pub const SYSCALL_YIELD: u64 = 1;
+pub const SYSCALL_SLEEP: u64 = 2;
+pub const SYSCALL_GETPID: u64 = 3;
+pub const SYSCALL_WRITE: u64 = 4;
+pub const SYSCALL_READKEY: u64 = 5;
+pub const SYSCALL_CREATETASK: u64 = 6;
+pub const SYSCALL_CHANNELCREATE: u64 = 7;
+pub const SYSCALL_CHANNELSEND: u64 = 8;
+pub const SYSCALL_CHANNELTRYSEND: u64 = 9;
+pub const SYSCALL_CHANNELRECEIVE: u64 = 10;
+pub const SYSCALL_CHANNELTRYRECEIVE: u64 = 11;
+pub const SYSCALL_CHANNELSHARE: u64 = 12;
+pub const SYSCALL_CHANNELCLOSE: u64 = 13;
+pub const SYSCALL_SIGNAL: u64 = 14;
+pub const SYSCALL_SETREALTIME: u64 = 15;
+pub const SYSCALL_WAITPERIOD: u64 = 16;
+
+pub const SYSCALL_ERROR: u64 = 0xFFFFFFFFFFFFFFFF;
+
+const SYSCALL_MAXWRITESIZE: u64 = 4096;
+
+type SyscallFunc = fn(u64, u64, u64, u64, u64) -> u64;
+
+struct Syscall {
+    pub number: u64,
+    pub function: SyscallFunc,
+}
+
+static SYSCALL_TABLE: &[Syscall] = &[
+    Syscall {
+        number: SYSCALL_EXIT,
+        function: sys_exit,
+    },
+    Syscall {
+        number: SYSCALL_YIELD,
+        function: sys_yield,
+    },
+    Syscall {
+        number: SYSCALL_SLEEP,
+        function: sys_sleep,
+    },
+    Syscall {
+        number: SYSCALL_GETPID,
+        function: sys_getpid,
+    },
+    Syscall {
+        number: SYSCALL_WRITE,
+        function: sys_write,
+    },
+    Syscall {
+        number: SYSCALL_READKEY,
+        function: sys_read_key,
+    },
+    Syscall {
+        number: SYSCALL_CREATETASK,
+        function: sys_create_task,
+    },
+    Syscall {
+        number: SYSCALL_CHANNELCREATE,
+        function: sys_channel_create,
+    },
+    Syscall {
+        number: SYSCALL_CHANNELSEND,
+        function: sys_channel_send,
+    },
+    Syscall {
+        number: SYSCALL_CHANNELTRYSEND,
+        function: sys_channel_try_send,
+    },
+    Syscall {
+        number: SYSCALL_CHANNELRECEIVE,
+        function: sys_channel_receive,
+    },
+    Syscall {
+        number: SYSCALL_CHANNELTRYRECEIVE,
+        function: sys_channel_try_receive,
+    },
+    Syscall {
+        number: SYSCALL_CHANNELSHARE,
+        function: sys_channel_share,
+    },
+    Syscall {
+        number: SYSCALL_CHANNELCLOSE,
+        function: sys_channel_close,
+    },
+    Syscall {
+        number: SYSCALL_SIGNAL,
+        function: sys_signal,
+    },
+    Syscall {
+        number: SYSCALL_SETREALTIME,
+        function: sys_set_realtime,
+    },
+    Syscall {
+        number: SYSCALL_WAITPERIOD,
+        function: sys_wait_period,
+    },
+];
+
+pub fn init_syscall() {
+    write_MSR(MSR_IA32_EFER, read_MSR(MSR_IA32_EFER) | EFER_SCE);
+    // SYSRET loads CS from STAR[63:48] + 16 and SS from STAR[63:48] + 8,
+    // which are the user code and user data descriptors
+    let star = (GDT_KERNELCODESEGMENT as u64) << 32 | (GDT_KERNELDATASEGMENT as u64) << 48;
+    write_MSR(MSR_IA32_STAR, star);
+    write_MSR(MSR_IA32_LSTAR, syscall_entry as u64);
+    write_MSR(MSR_IA32_FMASK, SYSCALL_FLAGMASK);
+}
+
+// RAX holds the syscall number, RDI, RSI, RDX, R10 and R8 the arguments.
+// The result comes back in RAX; only RCX and R11 are clobbered besides it.
+// The kernel stack is found through the per-CPU area, since the task may be
+// moved to another core while it is in the kernel.
+#[naked]
+pub fn syscall_entry() {
+    use core::arch::asm;
+    unsafe {
+        asm!(
+        "swapgs",
+        "mov gs:[{user_stack}], rsp",
+        "mov rsp, gs:[{kernel_stack}]",
+        "push qword ptr gs:[{user_stack}]",
+        "push r11",
+        "push rcx",
+        "push rbp",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push r8",
+        "push r9",
+        "push r10",
+        "sti",
+
+        "mov rcx, r10",
+        "mov r9, rax",
+        "call {func}",
+
+        "cli",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rbp",
+        "pop rcx",
+        "pop r11",
+        "pop rsp",
+        "swapgs",
+        "sysretq",
+        user_stack = const PERCPU_USERSTACKOFFSET,
+        kernel_stack = const PERCPU_KERNELSTACKOFFSET,
+        func = sym syscall_handler,
+        options(noreturn));
+    }
+}
+
+extern "C" fn syscall_handler(
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+    number: u64,
+) -> u64 {
+    for syscall in SYSCALL_TABLE {
+        if syscall.number == number {
+            return (syscall.function)(arg1, arg2, arg3, arg4, arg5);
+        }
+    }
+    SYSCALL_ERROR
+}
+
+// Anything handed in from ring 3 has to lie in memory ring 3 may touch
+fn user_buffer<'a>(address: u64, size: u64) -> Result<&'a [u8], ()> {
+    if size == 0 {
+        return Ok(&[]);
+    }
+    if !page::is_user_accessible(address, size) {
+        return Err(());
+    }
+    Ok(unsafe { core::slice::from_raw_parts(address as *const u8, size as usize) })
+}
+
+fn sys_exit(exit_code: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    process::exit(exit_code);
+    SYSCALL_ERROR
+}
+
+fn sys_yield(_: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    process::yield_next();
+    0
+}
+
+fn sys_sleep(millisecond: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    process::sleep(millisecond);
+    0
+}
+
+fn sys_getpid(_: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    process::get_pid()
+}
+
+fn sys_write(address: u64, size: u64, _: u64, _: u64, _: u64) -> u64 {
+    if size > SYSCALL_MAXWRITESIZE {
+        return SYSCALL_ERROR;
+    }
+    match user_buffer(address, size) {
+        Ok(buffer) => match str::from_utf8(buffer) {
+            Ok(string) => {
+                print!("{}", string);
+                size
+            }
+            Err(_) => SYSCALL_ERROR,
+        },
+        Err(()) => SYSCALL_ERROR,
+    }
+}
+
+fn sys_read_key(_: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    console::getch() as u64
+}
+
+fn sys_create_task(entry: u64, arg: u64, priority: u64, _: u64, _: u64) -> u64 {
+    if !page::is_user_accessible(entry, 1) || priority > PRIORITY_LOWIST {
+        return SYSCALL_ERROR;
+    }
+    let name = process::get_process_from_id(process::get_pid()).unwrap().name();
+    match process::create_thread(name, priority | PROCESS_FLAG_USER, entry, arg) {
+        Ok(pid) => pid,
+        Err(()) => SYSCALL_ERROR,
+    }
+}
+
+fn sys_channel_create(capacity: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    channel::create_handle(capacity).unwrap_or(SYSCALL_ERROR)
+}
+
+fn sys_channel_send(handle: u64, data: u64, _: u64, _: u64, _: u64) -> u64 {
+    match channel::get_channel(handle).and_then(|id| channel::send(id, data)) {
+        Ok(()) => 0,
+        Err(()) => SYSCALL_ERROR,
+    }
+}
+
+fn sys_channel_try_send(handle: u64, data: u64, _: u64, _: u64, _: u64) -> u64 {
+    match channel::get_channel(handle).and_then(|id| channel::try_send(id, data)) {
+        Ok(()) => 0,
+        Err(()) => SYSCALL_ERROR,
+    }
+}
+
+// The message is stored to the Message sized buffer at `address`
+fn write_message(address: u64, message: Result<Message, ()>) -> u64 {
+    match message {
+        Ok(message) => {
+            unsafe { core::ptr::write_unaligned(address as *mut Message, message) };
+            0
+        }
+        Err(()) => SYSCALL_ERROR,
+    }
+}
+
+fn sys_channel_receive(handle: u64, address: u64, _: u64, _: u64, _: u64) -> u64 {
+    if !page::is_user_writable(address, size_of::<Message>() as u64) {
+        return SYSCALL_ERROR;
+    }
+    write_message(address, channel::get_channel(handle).and_then(channel::receive))
+}
+
+fn sys_channel_try_receive(handle: u64, address: u64, _: u64, _: u64, _: u64) -> u64 {
+    if !page::is_user_writable(address, size_of::<Message>() as u64) {
+        return SYSCALL_ERROR;
+    }
+    write_message(address, channel::get_channel(handle).and_then(channel::try_receive))
+}
+
+fn sys_channel_share(handle: u64, pid: u64, _: u64, _: u64, _: u64) -> u64 {
+    channel::share_handle(handle, pid).unwrap_or(SYSCALL_ERROR)
+}
+
+fn sys_channel_close(handle: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    match channel::close_handle(handle) {
+        Ok(()) => 0,
+        Err(()) => SYSCALL_ERROR,
+    }
+}
+
+fn sys_signal(pid: u64, signal: u64, _: u64, _: u64, _: u64) -> u64 {
+    if !process::can_signal(process::get_pid(), pid) {
+        return SYSCALL_ERROR;
+    }
+    match process::send_signal(pid, signal) {
+        Ok(()) => 0,
+        Err(()) => SYSCALL_ERROR,
+    }
+}
+
+// Applies to the calling task; a period of 0 leaves the deadline class
+fn sys_set_realtime(period: u64, runtime: u64, deadline: u64, _: u64, _: u64) -> u64 {
+    let pid = process::get_pid();
+    let result = if period == 0 {
+        process::clear_realtime(pid)
+    } else {
+        process::set_realtime(pid, period, runtime, deadline)
+    };
+    match result {
+        Ok(()) => 0,
+        Err(()) => SYSCALL_ERROR,
+    }
+}
+
+fn sys_wait_period(_: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    match process::wait_next_period() {
+        Ok(()) => 0,
+        Err(()) => SYSCALL_ERROR,
+    }
+}
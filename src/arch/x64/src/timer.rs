@@ -1,6 +1,6 @@
 use crate::{
     assembly::{InPortByte, OutPortByte},
-    print,
+    print, watchdog,
 };
 
 const PIT_FREQUENCY: u64 = 1193180;
@@ -24,6 +24,84 @@ const PIT_COUNTER0_PERIODIC: u8 =
     PIT_CONTROL_COUNTER0 | PIT_CONTROL_LSBMSBRW | PIT_CONTROL_MODE2 | PIT_CONTROL_BINARYCOUNTER;
 const PIT_COUNTER0_LATCH: u8 = PIT_CONTROL_COUNTER0 | PIT_CONTROL_LATCH;
 
+// PIT counts in one tick of the periodic timer
+const PIT_TICKCOUNT: u64 = PIT_FREQUENCY / 1000;
+const PIT_MAXCOUNT: u64 = 0xFFFF;
+
+static mut TICK_COUNT: u64 = 0;
+static mut INTERRUPT_COUNT: u64 = 0;
+static mut TICKLESS_ENABLED: bool = true;
+// Length of the one-shot in flight in PIT counts, 0 while ticking
+// periodically, and the counts left over from the last one
+static mut ONESHOT_COUNT: u64 = 0;
+static mut ONESHOT_REMAINDER: u64 = 0;
+
+// Called on every timer interrupt; returns the ticks that have passed, which
+// is more than one when the interrupt ends a one-shot
+pub fn increase_tick_count() -> u64 {
+    unsafe {
+        INTERRUPT_COUNT += 1;
+        if ONESHOT_COUNT != 0 {
+            return finish_oneshot(ONESHOT_COUNT);
+        }
+        TICK_COUNT += 1;
+    }
+    1
+}
+
+pub fn get_interrupt_count() -> u64 {
+    unsafe { INTERRUPT_COUNT }
+}
+
+pub fn set_tickless(enable: bool) {
+    unsafe { TICKLESS_ENABLED = enable };
+}
+
+pub fn is_tickless() -> bool {
+    unsafe { TICKLESS_ENABLED }
+}
+
+// Replaces the periodic tick with a single interrupt `tick` ticks from now,
+// or as far as the 16 bit counter reaches. Interrupts must be disabled.
+pub fn start_oneshot(tick: u64) {
+    let count = tick.saturating_mul(PIT_TICKCOUNT).clamp(PIT_TICKCOUNT, PIT_MAXCOUNT);
+    unsafe { ONESHOT_COUNT = count };
+    init_PIT(count as u16, false);
+}
+
+// Goes back to the periodic tick when an interrupt other than the timer ended
+// the halt; returns the ticks that passed. Interrupts must be disabled.
+pub fn stop_oneshot() -> u64 {
+    unsafe {
+        if ONESHOT_COUNT == 0 {
+            return 0;
+        }
+        // The counter keeps going down past zero, so it wraps once it has fired
+        let remaining = read_counter0() as u64;
+        let elapsed = if remaining > ONESHOT_COUNT {
+            ONESHOT_COUNT
+        } else {
+            ONESHOT_COUNT - remaining
+        };
+        finish_oneshot(elapsed)
+    }
+}
+
+unsafe fn finish_oneshot(elapsed: u64) -> u64 {
+    let count = elapsed + ONESHOT_REMAINDER;
+    let ticks = count / PIT_TICKCOUNT;
+    ONESHOT_REMAINDER = count % PIT_TICKCOUNT;
+    ONESHOT_COUNT = 0;
+    TICK_COUNT += ticks;
+    init_PIT(PIT_TICKCOUNT as u16, true);
+    ticks
+}
+
+// Number of timer interrupts since boot, one per millisecond with the default PIT setting
+pub fn get_tick_count() -> u64 {
+    unsafe { TICK_COUNT }
+}
+
 pub fn convert_from_ms(time: u64) -> u64 {
     PIT_FREQUENCY * time / 1000
 }
@@ -57,6 +135,8 @@ pub fn wait_using_PIT(count: u16) {
             break;
         }
     }
+    // Busy waits run with interrupts disabled, so the tick count stands still
+    watchdog::touch();
 }
 
 pub fn wait(milisecond: u64) {
@@ -76,6 +156,34 @@ const RTC_ADDRESS_DAYOFWEEK: u8 = 0x06;
 const RTC_ADDRESS_DAYOFMONTH: u8 = 0x07;
 const RTC_ADDRESS_MONTH: u8 = 0x08;
 const RTC_ADDRESS_YEAR: u8 = 0x09;
+const RTC_ADDRESS_STATUSA: u8 = 0x0A;
+const RTC_ADDRESS_STATUSB: u8 = 0x0B;
+const RTC_ADDRESS_STATUSC: u8 = 0x0C;
+
+const RTC_STATUSA_RATEMASK: u8 = 0x0F;
+const RTC_STATUSB_PERIODIC: u8 = 0x40;
+const RTC_STATUSC_PERIODIC: u8 = 0x40;
+
+// Starts the RTC periodic interrupt at 32768 >> (rate - 1) Hz, rate 3 to 15
+pub fn enable_rtc_periodic(rate: u8) {
+    OutPortByte(RTC_CMOSADDRESS, RTC_ADDRESS_STATUSA);
+    let status = InPortByte(RTC_CMOSDATA);
+    OutPortByte(RTC_CMOSADDRESS, RTC_ADDRESS_STATUSA);
+    OutPortByte(RTC_CMOSDATA, (status & !RTC_STATUSA_RATEMASK) | (rate & RTC_STATUSA_RATEMASK));
+
+    OutPortByte(RTC_CMOSADDRESS, RTC_ADDRESS_STATUSB);
+    let status = InPortByte(RTC_CMOSDATA);
+    OutPortByte(RTC_CMOSADDRESS, RTC_ADDRESS_STATUSB);
+    OutPortByte(RTC_CMOSDATA, status | RTC_STATUSB_PERIODIC);
+    acknowledge_rtc();
+}
+
+// Reading status C lets the RTC raise its next interrupt; returns whether
+// the periodic interrupt was the cause
+pub fn acknowledge_rtc() -> bool {
+    OutPortByte(RTC_CMOSADDRESS, RTC_ADDRESS_STATUSC);
+    InPortByte(RTC_CMOSDATA) & RTC_STATUSC_PERIODIC != 0
+}
 
 #[repr(C, packed(1))]
 pub struct Date {
@@ -0,0 +1,229 @@
+use core::{
+    fmt::{self, Write},
+    hint, ptr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{
+    assembly::read_TSC,
+    memory::{alloc_frames, FRAME_SIZE},
+    process,
+    serial::SERIAL,
+    timer,
+};
+
+const TRACE_EVENTCOUNT: u64 = 8192;
+const TRACE_CALIBRATIONMS: u64 = 10;
+
+// Chrome trace thread IDs for events that do not belong to a task. Task IDs
+// always have a generation in their upper half, so they never collide.
+const TRACE_TID_INTERRUPT: u64 = 0;
+const TRACE_TID_LOCK: u64 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TraceKind {
+    Switch = 1,
+    IrqEnter = 2,
+    IrqExit = 3,
+    TaskCreate = 4,
+    TaskExit = 5,
+    LockWait = 6,
+}
+
+// A writer claims a slot by bumping TRACE_HEAD, clears its sequence while it
+// fills the slot in and then publishes it with the claimed index plus one.
+// Readers only trust a slot whose sequence matches before and after copying.
+#[repr(C)]
+struct TraceEvent {
+    sequence: AtomicU64,
+    timestamp: u64,
+    kind: u64,
+    arg0: u64,
+    arg1: u64,
+}
+
+static TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+static TRACE_BUFFER: AtomicU64 = AtomicU64::new(0);
+static TRACE_HEAD: AtomicU64 = AtomicU64::new(0);
+static TRACE_STARTTSC: AtomicU64 = AtomicU64::new(0);
+static TRACE_TSCPERMS: AtomicU64 = AtomicU64::new(1);
+
+pub fn record(kind: TraceKind, arg0: u64, arg1: u64) {
+    if !TRACE_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let buffer = TRACE_BUFFER.load(Ordering::Acquire) as *mut TraceEvent;
+    if buffer.is_null() {
+        return;
+    }
+    let index = TRACE_HEAD.fetch_add(1, Ordering::Relaxed);
+    let event = unsafe { &mut *buffer.add((index % TRACE_EVENTCOUNT) as usize) };
+    event.sequence.store(0, Ordering::Release);
+    unsafe {
+        ptr::write_volatile(&mut event.timestamp, read_TSC());
+        ptr::write_volatile(&mut event.kind, kind as u64);
+        ptr::write_volatile(&mut event.arg0, arg0);
+        ptr::write_volatile(&mut event.arg1, arg1);
+    }
+    event.sequence.store(index + 1, Ordering::Release);
+}
+
+// Measures the TSC against the timer tick so timestamps can be exported in
+// microseconds; needs interrupts enabled
+fn calibrate() -> u64 {
+    let tick = timer::get_tick_count();
+    while timer::get_tick_count() == tick {
+        hint::spin_loop();
+    }
+    let start_tick = timer::get_tick_count();
+    let start_tsc = read_TSC();
+    while timer::get_tick_count() - start_tick < TRACE_CALIBRATIONMS {
+        hint::spin_loop();
+    }
+    ((read_TSC() - start_tsc) / TRACE_CALIBRATIONMS).max(1)
+}
+
+pub fn start() -> Result<(), ()> {
+    if TRACE_BUFFER.load(Ordering::Acquire) == 0 {
+        let size = TRACE_EVENTCOUNT * core::mem::size_of::<TraceEvent>() as u64;
+        let buffer = alloc_frames((size + FRAME_SIZE - 1) / FRAME_SIZE).ok_or(())?;
+        TRACE_BUFFER.store(buffer, Ordering::Release);
+    }
+    TRACE_TSCPERMS.store(calibrate(), Ordering::Relaxed);
+    TRACE_HEAD.store(0, Ordering::Relaxed);
+    TRACE_STARTTSC.store(read_TSC(), Ordering::Relaxed);
+    TRACE_ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+pub fn stop() {
+    TRACE_ENABLED.store(false, Ordering::Release);
+}
+
+pub fn is_enabled() -> bool {
+    TRACE_ENABLED.load(Ordering::Relaxed)
+}
+
+struct Timestamp(u64);
+
+// Microseconds since `trace start`, with nanoseconds as the fraction
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let start = TRACE_STARTTSC.load(Ordering::Relaxed);
+        let tsc_per_ms = TRACE_TSCPERMS.load(Ordering::Relaxed) as u128;
+        let nanosecond = (self.0.saturating_sub(start) as u128 * 1000000 / tsc_per_ms) as u64;
+        write!(f, "{}.{:03}", nanosecond / 1000, nanosecond % 1000)
+    }
+}
+
+fn write_escaped(output: &mut impl Write, string: &str) -> fmt::Result {
+    for character in string.chars() {
+        match character {
+            '"' | '\\' => write!(output, "\\{}", character)?,
+            ' '..='~' => output.write_char(character)?,
+            _ => write!(output, "\\u{:04x}", character as u32)?,
+        }
+    }
+    Ok(())
+}
+
+fn write_thread_name(output: &mut impl Write, tid: u64, name: &str) -> fmt::Result {
+    write!(
+        output,
+        "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":{},\"args\":{{\"name\":\"",
+        tid
+    )?;
+    write_escaped(output, name)?;
+    write!(output, "\"}}}}")
+}
+
+fn write_event(output: &mut impl Write, kind: u64, timestamp: u64, arg0: u64, arg1: u64) -> fmt::Result {
+    let ts = Timestamp(timestamp);
+    match kind {
+        k if k == TraceKind::Switch as u64 => write!(
+            output,
+            "{{\"name\":\"run\",\"ph\":\"E\",\"pid\":0,\"tid\":{},\"ts\":{}}},\n\
+             {{\"name\":\"run\",\"ph\":\"B\",\"pid\":0,\"tid\":{},\"ts\":{}}}",
+            arg0, ts, arg1, ts
+        ),
+        k if k == TraceKind::IrqEnter as u64 || k == TraceKind::IrqExit as u64 => write!(
+            output,
+            "{{\"name\":\"IRQ {}\",\"ph\":\"{}\",\"pid\":0,\"tid\":{},\"ts\":{}}}",
+            arg0,
+            if k == TraceKind::IrqEnter as u64 { "B" } else { "E" },
+            TRACE_TID_INTERRUPT,
+            ts
+        ),
+        k if k == TraceKind::TaskCreate as u64 => write!(
+            output,
+            "{{\"name\":\"create\",\"ph\":\"i\",\"s\":\"t\",\"pid\":0,\"tid\":{},\"ts\":{},\
+             \"args\":{{\"parent\":\"0x{:X}\"}}}}",
+            arg0, ts, arg1
+        ),
+        k if k == TraceKind::TaskExit as u64 => write!(
+            output,
+            "{{\"name\":\"exit\",\"ph\":\"i\",\"s\":\"t\",\"pid\":0,\"tid\":{},\"ts\":{},\
+             \"args\":{{\"code\":\"0x{:X}\"}}}}",
+            arg0, ts, arg1
+        ),
+        _ => {
+            let start = Timestamp(timestamp.saturating_sub(arg1));
+            let end = Timestamp(timestamp);
+            write!(
+                output,
+                "{{\"name\":\"lock wait\",\"ph\":\"B\",\"pid\":0,\"tid\":{},\"ts\":{},\
+                 \"args\":{{\"lock\":\"0x{:X}\"}}}},\n\
+                 {{\"name\":\"lock wait\",\"ph\":\"E\",\"pid\":0,\"tid\":{},\"ts\":{}}}",
+                TRACE_TID_LOCK, start, arg0, TRACE_TID_LOCK, end
+            )
+        }
+    }
+}
+
+// Streams the buffer over COM1 as a Chrome trace_event JSON document.
+// Recording is paused meanwhile so the oldest events are not overwritten.
+pub fn dump() -> u64 {
+    let enabled = TRACE_ENABLED.swap(false, Ordering::AcqRel);
+    let buffer = TRACE_BUFFER.load(Ordering::Acquire) as *const TraceEvent;
+    let head = TRACE_HEAD.load(Ordering::Acquire);
+    let mut count = 0;
+
+    let mut serial = SERIAL.lock();
+    let _ = write!(serial, "{{\"traceEvents\":[\n");
+    let _ = write_thread_name(&mut *serial, TRACE_TID_INTERRUPT, "Interrupts");
+    let _ = write!(serial, ",\n");
+    let _ = write_thread_name(&mut *serial, TRACE_TID_LOCK, "Lock Waits");
+    for pid in process::task_ids() {
+        if let Some(task) = process::get_process_from_id(pid) {
+            let _ = write!(serial, ",\n");
+            let _ = write_thread_name(&mut *serial, pid, task.name());
+        }
+    }
+    if !buffer.is_null() {
+        for index in head.saturating_sub(TRACE_EVENTCOUNT)..head {
+            let event = unsafe { &*buffer.add((index % TRACE_EVENTCOUNT) as usize) };
+            if event.sequence.load(Ordering::Acquire) != index + 1 {
+                continue;
+            }
+            let (timestamp, kind, arg0, arg1) = unsafe {
+                (
+                    ptr::read_volatile(&event.timestamp),
+                    ptr::read_volatile(&event.kind),
+                    ptr::read_volatile(&event.arg0),
+                    ptr::read_volatile(&event.arg1),
+                )
+            };
+            if event.sequence.load(Ordering::Acquire) != index + 1 {
+                continue;
+            }
+            let _ = write!(serial, ",\n");
+            let _ = write_event(&mut *serial, kind, timestamp, arg0, arg1);
+            count += 1;
+        }
+    }
+    let _ = write!(serial, "\n]}}\n");
+    drop(serial);
+
+    TRACE_ENABLED.store(enabled, Ordering::Release);
+    count
+}
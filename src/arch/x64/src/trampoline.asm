@@ -0,0 +1,130 @@
+[BITS 16]
+
+global ap_trampoline_start, ap_trampoline_end
+global ap_trampoline_stack, ap_trampoline_entry, ap_trampoline_cpu
+
+SECTION .text
+
+; 부트 스트랩 프로세서가 이 코드를 1MB 이하의 0x8000 어드레스로 복사한 후
+; INIT-SIPI-SIPI로 애플리케이션 프로세서를 깨움
+; 0x8000에서 실행되므로 모든 어드레스는 ( 레이블 - $$ + 0x8000 )으로 계산
+ap_trampoline_start:
+    cli
+    xor ax, ax          ; SIPI로 시작하면 CS는 0x0800이므로 DS를 0으로 설정하여
+    mov ds, ax          ; 계산한 어드레스를 그대로 사용
+
+    ; x86 스테이지의 EntryPoint.s와 같은 GDT를 로드하고 보호 모드로 전환
+    lgdt [ GDTR - $$ + 0x8000 ]
+
+    mov eax, 0x4000003B
+    mov cr0, eax
+
+    jmp dword 0x18 : ( PROTECTEDMODE - $$ + 0x8000 )
+
+[BITS 32]
+PROTECTEDMODE:
+    mov ax, 0x20
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+
+    ; kSwitchAndExecute64BitKernel과 같은 순서로 IA-32e 모드로 전환
+    ; 페이지 테이블은 부트 스트랩 프로세서가 만든 0x100000을 그대로 사용
+    mov eax, cr4
+    or eax, 0x20
+    mov cr4, eax
+
+    mov eax, 0x100000
+    mov cr3, eax
+
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, 0x0100
+    wrmsr
+
+    mov eax, cr0
+    or eax, 0xE0000000
+    xor eax, 0x60000000
+    mov cr0, eax
+
+    jmp 0x08 : ( LONGMODE - $$ + 0x8000 )
+
+[BITS 64]
+LONGMODE:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+
+    ; 부트 스트랩 프로세서가 채워둔 스택과 진입 함수로 이동
+    ;   PARAM: 코어 인덱스
+    mov rsp, qword [ ap_trampoline_stack - $$ + 0x8000 ]
+    mov rbp, rsp
+    mov rdi, qword [ ap_trampoline_cpu - $$ + 0x8000 ]
+    mov rax, qword [ ap_trampoline_entry - $$ + 0x8000 ]
+    call rax
+
+    jmp $
+
+align 8, db 0
+
+dw 0x0000
+
+GDTR:
+    dw GDTEND - GDT - 1
+    dd ( GDT - $$ + 0x8000 )
+
+GDT:
+    NULLDescripter:
+        dw 0x0000
+        dw 0x0000
+        db 0x00
+        db 0x00
+        db 0x00
+        db 0x00
+
+    IA_32eCODEDESCRIPTER:
+        dw 0xffff
+        dw 0x0000
+        db 0x00
+        db 0x9a
+        db 0xaf
+        db 0x00
+
+    IA_32eDATADESCRIPTER:
+        dw 0xffff
+        dw 0x0000
+        db 0x00
+        db 0x92
+        db 0xaf
+        db 0x00
+
+    CODEDESCRIPTER:
+        dw 0xffff
+        dw 0x0000
+        db 0x00
+        db 0x9a
+        db 0xcf
+        db 0x00
+
+    DATADESCRIPTER:
+        dw 0xffff
+        dw 0x0000
+        db 0x00
+        db 0x92
+        db 0xcf
+        db 0x00
+GDTEND:
+
+align 8, db 0
+
+; 애플리케이션 프로세서를 깨우기 전에 부트 스트랩 프로세서가 채우는 값
+ap_trampoline_stack: dq 0
+ap_trampoline_entry: dq 0
+ap_trampoline_cpu: dq 0
+
+ap_trampoline_end:
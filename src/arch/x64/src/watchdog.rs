@@ -0,0 +1,174 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use crate::{
+    apic::{self, IOAPIC_DELIVERYMODE_NMI},
+    console::report_line,
+    descriptor::{IST_SIZE, IST_STARTADDRESS},
+    pic, println,
+    process::{self, Context, Process, PROCESS_EXITCODE_WATCHDOG, PROCESS_FLAG_IDLETASK},
+    serial::SERIAL,
+    spinlock,
+    timer,
+};
+
+// The RTC periodic interrupt is routed through the I/O APIC as an NMI, so it
+// still arrives while interrupts are disabled
+const WATCHDOG_RTCIRQ: u8 = 8;
+const WATCHDOG_RTCRATE: u8 = 13;
+const WATCHDOG_PERIODMS: u64 = 125;
+const WATCHDOG_DEFAULTTIMEOUTMS: u64 = 2000;
+const WATCHDOG_MAXBACKTRACECOUNT: usize = 16;
+// Frame pointers are only followed inside the identity mapped memory
+const WATCHDOG_MAXFRAMEADDRESS: u64 = 0x1000000000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogAction {
+    Kill = 0,
+    Panic = 1,
+}
+
+static WATCHDOG_ENABLED: AtomicBool = AtomicBool::new(false);
+static WATCHDOG_TIMEOUTMS: AtomicU64 = AtomicU64::new(WATCHDOG_DEFAULTTIMEOUTMS);
+static WATCHDOG_ACTION: AtomicU8 = AtomicU8::new(WatchdogAction::Kill as u8);
+static WATCHDOG_LASTTICK: AtomicU64 = AtomicU64::new(0);
+static WATCHDOG_STALLMS: AtomicU64 = AtomicU64::new(0);
+// What the last expiry found, for the task that reports a kill
+static WATCHDOG_STUCKID: AtomicU64 = AtomicU64::new(0);
+static WATCHDOG_STUCKRIP: AtomicU64 = AtomicU64::new(0);
+
+// Only the bootstrap processor is watched. The NMI goes to APIC ID 0 and the
+// tick it checks is the PIT's, which only that core counts; an application
+// processor that hangs with interrupts disabled goes unnoticed.
+pub fn init_watchdog() {
+    pic::mask_irq(WATCHDOG_RTCIRQ);
+    timer::enable_rtc_periodic(WATCHDOG_RTCRATE);
+    apic::set_irq_redirection(WATCHDOG_RTCIRQ, IOAPIC_DELIVERYMODE_NMI, 0);
+    touch();
+    WATCHDOG_ENABLED.store(true, Ordering::Release);
+}
+
+pub fn set_enabled(enable: bool) {
+    touch();
+    WATCHDOG_ENABLED.store(enable, Ordering::Release);
+}
+
+pub fn is_enabled() -> bool {
+    WATCHDOG_ENABLED.load(Ordering::Acquire)
+}
+
+pub fn set_timeout(millisecond: u64) {
+    touch();
+    WATCHDOG_TIMEOUTMS.store(millisecond.max(WATCHDOG_PERIODMS), Ordering::Release);
+}
+
+pub fn get_timeout() -> u64 {
+    WATCHDOG_TIMEOUTMS.load(Ordering::Acquire)
+}
+
+pub fn set_action(action: WatchdogAction) {
+    WATCHDOG_ACTION.store(action as u8, Ordering::Release);
+}
+
+pub fn get_action() -> WatchdogAction {
+    match WATCHDOG_ACTION.load(Ordering::Acquire) {
+        0 => WatchdogAction::Kill,
+        _ => WatchdogAction::Panic,
+    }
+}
+
+// Code that keeps interrupts disabled on purpose, like the PIT busy waits,
+// calls this to show that it is still making progress
+pub fn touch() {
+    WATCHDOG_LASTTICK.store(timer::get_tick_count(), Ordering::Relaxed);
+    WATCHDOG_STALLMS.store(0, Ordering::Relaxed);
+}
+
+// Called from the NMI handler with the interrupted context; returns false
+// when the NMI did not come from the watchdog timer. Reading RTC status C
+// changes the CMOS index, which can garble a date read it interrupts.
+pub fn handle_nmi(context: &mut Context) -> bool {
+    if !timer::acknowledge_rtc() {
+        return false;
+    }
+    if !is_enabled() {
+        return true;
+    }
+    let tick = timer::get_tick_count();
+    if tick != WATCHDOG_LASTTICK.load(Ordering::Relaxed) {
+        touch();
+        return true;
+    }
+    let stall = WATCHDOG_STALLMS.fetch_add(WATCHDOG_PERIODMS, Ordering::Relaxed) + WATCHDOG_PERIODMS;
+    if stall < get_timeout() {
+        return true;
+    }
+    touch();
+
+    let pid = process::try_get_pid();
+    let rip = context.registers[Process::RIP];
+    WATCHDOG_STUCKID.store(pid.unwrap_or(0), Ordering::Relaxed);
+    WATCHDOG_STUCKRIP.store(rip, Ordering::Relaxed);
+    match (get_action(), pid) {
+        (WatchdogAction::Kill, Some(pid)) if can_kill(context, pid) => {
+            // The task leaves through watchdog_kill as if it had called it,
+            // on its own stack realigned for the call
+            let stack_pointer = context.registers[Process::RSP];
+            context.registers[Process::RSP] = (stack_pointer & !0xF) - 8;
+            context.registers[Process::RIP] = watchdog_kill as u64;
+        }
+        _ => panic_with_backtrace(context, pid),
+    }
+    true
+}
+
+// Only kernel code can keep interrupts disabled. A task can be ended when it
+// was not in the middle of an interrupt handler and holds no lock, since an
+// ended task never gives one back. The serial port is a plain mutex that the
+// count does not see, so it has to be free as well.
+fn can_kill(context: &Context, pid: u64) -> bool {
+    let stack_pointer = context.registers[Process::RSP];
+    let in_handler = stack_pointer >= IST_STARTADDRESS as u64
+        && stack_pointer < (IST_STARTADDRESS + IST_SIZE) as u64;
+    let is_idle = match process::get_process_from_id(pid) {
+        Some(task) => task.flags & PROCESS_FLAG_IDLETASK != 0,
+        None => true,
+    };
+    context.registers[Process::CS] & 0x3 == 0
+        && !in_handler
+        && !is_idle
+        && spinlock::held_count() == 0
+        && !SERIAL.is_locked()
+}
+
+extern "C" fn watchdog_kill() -> ! {
+    println!(
+        "[WATCHDOG] Task ID[0x{:X}] Stuck At RIP[0x{:X}], Task Ended",
+        WATCHDOG_STUCKID.load(Ordering::Relaxed),
+        WATCHDOG_STUCKRIP.load(Ordering::Relaxed)
+    );
+    process::end_process(process::get_pid(), PROCESS_EXITCODE_WATCHDOG);
+    loop {}
+}
+
+// Walks the frame pointer chain of the interrupted code
+fn panic_with_backtrace(context: &Context, pid: Option<u64>) -> ! {
+    let rip = context.registers[Process::RIP];
+    report_line(
+        1,
+        format_args!("[WATCHDOG] Task ID[0x{:X}] Stuck At RIP[0x{:X}]", pid.unwrap_or(0), rip),
+    );
+    report_line(2, format_args!("Backtrace:"));
+    let mut frame = context.registers[Process::RBP];
+    for index in 0..WATCHDOG_MAXBACKTRACECOUNT {
+        if frame == 0 || frame & 0x7 != 0 || frame >= WATCHDOG_MAXFRAMEADDRESS {
+            break;
+        }
+        let (previous, return_address) = unsafe { (*(frame as *const u64), *((frame + 8) as *const u64)) };
+        report_line(3 + index as i32, format_args!("  #{:<2} 0x{:X}", index, return_address));
+        if previous <= frame {
+            break;
+        }
+        frame = previous;
+    }
+    panic!("Watchdog: Task ID[0x{:X}] Stuck At RIP[0x{:X}]", pid.unwrap_or(0), rip);
+}
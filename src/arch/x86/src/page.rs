@@ -13,6 +13,8 @@ const PAGE_FLAGS_G: u32 = 0x00000100;
 const PAGE_FLAGS_PAT: u32 = 0x00001000;
 const PAGE_FLAGS_EXB: u32 = 0x80000000;
 const PAGE_FLAGS_DEFAULT: u32 = PAGE_FLAGS_P | PAGE_FLAGS_RW;
+// Upper levels allow user access; each 2MB page decides on its own in the PD entry
+const PAGE_FLAGS_DIRECTORY: u32 = PAGE_FLAGS_DEFAULT | PAGE_FLAGS_US;
 const PAGE_TABLESIZE: u32 = 0x1000;
 const PAGE_MAXENTRYCOUNT: u32 = 512;
 const PAGE_DEFAULTSIZE: u32 = 0x200000;
@@ -43,7 +45,7 @@ impl PageTableEntryStruct {
 
 pub unsafe fn InitPageTable() {
     let PML4Entry = 0x100000 as *mut PML4ENTRY;
-    (*PML4Entry.offset(0)).set(0, 0x101000, PAGE_FLAGS_DEFAULT, 0);
+    (*PML4Entry.offset(0)).set(0, 0x101000, PAGE_FLAGS_DIRECTORY, 0);
     for i in 1..PAGE_MAXENTRYCOUNT {
         (*PML4Entry.offset(i as isize)).set(0, 0, 0, 0);
     }
@@ -53,7 +55,7 @@ pub unsafe fn InitPageTable() {
         (*PDPTEntry.offset(i as isize)).set(
             0,
             0x102000 + (i * PAGE_TABLESIZE),
-            PAGE_FLAGS_DEFAULT,
+            PAGE_FLAGS_DIRECTORY,
             0,
         );
     }
@@ -0,0 +1,57 @@
+[BITS 64]
+
+SECTION .text
+
+global _start
+
+SYSCALL_EXIT    equ 0
+SYSCALL_WRITE   equ 4
+
+; Prints a greeting and every argument it was started with, then exits with
+; the argument count
+_start:
+    mov r12, rdi
+    mov r13, rsi
+
+    lea rdi, [rel greeting]
+    mov rsi, greeting.length
+    mov rax, SYSCALL_WRITE
+    syscall
+
+    xor rbx, rbx
+.argument:
+    cmp rbx, r12
+    jae .exit
+
+    mov rdi, [r13 + rbx * 8]
+    xor rsi, rsi
+.length:
+    cmp byte [rdi + rsi], 0
+    je .print
+    inc rsi
+    jmp .length
+.print:
+    mov rax, SYSCALL_WRITE
+    syscall
+
+    lea rdi, [rel newline]
+    mov rsi, 1
+    mov rax, SYSCALL_WRITE
+    syscall
+
+    inc rbx
+    jmp .argument
+
+.exit:
+    mov rdi, r12
+    mov rax, SYSCALL_EXIT
+    syscall
+    jmp $
+
+SECTION .rodata
+
+greeting:
+    db "Hello From User Program", 10
+.length equ $ - greeting
+newline:
+    db 10